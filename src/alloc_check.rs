@@ -0,0 +1,119 @@
+//! A small harness to detect allocations happening on the audio thread.
+//!
+//! When the `realtime-alloc-check` feature is enabled, every call to the wrapper's `process`
+//! (including the ones where an audio processor is hot-swapped) is marked as a realtime scope.
+//! Installing [`AllocationDetector`] as the global allocator of a test host will then record
+//! every allocation or deallocation happening inside such a scope, which can be checked using
+//! [`realtime_allocation_count`]:
+//!
+//! ```ignore
+//! use clap_hot_reload::alloc_check::*;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: AllocationDetector = AllocationDetector::system();
+//!
+//! // ... process some audio, and trigger a hot-reload in between ...
+//!
+//! assert_eq!(realtime_allocation_count(), 0);
+//! ```
+//!
+//! The wrapped plugin runs inside the same scopes, so its own allocations are counted too.
+//!
+//! Swapping processors never allocates. The only known sources of allocations left in the
+//! wrapper are its event buffers, which are preallocated but still grow if a single block carries
+//! more events than they can hold: this takes over a thousand events between the host and the
+//! wrapped plugin. Replayed notes never go past their buffer, see `MAX_RECOVERED_EVENTS`.
+//!
+//! The crate's own tests check this when run with `cargo test --features realtime-alloc-check`.
+
+#![allow(unsafe_code)] // Needed to implement GlobalAlloc
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static REALTIME_ALLOCATION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static IN_REALTIME_SCOPE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A global allocator wrapper that counts allocations happening inside realtime scopes.
+pub struct AllocationDetector<A = System> {
+    inner: A,
+}
+
+impl AllocationDetector<System> {
+    pub const fn system() -> Self {
+        Self { inner: System }
+    }
+}
+
+impl<A> AllocationDetector<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    #[inline]
+    fn record(&self) {
+        // try_with: this can be called while the thread-local is being torn down.
+        if let Ok(true) = IN_REALTIME_SCOPE.try_with(|s| s.get()) {
+            REALTIME_ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// SAFETY: all calls are forwarded to the inner allocator.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for AllocationDetector<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.record();
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.record();
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.record();
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.record();
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Returns how many allocations and deallocations happened inside realtime scopes so far.
+pub fn realtime_allocation_count() -> usize {
+    REALTIME_ALLOCATION_COUNT.load(Ordering::Relaxed)
+}
+
+/// Resets the counter returned by [`realtime_allocation_count`].
+pub fn reset_realtime_allocation_count() {
+    REALTIME_ALLOCATION_COUNT.store(0, Ordering::Relaxed)
+}
+
+pub(crate) struct RealtimeScope {
+    was_in_scope: bool,
+}
+
+impl RealtimeScope {
+    pub fn enter() -> Self {
+        Self {
+            was_in_scope: IN_REALTIME_SCOPE.with(|s| s.replace(true)),
+        }
+    }
+}
+
+impl Drop for RealtimeScope {
+    fn drop(&mut self) {
+        IN_REALTIME_SCOPE.with(|s| s.set(self.was_in_scope))
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: AllocationDetector = AllocationDetector::system();
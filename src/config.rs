@@ -0,0 +1,298 @@
+use crate::log::{log, LogLevel, LogSink, SharedLogSink};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The configuration of the hot-reloader.
+///
+/// This can be passed as an optional second argument to
+/// [`export_reloadable_clap_entry!`](crate::export_reloadable_clap_entry).
+///
+/// Some options can also be overridden at load time with environment variables:
+///
+/// * `CLAP_HOT_RELOAD`: set to `0` or `off` to disable reloading;
+/// * `CLAP_HOT_RELOAD_DEBOUNCE_MS`: the debounce time of the file watcher, in milliseconds;
+/// * `CLAP_HOT_RELOAD_POLL_MS`: if set, polls the bundle for changes at this interval, in
+///   milliseconds;
+/// * `CLAP_HOT_RELOAD_EXTRA_PATHS`: additional paths to watch, separated like `PATH`;
+/// * `CLAP_HOT_RELOAD_CONTROL_FILE`: see [`WatcherConfig::with_control_file`];
+/// * `CLAP_HOT_RELOAD_CONTROL_SOCKET`: see [`WatcherConfig::with_control_socket`];
+/// * `CLAP_HOT_RELOAD_LOG`: the most verbose [`LogLevel`] to log: `error`, `warn`, `info` or
+///   `debug`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotReloadConfig {
+    pub(crate) crossfade: CrossfadeConfig,
+    pub(crate) stable_param_list: bool,
+    pub(crate) watcher: WatcherConfig,
+    pub(crate) reloading_enabled: bool,
+    pub(crate) log_sink: Option<SharedLogSink>,
+    pub(crate) log_level: LogLevel,
+}
+
+impl HotReloadConfig {
+    pub const fn new() -> Self {
+        Self {
+            crossfade: CrossfadeConfig::new(),
+            stable_param_list: false,
+            watcher: WatcherConfig::new(),
+            reloading_enabled: true,
+            log_sink: None,
+            log_level: LogLevel::Info,
+        }
+    }
+
+    /// Enables or disables reloading altogether. If disabled, the bundle is not watched, and
+    /// the wrapped plugin is loaded as-is.
+    ///
+    /// For instance, to only enable reloading in debug builds:
+    ///
+    /// ```ignore
+    /// HotReloadConfig::new().with_reloading(cfg!(debug_assertions))
+    /// ```
+    pub const fn with_reloading(mut self, enabled: bool) -> Self {
+        self.reloading_enabled = enabled;
+        self
+    }
+
+    /// Sends diagnostic events to the given sink, instead of writing them to stderr and to a log
+    /// file next to the bundle.
+    pub fn with_log_sink(mut self, sink: impl LogSink + 'static) -> Self {
+        self.log_sink = Some(SharedLogSink(Arc::new(sink)));
+        self
+    }
+
+    /// Sets the most verbose level of the events to log. Defaults to [`LogLevel::Info`].
+    pub const fn with_log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Sets how the bundle is watched for changes.
+    pub fn with_watcher(mut self, watcher: WatcherConfig) -> Self {
+        self.watcher = watcher;
+        self
+    }
+
+    /// Sets how the audio output of a previous plugin instance transitions to the new one.
+    pub const fn with_crossfade(mut self, crossfade: CrossfadeConfig) -> Self {
+        self.crossfade = crossfade;
+        self
+    }
+
+    /// Never removes parameters from the list presented to the host during a session.
+    ///
+    /// Parameters removed by a reload are kept as hidden placeholders: any automation or value
+    /// change targeting them is ignored. They come back to life if a later reload restores them.
+    ///
+    /// This avoids forcing a full parameter rescan, which many hosts only support while the
+    /// plugin is deactivated.
+    pub const fn with_stable_param_list(mut self) -> Self {
+        self.stable_param_list = true;
+        self
+    }
+}
+
+impl Default for HotReloadConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HotReloadConfig {
+    /// Applies the overrides from environment variables. See [`HotReloadConfig`].
+    pub(crate) fn with_env_overrides(mut self) -> Self {
+        if let Ok(value) = std::env::var("CLAP_HOT_RELOAD") {
+            if matches!(value.trim(), "0" | "off" | "false") {
+                self.reloading_enabled = false;
+            }
+        }
+
+        if let Ok(value) = std::env::var("CLAP_HOT_RELOAD_LOG") {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "error" => self.log_level = LogLevel::Error,
+                "warn" => self.log_level = LogLevel::Warn,
+                "info" => self.log_level = LogLevel::Info,
+                "debug" => self.log_level = LogLevel::Debug,
+                _ => log!(
+                    Warn,
+                    Setup,
+                    "Ignoring invalid value for CLAP_HOT_RELOAD_LOG: {value}"
+                ),
+            }
+        }
+
+        if let Some(debounce) = read_env_duration_ms("CLAP_HOT_RELOAD_DEBOUNCE_MS") {
+            self.watcher.debounce = debounce;
+        }
+
+        if let Some(interval) = read_env_duration_ms("CLAP_HOT_RELOAD_POLL_MS") {
+            self.watcher.poll_interval = Some(interval);
+        }
+
+        if let Some(paths) = std::env::var_os("CLAP_HOT_RELOAD_EXTRA_PATHS") {
+            self.watcher
+                .extra_paths
+                .extend(std::env::split_paths(&paths));
+        }
+
+        self
+    }
+}
+
+fn read_env_duration_ms(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+
+    match value.trim().parse() {
+        Ok(ms) => Some(Duration::from_millis(ms)),
+        Err(e) => {
+            log!(Warn, Setup, "Ignoring invalid value for {name}: {e}");
+            None
+        }
+    }
+}
+
+/// The configuration of the file watcher.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatcherConfig {
+    pub(crate) debounce: Duration,
+    pub(crate) poll_interval: Option<Duration>,
+    pub(crate) extra_paths: Vec<PathBuf>,
+    pub(crate) control_file: Option<PathBuf>,
+    pub(crate) control_socket: Option<PathBuf>,
+    pub(crate) history_size: usize,
+}
+
+impl WatcherConfig {
+    /// The default configuration: uses the OS's file notifications, with a 250ms debounce time.
+    ///
+    /// The bundle is only polled for changes every second instead if the watcher can't be set up
+    /// at all. Network and FUSE filesystems usually accept watches but never report any change:
+    /// polling must be enabled explicitly for them, see [`Self::with_polling`].
+    pub const fn new() -> Self {
+        Self {
+            debounce: Duration::from_millis(250),
+            poll_interval: None,
+            extra_paths: Vec::new(),
+            control_file: None,
+            control_socket: None,
+            history_size: 4,
+        }
+    }
+
+    /// Sets how long to wait for file events to settle before checking for a new bundle.
+    pub const fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Polls the bundle for changes at the given interval, instead of relying on the OS's file
+    /// notifications.
+    ///
+    /// This is needed for e.g. NFS, SMB or FUSE filesystems: they often accept watches without
+    /// ever reporting changes, which can't be detected. It can also be enabled at load time with
+    /// the `CLAP_HOT_RELOAD_POLL_MS` environment variable.
+    pub const fn with_polling(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    /// Watches an additional path. Changes to it trigger a check for a new bundle.
+    ///
+    /// This is useful when e.g. the bundle is a link into a directory that gets replaced.
+    pub fn with_extra_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.extra_paths.push(path.into());
+        self
+    }
+
+    /// Watches a control file, to trigger actions manually.
+    ///
+    /// Whenever the file is modified or touched, the command it contains is run: `reload`,
+    /// `pause`, `resume` or `revert` (to the previous bundle in the history, see
+    /// [`with_history_size`](Self::with_history_size)). An empty file triggers a reload. The file is created if it
+    /// doesn't exist.
+    pub fn with_control_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_file = Some(path.into());
+        self
+    }
+
+    /// Sets how many previously loaded bundles are kept around to be rolled back to, either with
+    /// the `revert` command or automatically when a new bundle fails to load. Defaults to 4.
+    pub const fn with_history_size(mut self, history_size: usize) -> Self {
+        self.history_size = history_size;
+        self
+    }
+
+    /// Listens for commands on a Unix domain socket at the given path.
+    ///
+    /// The socket accepts the same commands as the [control file](Self::with_control_file), one
+    /// per line, and answers each one with either `ok` or an error message.
+    pub fn with_control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_socket = Some(path.into());
+        self
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The gain curve used when cross-fading between two plugin instances.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CrossfadeCurve {
+    /// A linear ramp. This causes a level dip on correlated signals.
+    Linear,
+    /// A constant-power fade, which keeps the perceived level of uncorrelated signals constant.
+    EqualPower,
+    /// A smooth-step curve, starting and ending slowly.
+    SCurve,
+    /// No fade at all: the new instance is switched to immediately.
+    Instant,
+}
+
+/// The configuration of the transition between the audio output of two plugin instances.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CrossfadeConfig {
+    pub(crate) duration: Duration,
+    pub(crate) curve: CrossfadeCurve,
+    pub(crate) overlap: bool,
+}
+
+impl CrossfadeConfig {
+    /// The default configuration: a 250ms linear crossfade.
+    pub const fn new() -> Self {
+        Self {
+            duration: Duration::from_millis(250),
+            curve: CrossfadeCurve::Linear,
+            overlap: true,
+        }
+    }
+
+    /// Sets the total duration of the transition.
+    pub const fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Sets the gain curve to use.
+    pub const fn with_curve(mut self, curve: CrossfadeCurve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    /// Fades the previous instance out completely before fading the new one in, so that the
+    /// output of both instances never overlaps.
+    ///
+    /// Each fade then takes half of the total duration.
+    pub const fn without_overlap(mut self) -> Self {
+        self.overlap = false;
+        self
+    }
+}
+
+impl Default for CrossfadeConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,222 @@
+#![allow(unsafe_code)] // Needed to implement the extension's C ABI
+
+//! A custom CLAP extension to hand the DSP state of an audio processor over to its replacement.
+//!
+//! By default, a new audio processor starts cold (empty delay lines, idle envelopes...), and is
+//! cross-faded with the previous one. With this extension, the outgoing processor exports its
+//! DSP state on the audio thread, and the incoming one imports it before processing its first
+//! block. The switch is then an instant cut, without any cross-fade.
+//!
+//! Held notes aren't replayed to the new instance after a handoff: they are expected to be part
+//! of the handed-off state.
+//!
+//! The blob format is up to the plugin, and is tagged with a schema version: if the new instance
+//! declines it, the wrapper falls back to a cross-fade.
+
+use crate::util::cstr;
+use clack_host::prelude::{PluginAudioProcessorHandle, PluginMainThreadHandle};
+use clack_plugin::extensions::wrapper::PluginWrapper;
+use clack_plugin::extensions::{
+    Extension, ExtensionImplementation, PluginExtensionSide, RawExtension,
+    RawExtensionImplementation,
+};
+use clack_plugin::plugin::Plugin;
+use clap_sys::plugin::clap_plugin;
+use std::ffi::CStr;
+
+/// The ID of the DSP handoff extension.
+pub const CLAP_EXT_DSP_HANDOFF: &CStr = cstr(b"clap-hot-reload.dsp-handoff/1\0");
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct clap_plugin_dsp_handoff {
+    /// Returns the maximum size of the exported DSP state, in bytes. The buffer given to
+    /// `export_state` is allocated with this size. Returns 0 if there is nothing to export.
+    /// [main-thread & active]
+    pub max_size: Option<unsafe extern "C" fn(plugin: *const clap_plugin) -> u32>,
+    /// Writes the DSP state into the given buffer, and its schema version to `version`. Returns
+    /// the written size, or 0 if nothing was exported. This must not allocate or block.
+    /// [audio-thread]
+    pub export_state: Option<
+        unsafe extern "C" fn(
+            plugin: *const clap_plugin,
+            buffer: *mut u8,
+            capacity: u32,
+            version: *mut u32,
+        ) -> u32,
+    >,
+    /// Reads the DSP state exported by the instance of a previous build. This is called before
+    /// the first block is processed. Returns false if it was declined, e.g. because its schema
+    /// version isn't supported. This must not allocate or block.
+    /// [audio-thread]
+    pub import_state: Option<
+        unsafe extern "C" fn(
+            plugin: *const clap_plugin,
+            buffer: *const u8,
+            size: u32,
+            version: u32,
+        ) -> bool,
+    >,
+}
+
+/// The DSP handoff extension, implemented by plugins.
+#[derive(Copy, Clone)]
+pub struct PluginDspHandoff(RawExtension<PluginExtensionSide, clap_plugin_dsp_handoff>);
+
+// SAFETY: the type matches the extension ID.
+unsafe impl Extension for PluginDspHandoff {
+    const IDENTIFIERS: &'static [&'static CStr] = &[CLAP_EXT_DSP_HANDOFF];
+    type ExtensionSide = PluginExtensionSide;
+
+    #[inline]
+    unsafe fn from_raw(raw: RawExtension<Self::ExtensionSide>) -> Self {
+        Self(raw.cast())
+    }
+}
+
+impl PluginDspHandoff {
+    pub fn max_size(&self, plugin: &mut PluginMainThreadHandle) -> usize {
+        match plugin.use_extension(&self.0).max_size {
+            // SAFETY: this type ensures the function pointer is valid.
+            Some(max_size) => unsafe { max_size(plugin.as_raw()) as usize },
+            None => 0,
+        }
+    }
+
+    /// Returns the written size and the schema version of the exported state, or `None` if
+    /// nothing was exported.
+    pub fn export_state(
+        &self,
+        plugin: &mut PluginAudioProcessorHandle,
+        buffer: &mut [u8],
+    ) -> Option<(usize, u32)> {
+        let export_state = plugin.use_extension(&self.0).export_state?;
+        let capacity = u32::try_from(buffer.len()).unwrap_or(u32::MAX);
+        let mut version = 0;
+
+        // SAFETY: this type ensures the function pointer is valid.
+        let size =
+            unsafe { export_state(plugin.as_raw(), buffer.as_mut_ptr(), capacity, &mut version) };
+
+        // Don't trust the plugin to stay within the buffer's bounds.
+        match size as usize {
+            0 => None,
+            size if size > capacity as usize => None,
+            size => Some((size, version)),
+        }
+    }
+
+    /// Returns false if the plugin declined the DSP state.
+    pub fn import_state(
+        &self,
+        plugin: &mut PluginAudioProcessorHandle,
+        state: &[u8],
+        version: u32,
+    ) -> bool {
+        let Some(import_state) = plugin.use_extension(&self.0).import_state else {
+            return false;
+        };
+
+        let Ok(size) = u32::try_from(state.len()) else {
+            return false;
+        };
+
+        // SAFETY: this type ensures the function pointer is valid.
+        unsafe { import_state(plugin.as_raw(), state.as_ptr(), size, version) }
+    }
+}
+
+/// Implementation of the main-thread part of the DSP handoff extension.
+pub trait PluginDspHandoffImpl {
+    /// Returns the maximum size of the exported DSP state, in bytes.
+    fn max_size(&mut self) -> usize;
+}
+
+/// Implementation of the audio-thread part of the DSP handoff extension.
+///
+/// Neither of these methods may allocate or block.
+pub trait PluginAudioProcessorDspHandoffImpl {
+    /// Writes the DSP state into the buffer, and returns the written size and its schema version.
+    fn export_state(&mut self, buffer: &mut [u8]) -> Option<(usize, u32)>;
+    /// Reads DSP state of the given schema version. Returns false to decline it.
+    fn import_state(&mut self, state: &[u8], version: u32) -> bool;
+}
+
+impl<P: Plugin> ExtensionImplementation<P> for PluginDspHandoff
+where
+    for<'a> P::MainThread<'a>: PluginDspHandoffImpl,
+    for<'a> P::AudioProcessor<'a>: PluginAudioProcessorDspHandoffImpl,
+{
+    #[doc(hidden)]
+    const IMPLEMENTATION: RawExtensionImplementation =
+        RawExtensionImplementation::new(&clap_plugin_dsp_handoff {
+            max_size: Some(max_size::<P>),
+            export_state: Some(export_state::<P>),
+            import_state: Some(import_state::<P>),
+        });
+}
+
+unsafe extern "C" fn max_size<P: Plugin>(plugin: *const clap_plugin) -> u32
+where
+    for<'a> P::MainThread<'a>: PluginDspHandoffImpl,
+    for<'a> P::AudioProcessor<'a>: PluginAudioProcessorDspHandoffImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        let max_size = plugin.main_thread().as_mut().max_size();
+        Ok(u32::try_from(max_size).unwrap_or(u32::MAX))
+    })
+    .unwrap_or(0)
+}
+
+unsafe extern "C" fn export_state<P: Plugin>(
+    plugin: *const clap_plugin,
+    buffer: *mut u8,
+    capacity: u32,
+    version: *mut u32,
+) -> u32
+where
+    for<'a> P::MainThread<'a>: PluginDspHandoffImpl,
+    for<'a> P::AudioProcessor<'a>: PluginAudioProcessorDspHandoffImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        if buffer.is_null() || version.is_null() {
+            return Ok(0);
+        }
+
+        let buffer = core::slice::from_raw_parts_mut(buffer, capacity as usize);
+
+        let Some((size, exported_version)) =
+            plugin.audio_processor()?.as_mut().export_state(buffer)
+        else {
+            return Ok(0);
+        };
+
+        *version = exported_version;
+        Ok(size.min(capacity as usize) as u32)
+    })
+    .unwrap_or(0)
+}
+
+unsafe extern "C" fn import_state<P: Plugin>(
+    plugin: *const clap_plugin,
+    buffer: *const u8,
+    size: u32,
+    version: u32,
+) -> bool
+where
+    for<'a> P::MainThread<'a>: PluginDspHandoffImpl,
+    for<'a> P::AudioProcessor<'a>: PluginAudioProcessorDspHandoffImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        if buffer.is_null() {
+            return Ok(false);
+        }
+
+        let state = core::slice::from_raw_parts(buffer, size as usize);
+        Ok(plugin
+            .audio_processor()?
+            .as_mut()
+            .import_state(state, version))
+    })
+    .unwrap_or(false)
+}
@@ -1,10 +1,16 @@
-use crate::util::load_if_different_bundle;
-use crate::watcher::WatcherMaster;
-use crate::wrapper::{WrapperHost, WrapperPlugin, WrapperPluginMainThread, WrapperPluginShared};
+use crate::config::HotReloadConfig;
+use crate::log::log;
+use crate::util::{bundle_executable_path, load_if_different_bundle, LoadedBundle};
+use crate::watcher::{BundleNotifier, BundleReceiver, WatcherMaster};
+use crate::wrapper::{
+    OuterHostCapabilities, WrapperHost, WrapperPlugin, WrapperPluginMainThread, WrapperPluginShared,
+};
 use clack_host::bundle::PluginBundle;
 use clack_plugin::entry::prelude::*;
+use clap_sys::host::clap_host;
 use std::ffi::{CStr, CString};
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
 
 pub struct HotReloaderEntry {
     plugin_factory: Option<PluginFactoryWrapper<HotReloaderPluginFactory>>,
@@ -26,9 +32,17 @@ impl HotReloaderEntry {
     pub fn new(
         bundle_path: &CStr,
         inner_entry: &'static EntryDescriptor,
+        config: HotReloadConfig,
     ) -> Result<Self, EntryLoadError> {
+        let config = config.with_env_overrides();
+
         // TODO: unwrap
         let bundle_path = bundle_path.to_str().unwrap();
+        crate::log::init(
+            config.log_sink.clone(),
+            config.log_level,
+            Path::new(bundle_path),
+        );
         let initial_bundle = load_initial_bundle(inner_entry, bundle_path)?;
 
         if initial_bundle.get_plugin_factory().is_none() {
@@ -37,11 +51,19 @@ impl HotReloaderEntry {
             });
         }
 
-        let watcher = WatcherMaster::new(initial_bundle.clone(), Path::new(bundle_path));
+        let watcher = if config.reloading_enabled {
+            WatcherMaster::new(
+                initial_bundle.clone(),
+                Path::new(bundle_path),
+                &config.watcher,
+            )
+        } else {
+            None
+        };
 
         let factory = match watcher {
-            None => HotReloaderPluginFactory::new_non_reloading(initial_bundle),
-            Some(w) => HotReloaderPluginFactory::new(w, &initial_bundle),
+            None => HotReloaderPluginFactory::new_non_reloading(initial_bundle, config),
+            Some(w) => HotReloaderPluginFactory::new(w, &initial_bundle, config),
         };
 
         Ok(Self {
@@ -52,53 +74,63 @@ impl HotReloaderEntry {
 
 struct HotReloaderPluginFactory {
     watcher: Option<WatcherMaster>,
-    static_bundle: Option<PluginBundle>,
-    descriptors: Vec<PluginDescriptor>,
+    static_bundle: Option<LoadedBundle>,
+    descriptors: DescriptorStore,
+    table: Mutex<DescriptorTable>,
+    config: HotReloadConfig,
 }
 
 impl HotReloaderPluginFactory {
-    pub fn new(watcher: WatcherMaster, initial_bundle: &PluginBundle) -> Self {
-        let descriptors = if let Some(factory) = initial_bundle.get_plugin_factory() {
-            factory
-                .plugin_descriptors()
-                .filter_map(clone_plugin_descriptor)
-                .collect()
-        } else {
-            vec![]
-        };
+    pub fn new(
+        watcher: WatcherMaster,
+        initial_bundle: &LoadedBundle,
+        config: HotReloadConfig,
+    ) -> Self {
+        // The factory has its own receiver, to follow the plugin list of new bundles.
+        let receiver = watcher.new_receiver(None);
+        let descriptors = DescriptorStore::new();
+        let table = DescriptorTable::new(&descriptors, initial_bundle, Some(receiver));
 
         Self {
+            descriptors,
+            table: Mutex::new(table),
             watcher: Some(watcher),
             static_bundle: None,
-            descriptors,
+            config,
         }
     }
 
-    pub fn new_non_reloading(plugin_bundle: PluginBundle) -> Self {
-        let descriptors = if let Some(factory) = plugin_bundle.get_plugin_factory() {
-            factory
-                .plugin_descriptors()
-                .filter_map(clone_plugin_descriptor)
-                .collect()
-        } else {
-            vec![]
-        };
+    pub fn new_non_reloading(plugin_bundle: LoadedBundle, config: HotReloadConfig) -> Self {
+        let descriptors = DescriptorStore::new();
+        let table = DescriptorTable::new(&descriptors, &plugin_bundle, None);
 
         Self {
+            descriptors,
+            table: Mutex::new(table),
             watcher: None,
             static_bundle: Some(plugin_bundle),
-            descriptors,
+            config,
         }
     }
+
+    /// Returns the descriptor table, as of its last refresh.
+    fn table(&self) -> MutexGuard<'_, DescriptorTable> {
+        self.table.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl PluginFactory for HotReloaderPluginFactory {
     fn plugin_count(&self) -> u32 {
-        self.descriptors.len() as u32
+        // Hosts query the count before listing descriptors, so this is the only point where the
+        // table follows new bundles. Indices then stay stable until the next count.
+        let mut table = self.table();
+        table.refresh(&self.descriptors);
+        table.current.len() as u32
     }
 
     fn plugin_descriptor(&self, index: u32) -> Option<&PluginDescriptor> {
-        self.descriptors.get(index as usize)
+        let index = *self.table().current.get(index as usize)?;
+        self.descriptors.get(index)
     }
 
     fn create_plugin<'a>(
@@ -106,7 +138,8 @@ impl PluginFactory for HotReloaderPluginFactory {
         host_info: HostInfo<'a>,
         plugin_id: &CStr,
     ) -> Option<PluginInstance<'a>> {
-        let matching_descriptor = self.descriptors.iter().find(|d| d.id() == plugin_id)?;
+        let index = self.table().find(&self.descriptors, plugin_id)?;
+        let matching_descriptor = self.descriptors.get(index)?;
 
         let plugin_id: CString = plugin_id.into();
 
@@ -114,18 +147,29 @@ impl PluginFactory for HotReloaderPluginFactory {
             PluginInstance::<'a>::new_with_initializer::<WrapperPlugin, _>(
                 host_info,
                 matching_descriptor,
-                move |host| {
-                    let bundle_receiver = self.watcher.as_ref().map(|w| w.new_receiver());
+                move |mut host| {
+                    let bundle_receiver = self
+                        .watcher
+                        .as_ref()
+                        .map(|w| w.new_receiver(Some(new_bundle_notifier(host.shared()))));
 
                     let bundle = match &bundle_receiver {
                         None => self.static_bundle.as_ref().unwrap(), // PANIC: either static_bundle or watcher is always set.
                         Some(r) => r.current_bundle(),
                     };
 
-                    let instance = WrapperHost::new_instance(&host, bundle, &plugin_id);
+                    let host_capabilities = OuterHostCapabilities::new(&mut host);
+
+                    let instance = WrapperHost::new_instance(
+                        &host,
+                        bundle,
+                        &plugin_id,
+                        host_capabilities,
+                        None,
+                    )?;
 
                     Ok((
-                        WrapperPluginShared::new(host.shared(), &instance),
+                        WrapperPluginShared::new(host.shared(), &instance, &self.config),
                         move |shared| {
                             WrapperPluginMainThread::new(
                                 host,
@@ -133,6 +177,7 @@ impl PluginFactory for HotReloaderPluginFactory {
                                 instance,
                                 bundle_receiver,
                                 plugin_id,
+                                host_capabilities,
                             )
                         },
                     ))
@@ -142,48 +187,243 @@ impl PluginFactory for HotReloaderPluginFactory {
     }
 }
 
-fn clone_plugin_descriptor(
-    desc: clack_host::factory::PluginDescriptor,
-) -> Option<PluginDescriptor> {
-    Some(
-        PluginDescriptor::new(desc.id()?.to_str().ok()?, desc.name()?.to_str().ok()?)
-            .with_vendor(desc.vendor().and_then(|s| s.to_str().ok()).unwrap_or(""))
-            .with_url(desc.url().and_then(|s| s.to_str().ok()).unwrap_or(""))
-            .with_manual_url(
-                desc.manual_url()
-                    .and_then(|s| s.to_str().ok())
-                    .unwrap_or(""),
-            )
-            .with_support_url(
-                desc.support_url()
-                    .and_then(|s| s.to_str().ok())
-                    .unwrap_or(""),
-            )
-            .with_version(desc.version().and_then(|s| s.to_str().ok()).unwrap_or(""))
-            .with_description(
-                desc.description()
-                    .and_then(|s| s.to_str().ok())
-                    .unwrap_or(""),
-            )
-            .with_features(desc.features()),
-    )
+/// The maximum number of distinct plugin descriptors the factory can offer over all reloads.
+const MAX_DESCRIPTORS: usize = 256;
+
+/// An append-only store of the plugin descriptors offered to the host.
+///
+/// The host may keep pointers to descriptors it already queried, so they are never dropped
+/// while the factory is alive, even if they don't belong to the latest bundle anymore.
+struct DescriptorStore {
+    slots: Box<[OnceLock<(DescriptorInfo, PluginDescriptor)>]>,
+}
+
+impl DescriptorStore {
+    fn new() -> Self {
+        Self {
+            slots: (0..MAX_DESCRIPTORS).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &(DescriptorInfo, PluginDescriptor)> {
+        self.slots.iter().map_while(OnceLock::get)
+    }
+
+    fn info(&self, index: usize) -> Option<&DescriptorInfo> {
+        self.slots.get(index)?.get().map(|(info, _)| info)
+    }
+
+    fn get(&self, index: usize) -> Option<&PluginDescriptor> {
+        self.slots
+            .get(index)?
+            .get()
+            .map(|(_, descriptor)| descriptor)
+    }
+
+    /// Returns the index of the given descriptor, adding it if it isn't in the store yet.
+    ///
+    /// Returns `None` if the store is full. Callers are serialized by the descriptor table lock.
+    fn get_or_push(&self, info: DescriptorInfo) -> Option<usize> {
+        if let Some(index) = self.entries().position(|(i, _)| *i == info) {
+            return Some(index);
+        }
+
+        let index = self.entries().count();
+        let descriptor = info.to_descriptor();
+        self.slots.get(index)?.set((info, descriptor)).ok()?;
+
+        Some(index)
+    }
+}
+
+/// The descriptors of the latest bundle, as indices in the [`DescriptorStore`].
+struct DescriptorTable {
+    current: Vec<usize>,
+    bundle_receiver: Option<BundleReceiver>,
+}
+
+impl DescriptorTable {
+    fn new(
+        store: &DescriptorStore,
+        bundle: &PluginBundle,
+        bundle_receiver: Option<BundleReceiver>,
+    ) -> Self {
+        let mut table = Self {
+            current: Vec::new(),
+            bundle_receiver,
+        };
+
+        table.update(store, bundle);
+        table
+    }
+
+    /// Updates the table if a new bundle was produced since the last call.
+    fn refresh(&mut self, store: &DescriptorStore) {
+        let Some(bundle) = self
+            .bundle_receiver
+            .as_mut()
+            .and_then(|r| r.receive_new_bundle())
+        else {
+            return;
+        };
+
+        self.update(store, &bundle);
+
+        if let Some(receiver) = &mut self.bundle_receiver {
+            receiver.set_current_bundle(bundle);
+        }
+    }
+
+    fn update(&mut self, store: &DescriptorStore, bundle: &PluginBundle) {
+        let Some(factory) = bundle.get_plugin_factory() else {
+            // Keep offering the previous plugins, there's nothing better to do.
+            return;
+        };
+
+        let mut current = Vec::new();
+
+        for info in factory
+            .plugin_descriptors()
+            .filter_map(DescriptorInfo::read)
+        {
+            let id = info.id.clone();
+
+            match store.get_or_push(info) {
+                Some(index) => current.push(index),
+                None => log!(
+                    Error,
+                    Reload,
+                    "Can't offer more than {MAX_DESCRIPTORS} distinct plugin descriptors. Plugin {id} is not available anymore."
+                ),
+            }
+        }
+
+        for info in self.current.iter().filter_map(|&index| store.info(index)) {
+            if !current
+                .iter()
+                .any(|&index| store.info(index).is_some_and(|i| i.id == info.id))
+            {
+                let id = &info.id;
+                log!(
+                    Warn,
+                    Reload,
+                    "Plugin {id} was removed from the bundle. New instances of it can't be created anymore."
+                );
+            }
+        }
+
+        self.current = current;
+    }
+
+    fn find(&self, store: &DescriptorStore, id: &CStr) -> Option<usize> {
+        self.current.iter().copied().find(|&index| {
+            store
+                .info(index)
+                .is_some_and(|info| info.id.as_bytes() == id.to_bytes())
+        })
+    }
+}
+
+/// The contents of a plugin descriptor, to detect changes across bundles.
+#[derive(PartialEq)]
+struct DescriptorInfo {
+    id: String,
+    name: String,
+    vendor: String,
+    url: String,
+    manual_url: String,
+    support_url: String,
+    version: String,
+    description: String,
+    features: Vec<CString>,
+}
+
+impl DescriptorInfo {
+    fn read(desc: clack_host::factory::PluginDescriptor) -> Option<Self> {
+        let read = |s: Option<&CStr>| s.and_then(|s| s.to_str().ok()).unwrap_or("").to_owned();
+
+        Some(Self {
+            id: desc.id()?.to_str().ok()?.to_owned(),
+            name: desc.name()?.to_str().ok()?.to_owned(),
+            vendor: read(desc.vendor()),
+            url: read(desc.url()),
+            manual_url: read(desc.manual_url()),
+            support_url: read(desc.support_url()),
+            version: read(desc.version()),
+            description: read(desc.description()),
+            features: desc.features().map(CString::from).collect(),
+        })
+    }
+
+    fn to_descriptor(&self) -> PluginDescriptor {
+        PluginDescriptor::new(&self.id, &self.name)
+            .with_vendor(&self.vendor)
+            .with_url(&self.url)
+            .with_manual_url(&self.manual_url)
+            .with_support_url(&self.support_url)
+            .with_version(&self.version)
+            .with_description(&self.description)
+            .with_features(self.features.iter().map(|f| f.as_c_str()))
+    }
+}
+
+/// Requests a main thread callback from the host whenever a new bundle is available, so that
+/// instances get reloaded even if the host doesn't support timers.
+fn new_bundle_notifier(host: HostSharedHandle) -> BundleNotifier {
+    let host = NotifiedHost(host.as_raw());
+
+    Box::new(move || host.request_callback())
+}
+
+/// The host of a plugin instance, called from the watcher thread.
+///
+/// This keeps a raw pointer, as a [`HostSharedHandle`] can't outlive the instance's creation.
+struct NotifiedHost(*const clap_host);
+
+// SAFETY: only request_callback is called, which is thread-safe.
+#[allow(unsafe_code)]
+unsafe impl Send for NotifiedHost {}
+
+impl NotifiedHost {
+    #[allow(unsafe_code)]
+    fn request_callback(&self) {
+        // SAFETY: the notifier is owned by the instance's BundleReceiver, which unregisters it from
+        // the watcher thread when dropped, waiting for any ongoing call. This happens when the
+        // plugin instance is destroyed, while its host is still valid.
+        unsafe {
+            if let Some(request_callback) = (*self.0).request_callback {
+                request_callback(self.0)
+            }
+        }
+    }
 }
 
 #[allow(unsafe_code)]
 fn load_initial_bundle(
     initial_entry: &'static EntryDescriptor,
     self_path: &str,
-) -> Result<PluginBundle, EntryLoadError> {
+) -> Result<LoadedBundle, EntryLoadError> {
     let bundle = if let Ok(Some(different_bundle)) =
-        load_if_different_bundle(initial_entry, Path::new(self_path))
+        load_if_different_bundle(initial_entry, &bundle_executable_path(Path::new(self_path)))
     {
-        println!("Different bundle loaded. AAAA");
+        log!(
+            Debug,
+            Setup,
+            "Loaded the wrapped plugin from a separate copy of the bundle."
+        );
         different_bundle
     } else {
-        println!("Loading from the same bundle");
+        log!(
+            Debug,
+            Setup,
+            "Loading the wrapped plugin from the same bundle."
+        );
         // TODO: double utf8 check
-        unsafe { PluginBundle::load_from_raw(initial_entry, self_path) }
-            .map_err(|_| EntryLoadError)?
+        let bundle = unsafe { PluginBundle::load_from_raw(initial_entry, self_path) }
+            .map_err(|_| EntryLoadError)?;
+
+        // There is no previous instance to migrate parameters from.
+        LoadedBundle::without_metadata(bundle)
     };
 
     Ok(bundle)
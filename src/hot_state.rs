@@ -0,0 +1,154 @@
+#![allow(unsafe_code)] // Needed to implement the extension's C ABI
+
+//! A custom CLAP extension to hand transient state over to the instance of a newer build.
+//!
+//! Unlike the state of the CLAP state extension, this "hot state" is never stored with the
+//! project: it holds whatever is worth keeping across a reload, but not across sessions, such as
+//! filter memories, sample caches or the currently open GUI tab.
+//!
+//! The blob format is up to the plugin. It is tagged with a schema version, so that builds that
+//! can't read it may decline it. It is loaded after the CLAP state, if any.
+
+use crate::util::cstr;
+use clack_host::prelude::PluginMainThreadHandle;
+use clack_host::stream::{InputStream, OutputStream};
+use clack_plugin::extensions::wrapper::PluginWrapper;
+use clack_plugin::extensions::{
+    Extension, ExtensionImplementation, PluginExtensionSide, RawExtension,
+    RawExtensionImplementation,
+};
+use clack_plugin::plugin::{Plugin, PluginError};
+use clap_sys::plugin::clap_plugin;
+use clap_sys::stream::{clap_istream, clap_ostream};
+use std::ffi::CStr;
+
+/// The ID of the hot state extension.
+pub const CLAP_EXT_HOT_STATE: &CStr = cstr(b"clap-hot-reload.hot-state/1\0");
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct clap_plugin_hot_state {
+    /// Writes the hot state to the stream, and its schema version to `version`. Returns false if
+    /// there is nothing to hand over.
+    /// [main-thread]
+    pub save: Option<
+        unsafe extern "C" fn(
+            plugin: *const clap_plugin,
+            stream: *const clap_ostream,
+            version: *mut u32,
+        ) -> bool,
+    >,
+    /// Reads the hot state saved by the instance of a previous build. Returns false if it was
+    /// declined, e.g. because its schema version isn't supported.
+    /// [main-thread]
+    pub load: Option<
+        unsafe extern "C" fn(
+            plugin: *const clap_plugin,
+            stream: *const clap_istream,
+            version: u32,
+        ) -> bool,
+    >,
+}
+
+/// The hot state extension, implemented by plugins.
+#[derive(Copy, Clone)]
+pub struct PluginHotState(RawExtension<PluginExtensionSide, clap_plugin_hot_state>);
+
+// SAFETY: the type matches the extension ID.
+unsafe impl Extension for PluginHotState {
+    const IDENTIFIERS: &'static [&'static CStr] = &[CLAP_EXT_HOT_STATE];
+    type ExtensionSide = PluginExtensionSide;
+
+    #[inline]
+    unsafe fn from_raw(raw: RawExtension<Self::ExtensionSide>) -> Self {
+        Self(raw.cast())
+    }
+}
+
+impl PluginHotState {
+    /// Returns the schema version of the saved hot state, or `None` if nothing was saved.
+    pub fn save(
+        &self,
+        plugin: &mut PluginMainThreadHandle,
+        stream: &mut OutputStream,
+    ) -> Option<u32> {
+        let save = plugin.use_extension(&self.0).save?;
+        let mut version = 0;
+
+        // SAFETY: this type ensures the function pointer is valid.
+        unsafe { save(plugin.as_raw(), stream.as_raw_mut(), &mut version) }.then_some(version)
+    }
+
+    /// Returns false if the plugin declined the hot state.
+    pub fn load(
+        &self,
+        plugin: &mut PluginMainThreadHandle,
+        stream: &mut InputStream,
+        version: u32,
+    ) -> bool {
+        let Some(load) = plugin.use_extension(&self.0).load else {
+            return false;
+        };
+
+        // SAFETY: this type ensures the function pointer is valid.
+        unsafe { load(plugin.as_raw(), stream.as_raw_mut(), version) }
+    }
+}
+
+/// Implementation of the hot state extension.
+pub trait PluginHotStateImpl {
+    /// Writes the hot state, and returns its schema version.
+    fn save(&mut self, output: &mut OutputStream) -> Result<u32, PluginError>;
+    /// Reads hot state of the given schema version. Returning an error declines it.
+    fn load(&mut self, input: &mut InputStream, version: u32) -> Result<(), PluginError>;
+}
+
+impl<P: Plugin> ExtensionImplementation<P> for PluginHotState
+where
+    for<'a> P::MainThread<'a>: PluginHotStateImpl,
+{
+    #[doc(hidden)]
+    const IMPLEMENTATION: RawExtensionImplementation =
+        RawExtensionImplementation::new(&clap_plugin_hot_state {
+            save: Some(save::<P>),
+            load: Some(load::<P>),
+        });
+}
+
+unsafe extern "C" fn save<P: Plugin>(
+    plugin: *const clap_plugin,
+    stream: *const clap_ostream,
+    version: *mut u32,
+) -> bool
+where
+    for<'a> P::MainThread<'a>: PluginHotStateImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        let output = OutputStream::from_raw_mut(&mut *(stream as *mut _));
+        let saved_version = plugin.main_thread().as_mut().save(output)?;
+
+        if let Some(version) = version.as_mut() {
+            *version = saved_version;
+        }
+
+        Ok(())
+    })
+    .is_some()
+}
+
+unsafe extern "C" fn load<P: Plugin>(
+    plugin: *const clap_plugin,
+    stream: *const clap_istream,
+    version: u32,
+) -> bool
+where
+    for<'a> P::MainThread<'a>: PluginHotStateImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        let input = InputStream::from_raw_mut(&mut *(stream as *mut _));
+        plugin.main_thread().as_mut().load(input, version)?;
+
+        Ok(())
+    })
+    .is_some()
+}
@@ -1,10 +1,22 @@
 #![deny(unsafe_code)]
 
+#[cfg(feature = "realtime-alloc-check")]
+pub mod alloc_check;
+mod config;
+pub mod dsp_handoff;
 mod entry;
+pub mod hot_state;
+mod log;
+mod param_migrations;
+pub mod reload_events;
 mod util;
 mod watcher;
 mod wrapper;
 
+pub use config::*;
+pub use log::{LogEvent, LogLevel, LogSink, LogStage};
+pub use param_migrations::{ParamIdMigration, ParamIdMigrations};
+
 #[doc(hidden)]
 pub mod _macro_utils {
     pub use crate::entry::HotReloaderEntry;
@@ -15,6 +27,9 @@ pub mod _macro_utils {
 #[macro_export]
 macro_rules! export_reloadable_clap_entry {
     ($entry_value:expr) => {
+        $crate::export_reloadable_clap_entry!($entry_value, $crate::HotReloadConfig::new());
+    };
+    ($entry_value:expr, $config:expr) => {
         $crate::_macro_utils::clack_export_entry!(
             $crate::_macro_utils::HotReloaderEntry,
             ({
@@ -25,7 +40,13 @@ macro_rules! export_reloadable_clap_entry {
                 pub static __clack_hotreload_wrapped_entry: $crate::_macro_utils::EntryDescriptor =
                     $entry_value;
 
-                |p| $crate::_macro_utils::HotReloaderEntry::new(p, &__clack_hotreload_wrapped_entry)
+                |p| {
+                    $crate::_macro_utils::HotReloaderEntry::new(
+                        p,
+                        &__clack_hotreload_wrapped_entry,
+                        $config,
+                    )
+                }
             })
         );
     };
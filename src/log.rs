@@ -0,0 +1,336 @@
+//! Diagnostics of the hot-reloader.
+//!
+//! Events are sent to a [`LogSink`]. By default, they are written to stderr and to a rotating
+//! log file next to the bundle (e.g. `my_plugin.clap.log`), as stdout and stderr are usually
+//! discarded by DAWs.
+//!
+//! Events from the audio thread are queued without allocating or locking, and only reach the
+//! sink once the main thread picks them up.
+
+use crate::wrapper::BoundedQueue;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Once the log file reaches this size, it is rotated.
+const MAX_LOG_FILE_SIZE: u64 = 1024 * 1024;
+/// How many rotated log files are kept, in addition to the current one.
+const ROTATED_LOG_FILE_COUNT: usize = 3;
+/// How many audio thread events can wait for the main thread before new ones are dropped.
+const AUDIO_EVENT_QUEUE_CAPACITY: usize = 32;
+
+/// The severity of a [`LogEvent`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        })
+    }
+}
+
+/// The part of the hot-reloader a [`LogEvent`] comes from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogStage {
+    /// Loading the hot-reloader, and its configuration.
+    Setup,
+    /// Watching the bundle for changes, and handling commands.
+    Watch,
+    /// Loading a new bundle.
+    Reload,
+    /// Replacing a plugin instance with one from a new bundle.
+    Swap,
+    /// Transferring the state of a plugin instance to its replacement.
+    StateTransfer,
+    /// Transferring the GUI of a plugin instance to its replacement.
+    GuiTransfer,
+    /// Audio processing.
+    Audio,
+    /// Messages logged by the wrapped plugin.
+    Plugin,
+}
+
+impl fmt::Display for LogStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogStage::Setup => "setup",
+            LogStage::Watch => "watch",
+            LogStage::Reload => "reload",
+            LogStage::Swap => "swap",
+            LogStage::StateTransfer => "state-transfer",
+            LogStage::GuiTransfer => "gui-transfer",
+            LogStage::Audio => "audio",
+            LogStage::Plugin => "plugin",
+        })
+    }
+}
+
+/// A diagnostic event.
+pub struct LogEvent<'a> {
+    pub level: LogLevel,
+    pub stage: LogStage,
+    pub message: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for LogEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] {}", self.level, self.stage, self.message)
+    }
+}
+
+/// Receives the diagnostic events of the hot-reloader.
+///
+/// This can be set with [`HotReloadConfig::with_log_sink`](crate::HotReloadConfig::with_log_sink).
+/// It is always called from a non-realtime thread, but possibly from multiple threads at once.
+pub trait LogSink: Send + Sync {
+    fn log(&self, event: &LogEvent);
+}
+
+/// A [`LogSink`] set in the configuration.
+#[derive(Clone)]
+pub(crate) struct SharedLogSink(pub(crate) Arc<dyn LogSink>);
+
+impl fmt::Debug for SharedLogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedLogSink")
+    }
+}
+
+impl PartialEq for SharedLogSink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// An event sent from the audio thread. It can't be formatted there.
+#[derive(Copy, Clone)]
+struct AudioLogEvent {
+    /// The order events were queued in: the queue itself doesn't keep it.
+    sequence: u64,
+    level: LogLevel,
+    message: &'static str,
+}
+
+/// The events queued by the audio threads of all instances, waiting for a main thread.
+struct AudioEventQueue {
+    events: BoundedQueue<AudioLogEvent>,
+    next_sequence: AtomicU64,
+    /// Where events are sorted back in order before being logged.
+    drained: Mutex<Vec<AudioLogEvent>>,
+}
+
+impl AudioEventQueue {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            events: BoundedQueue::with_capacity(capacity),
+            next_sequence: AtomicU64::new(0),
+            drained: Mutex::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, level: LogLevel, message: &'static str) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+
+        let _ = self.events.try_push(AudioLogEvent {
+            sequence,
+            level,
+            message,
+        });
+    }
+
+    /// Hands all the queued events to the given function, oldest first.
+    fn drain(&self, mut f: impl FnMut(AudioLogEvent)) {
+        let mut drained = self.drained.lock().unwrap_or_else(PoisonError::into_inner);
+
+        drained.extend(self.events.drain());
+        drained.sort_unstable_by_key(|e| e.sequence);
+
+        for event in drained.drain(..) {
+            f(event)
+        }
+    }
+}
+
+struct Logger {
+    sink: Arc<dyn LogSink>,
+    level: LogLevel,
+    audio_events: AudioEventQueue,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets up the sink all events are sent to. Events logged before this are only written to stderr.
+pub(crate) fn init(sink: Option<SharedLogSink>, level: LogLevel, bundle_path: &Path) {
+    let sink = match sink {
+        Some(sink) => sink.0,
+        None => {
+            let mut path = bundle_path.as_os_str().to_os_string();
+            path.push(".log");
+
+            Arc::new(FileLogSink::new(path.into()))
+        }
+    };
+
+    let _ = LOGGER.set(Logger {
+        sink,
+        level,
+        audio_events: AudioEventQueue::with_capacity(AUDIO_EVENT_QUEUE_CAPACITY),
+    });
+}
+
+/// Sends an event to the sink. Use the [`log!`] macro instead.
+pub(crate) fn log_event(level: LogLevel, stage: LogStage, message: fmt::Arguments) {
+    let event = LogEvent {
+        level,
+        stage,
+        message,
+    };
+
+    match LOGGER.get() {
+        Some(logger) if level <= logger.level => logger.sink.log(&event),
+        Some(_) => {}
+        None => eprintln!("[CLAP PLUGIN HOT RELOADER] {event}"),
+    }
+}
+
+/// Queues an event from the audio thread. This never allocates or blocks: if too many events are
+/// waiting for the main thread, this one is dropped.
+pub(crate) fn log_from_audio_thread(level: LogLevel, message: &'static str) {
+    if let Some(logger) = LOGGER.get() {
+        logger.audio_events.push(level, message);
+    }
+}
+
+/// Sends the events queued by the audio thread to the sink. This must be called on the main
+/// thread.
+pub(crate) fn drain_audio_events() {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    logger.audio_events.drain(|event| {
+        log_event(
+            event.level,
+            LogStage::Audio,
+            format_args!("{}", event.message),
+        )
+    });
+}
+
+/// Logs an event, e.g. `log!(Warn, Watch, "Failed to watch {path:?}")`.
+macro_rules! log {
+    ($level:ident, $stage:ident, $($arg:tt)+) => {
+        $crate::log::log_event(
+            $crate::log::LogLevel::$level,
+            $crate::log::LogStage::$stage,
+            format_args!($($arg)+),
+        )
+    };
+}
+
+pub(crate) use log;
+
+/// The default sink: writes to stderr, and to a log file that is rotated once it gets too large.
+struct FileLogSink {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl FileLogSink {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, event: &LogEvent) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let needs_rotation = match file.as_ref() {
+            Some(file) => file.metadata()?.len() >= MAX_LOG_FILE_SIZE,
+            None => false,
+        };
+
+        if needs_rotation {
+            *file = None;
+            self.rotate();
+        }
+
+        let file = match file.as_mut() {
+            Some(file) => file,
+            None => file.insert(File::options().create(true).append(true).open(&self.path)?),
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        writeln!(
+            file,
+            "{}.{:03} {event}",
+            timestamp.as_secs(),
+            timestamp.subsec_millis()
+        )
+    }
+
+    /// Shifts all log files by one, dropping the oldest one.
+    fn rotate(&self) {
+        for index in (1..ROTATED_LOG_FILE_COUNT).rev() {
+            let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+
+        let _ = std::fs::rename(&self.path, self.rotated_path(1));
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+impl LogSink for FileLogSink {
+    fn log(&self, event: &LogEvent) {
+        eprintln!("[CLAP PLUGIN HOT RELOADER] {event}");
+
+        // Don't report the error through the sink itself, it would only fail again.
+        let _ = self.write(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_events_are_drained_in_order() {
+        let queue = AudioEventQueue::with_capacity(4);
+        queue.push(LogLevel::Error, "first");
+        queue.push(LogLevel::Error, "second");
+
+        // Taking the first event frees the first slot, which the next event then lands in.
+        let first = queue.events.drain().next().unwrap();
+        assert_eq!(first.message, "first");
+        queue.push(LogLevel::Error, "third");
+
+        let mut messages = Vec::new();
+        queue.drain(|event| messages.push(event.message));
+        assert_eq!(messages, ["second", "third"]);
+    }
+}
@@ -0,0 +1,102 @@
+#![allow(unsafe_code)] // Needed to share the migration table through a symbol
+
+use clack_plugin::utils::ClapId;
+use std::sync::Arc;
+
+/// A parameter that was renamed or renumbered between two builds of a plugin.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParamIdMigration {
+    /// The ID of the parameter in the previous build.
+    pub from: u32,
+    /// The ID of the same parameter in the current build.
+    pub to: u32,
+}
+
+/// The table of parameter ID migrations exported by a plugin bundle.
+///
+/// This should not be used directly, but through the
+/// [`export_param_migrations!`](crate::export_param_migrations) macro.
+#[repr(C)]
+pub struct ParamIdMigrations {
+    migrations: *const ParamIdMigration,
+    len: usize,
+}
+
+// SAFETY: this only ever points to immutable, 'static data.
+unsafe impl Sync for ParamIdMigrations {}
+
+impl ParamIdMigrations {
+    pub const fn new(migrations: &'static [ParamIdMigration]) -> Self {
+        Self {
+            migrations: migrations.as_ptr(),
+            len: migrations.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The library this table was loaded from must still be loaded.
+    pub(crate) unsafe fn read(&self) -> ParamMigrationTable {
+        if self.migrations.is_null() {
+            return ParamMigrationTable::default();
+        }
+
+        let migrations = core::slice::from_raw_parts(self.migrations, self.len);
+
+        ParamMigrationTable {
+            migrations: migrations
+                .iter()
+                .filter(|m| m.from != u32::MAX && m.to != u32::MAX)
+                .map(|m| (ClapId::new(m.from), ClapId::new(m.to)))
+                .collect(),
+        }
+    }
+}
+
+/// An owned copy of the parameter ID migrations of a bundle.
+#[derive(Clone)]
+pub struct ParamMigrationTable {
+    migrations: Arc<[(ClapId, ClapId)]>,
+}
+
+impl Default for ParamMigrationTable {
+    fn default() -> Self {
+        Self {
+            migrations: Arc::new([]),
+        }
+    }
+}
+
+impl ParamMigrationTable {
+    /// Returns the new ID of the given parameter, if it was migrated.
+    pub fn migrate(&self, previous_id: ClapId) -> Option<ClapId> {
+        self.migrations
+            .iter()
+            .find(|(from, _)| *from == previous_id)
+            .map(|(_, to)| *to)
+    }
+}
+
+/// Declares parameters that were renumbered since the previous build of the plugin.
+///
+/// When the plugin is hot-reloaded, the wrapper will keep presenting parameters under their
+/// previous ID to the host, so that automation and mappings are kept. All events and queries
+/// are routed to the new IDs.
+///
+/// ```ignore
+/// // Parameter 1 is now 10, and parameter 2 is now 20.
+/// clap_hot_reload::export_param_migrations!(1 => 10, 2 => 20);
+/// ```
+#[macro_export]
+macro_rules! export_param_migrations {
+    ($($from:expr => $to:expr),* $(,)?) => {
+        #[allow(non_upper_case_globals, missing_docs)]
+        #[allow(unsafe_code)] // For #[no_mangle], which the wrapper looks the table up by.
+        #[no_mangle]
+        pub static __clack_hotreload_param_migrations: $crate::ParamIdMigrations =
+            $crate::ParamIdMigrations::new(&[
+                $($crate::ParamIdMigration { from: $from, to: $to }),*
+            ]);
+    };
+}
@@ -0,0 +1,277 @@
+#![allow(unsafe_code)] // Needed to implement the extension's C ABI
+
+//! A custom CLAP extension that tells the wrapped plugin about hot-reloads.
+//!
+//! The hot-reloader implements [`HostReloadEvents`] for the plugin instances it wraps: plugins can
+//! query it during initialization to know whether they are replacing an instance from a previous
+//! build, e.g. to skip expensive warmups. Plugins can also implement [`PluginReloadEvents`] to be
+//! told when they are about to be replaced.
+//!
+//! The host side is only available to plugin instances created by the hot-reloader.
+
+use crate::util::cstr;
+use clack_host::extensions::wrapper::HostWrapper;
+use clack_host::prelude::{HostHandlers, PluginMainThreadHandle};
+use clack_plugin::extensions::wrapper::PluginWrapper;
+use clack_plugin::extensions::{
+    Extension, ExtensionImplementation, HostExtensionSide, PluginExtensionSide, RawExtension,
+    RawExtensionImplementation,
+};
+use clack_plugin::host::HostMainThreadHandle;
+use clack_plugin::plugin::Plugin;
+use clap_sys::host::clap_host;
+use clap_sys::plugin::clap_plugin;
+use clap_sys::string_sizes::CLAP_NAME_SIZE;
+use std::ffi::{c_char, CStr};
+
+/// The ID of both the host and plugin sides of the reload events extension.
+pub const CLAP_EXT_RELOAD_EVENTS: &CStr = cstr(b"clap-hot-reload.reload-events/1\0");
+
+/// Information about the bundle the previous instance was loaded from.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct clap_reload_bundle_info {
+    /// The version of the plugin in the previous bundle, as in its descriptor.
+    /// This is an empty string if it is unknown.
+    pub plugin_version: [c_char; CLAP_NAME_SIZE],
+    /// Whether `hash` is set.
+    pub has_hash: bool,
+    /// The BLAKE3 hash of the previous bundle's binary.
+    pub hash: [u8; 32],
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct clap_host_reload_events {
+    /// Returns true if this instance is replacing one from a previous build. Its state will be
+    /// loaded right after initialization, if the previous instance supports saving it.
+    /// [main-thread]
+    pub is_replacement: Option<unsafe extern "C" fn(host: *const clap_host) -> bool>,
+    /// Fills the information about the bundle of the replaced instance. Returns false if this
+    /// instance isn't replacing another one.
+    /// [main-thread]
+    pub get_previous_bundle: Option<
+        unsafe extern "C" fn(host: *const clap_host, info: *mut clap_reload_bundle_info) -> bool,
+    >,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct clap_plugin_reload_events {
+    /// Called when this instance is about to be replaced by one from a newer build. Its state is
+    /// saved right after.
+    /// [main-thread]
+    pub will_swap_out: Option<unsafe extern "C" fn(plugin: *const clap_plugin)>,
+    /// Called when the replacement announced by `will_swap_out` failed. This instance stays in use.
+    /// [main-thread]
+    pub swap_out_cancelled: Option<unsafe extern "C" fn(plugin: *const clap_plugin)>,
+}
+
+/// Information about the bundle the previous instance was loaded from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PreviousBundleInfo {
+    /// The version of the plugin in the previous bundle, if its descriptor had one.
+    pub plugin_version: Option<String>,
+    /// The BLAKE3 hash of the previous bundle's binary, if it is known.
+    pub hash: Option<[u8; 32]>,
+}
+
+impl PreviousBundleInfo {
+    fn write_to(&self, raw: &mut clap_reload_bundle_info) {
+        raw.plugin_version = [0; CLAP_NAME_SIZE];
+
+        let version = self
+            .plugin_version
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes();
+        // Always keep the last byte for the nul terminator.
+        let len = version.len().min(CLAP_NAME_SIZE - 1);
+
+        for (dst, src) in raw.plugin_version.iter_mut().zip(&version[..len]) {
+            *dst = *src as c_char;
+        }
+
+        raw.has_hash = self.hash.is_some();
+        raw.hash = self.hash.unwrap_or_default();
+    }
+
+    fn read_from(raw: &clap_reload_bundle_info) -> Self {
+        // SAFETY: the buffer is always nul-terminated, see write_to.
+        let version = unsafe { CStr::from_ptr(raw.plugin_version.as_ptr()) };
+
+        Self {
+            plugin_version: Some(version.to_string_lossy().into_owned()).filter(|v| !v.is_empty()),
+            hash: raw.has_hash.then_some(raw.hash),
+        }
+    }
+}
+
+/// The host side of the reload events extension, used by plugins.
+#[derive(Copy, Clone)]
+pub struct HostReloadEvents(RawExtension<HostExtensionSide, clap_host_reload_events>);
+
+// SAFETY: the type matches the extension ID.
+unsafe impl Extension for HostReloadEvents {
+    const IDENTIFIERS: &'static [&'static CStr] = &[CLAP_EXT_RELOAD_EVENTS];
+    type ExtensionSide = HostExtensionSide;
+
+    #[inline]
+    unsafe fn from_raw(raw: RawExtension<Self::ExtensionSide>) -> Self {
+        Self(raw.cast())
+    }
+}
+
+impl HostReloadEvents {
+    /// Returns true if this instance is replacing one from a previous build.
+    pub fn is_replacement(&self, host: &mut HostMainThreadHandle) -> bool {
+        match host.use_extension(&self.0).is_replacement {
+            // SAFETY: this type ensures the function pointer is valid.
+            Some(is_replacement) => unsafe { is_replacement(host.as_raw()) },
+            None => false,
+        }
+    }
+
+    /// Returns information about the bundle of the replaced instance, if there is one.
+    pub fn previous_bundle(&self, host: &mut HostMainThreadHandle) -> Option<PreviousBundleInfo> {
+        let get_previous_bundle = host.use_extension(&self.0).get_previous_bundle?;
+
+        let mut raw = clap_reload_bundle_info {
+            plugin_version: [0; CLAP_NAME_SIZE],
+            has_hash: false,
+            hash: [0; 32],
+        };
+
+        // SAFETY: this type ensures the function pointer is valid.
+        if !unsafe { get_previous_bundle(host.as_raw(), &mut raw) } {
+            return None;
+        }
+
+        // Don't trust the host to nul-terminate the string.
+        raw.plugin_version[CLAP_NAME_SIZE - 1] = 0;
+
+        Some(PreviousBundleInfo::read_from(&raw))
+    }
+}
+
+/// Implementation of the host side of the reload events extension.
+pub trait HostReloadEventsImpl {
+    fn is_replacement(&mut self) -> bool;
+    fn previous_bundle(&mut self) -> Option<PreviousBundleInfo>;
+}
+
+impl<H: HostHandlers> ExtensionImplementation<H> for HostReloadEvents
+where
+    for<'a> <H as HostHandlers>::MainThread<'a>: HostReloadEventsImpl,
+{
+    #[doc(hidden)]
+    const IMPLEMENTATION: RawExtensionImplementation =
+        RawExtensionImplementation::new(&clap_host_reload_events {
+            is_replacement: Some(is_replacement::<H>),
+            get_previous_bundle: Some(get_previous_bundle::<H>),
+        });
+}
+
+unsafe extern "C" fn is_replacement<H: HostHandlers>(host: *const clap_host) -> bool
+where
+    for<'a> <H as HostHandlers>::MainThread<'a>: HostReloadEventsImpl,
+{
+    HostWrapper::<H>::handle(host, |host| {
+        Ok(host.main_thread().as_mut().is_replacement())
+    })
+    .unwrap_or(false)
+}
+
+unsafe extern "C" fn get_previous_bundle<H: HostHandlers>(
+    host: *const clap_host,
+    info: *mut clap_reload_bundle_info,
+) -> bool
+where
+    for<'a> <H as HostHandlers>::MainThread<'a>: HostReloadEventsImpl,
+{
+    HostWrapper::<H>::handle(host, |host| {
+        let Some(info) = info.as_mut() else {
+            return Ok(false);
+        };
+
+        match host.main_thread().as_mut().previous_bundle() {
+            Some(previous_bundle) => {
+                previous_bundle.write_to(info);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// The plugin side of the reload events extension, used by the hot-reloader.
+#[derive(Copy, Clone)]
+pub struct PluginReloadEvents(RawExtension<PluginExtensionSide, clap_plugin_reload_events>);
+
+// SAFETY: the type matches the extension ID.
+unsafe impl Extension for PluginReloadEvents {
+    const IDENTIFIERS: &'static [&'static CStr] = &[CLAP_EXT_RELOAD_EVENTS];
+    type ExtensionSide = PluginExtensionSide;
+
+    #[inline]
+    unsafe fn from_raw(raw: RawExtension<Self::ExtensionSide>) -> Self {
+        Self(raw.cast())
+    }
+}
+
+impl PluginReloadEvents {
+    pub fn will_swap_out(&self, plugin: &mut PluginMainThreadHandle) {
+        if let Some(will_swap_out) = plugin.use_extension(&self.0).will_swap_out {
+            // SAFETY: this type ensures the function pointer is valid.
+            unsafe { will_swap_out(plugin.as_raw()) }
+        }
+    }
+
+    pub fn swap_out_cancelled(&self, plugin: &mut PluginMainThreadHandle) {
+        if let Some(swap_out_cancelled) = plugin.use_extension(&self.0).swap_out_cancelled {
+            // SAFETY: this type ensures the function pointer is valid.
+            unsafe { swap_out_cancelled(plugin.as_raw()) }
+        }
+    }
+}
+
+/// Implementation of the plugin side of the reload events extension.
+pub trait PluginReloadEventsImpl {
+    /// Called when this instance is about to be replaced by one from a newer build.
+    fn will_swap_out(&mut self);
+    /// Called when the replacement announced by [`will_swap_out`](Self::will_swap_out) failed.
+    fn swap_out_cancelled(&mut self);
+}
+
+impl<P: Plugin> ExtensionImplementation<P> for PluginReloadEvents
+where
+    for<'a> P::MainThread<'a>: PluginReloadEventsImpl,
+{
+    #[doc(hidden)]
+    const IMPLEMENTATION: RawExtensionImplementation =
+        RawExtensionImplementation::new(&clap_plugin_reload_events {
+            will_swap_out: Some(will_swap_out::<P>),
+            swap_out_cancelled: Some(swap_out_cancelled::<P>),
+        });
+}
+
+unsafe extern "C" fn will_swap_out<P: Plugin>(plugin: *const clap_plugin)
+where
+    for<'a> P::MainThread<'a>: PluginReloadEventsImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        plugin.main_thread().as_mut().will_swap_out();
+        Ok(())
+    });
+}
+
+unsafe extern "C" fn swap_out_cancelled<P: Plugin>(plugin: *const clap_plugin)
+where
+    for<'a> P::MainThread<'a>: PluginReloadEventsImpl,
+{
+    PluginWrapper::<P>::handle(plugin, |plugin| {
+        plugin.main_thread().as_mut().swap_out_cancelled();
+        Ok(())
+    });
+}
@@ -1,11 +1,13 @@
 use crate::_macro_utils::EntryDescriptor;
+use crate::param_migrations::{ParamIdMigrations, ParamMigrationTable};
 use clack_host::bundle::PluginBundle;
 use clack_plugin::entry::EntryLoadError;
 use libloading::Library;
 use std::ffi::CStr;
-use std::path::Path;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
-const fn cstr(bytes: &'static [u8]) -> &'static CStr {
+pub(crate) const fn cstr(bytes: &'static [u8]) -> &'static CStr {
     match CStr::from_bytes_with_nul(bytes) {
         Ok(str) => str,
         Err(_) => panic!(""),
@@ -13,12 +15,47 @@ const fn cstr(bytes: &'static [u8]) -> &'static CStr {
 }
 
 const WRAPPED_ENTRY_SYMBOL_NAME: &CStr = cstr(b"__clack_hotreload_wrapped_entry\0");
+const PARAM_MIGRATIONS_SYMBOL_NAME: &CStr = cstr(b"__clack_hotreload_param_migrations\0");
+
+/// A loaded plugin bundle, along with the hot-reloading metadata it exported.
+#[derive(Clone)]
+pub struct LoadedBundle {
+    bundle: PluginBundle,
+    pub param_migrations: ParamMigrationTable,
+    /// The hash of the bundle's binary, if it was loaded by the watcher.
+    pub file_hash: Option<[u8; 32]>,
+}
+
+impl LoadedBundle {
+    pub fn without_metadata(bundle: PluginBundle) -> Self {
+        Self {
+            bundle,
+            param_migrations: ParamMigrationTable::default(),
+            file_hash: None,
+        }
+    }
+
+    /// Returns true if both were loaded from the same library.
+    #[inline]
+    pub fn is_same_bundle(&self, other: &LoadedBundle) -> bool {
+        core::ptr::eq(self.raw_entry(), other.raw_entry())
+    }
+}
+
+impl Deref for LoadedBundle {
+    type Target = PluginBundle;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.bundle
+    }
+}
 
 #[allow(unsafe_code)]
 pub fn load_if_different_bundle(
     initial_entry: &EntryDescriptor,
     self_path: &Path,
-) -> Result<Option<PluginBundle>, EntryLoadError> {
+) -> Result<Option<LoadedBundle>, EntryLoadError> {
     let lib = unsafe { Library::new(self_path) }.map_err(|_| EntryLoadError)?;
 
     let symbol =
@@ -30,10 +67,80 @@ pub fn load_if_different_bundle(
         return Ok(None);
     }
 
+    // This symbol is optional: plugins without renamed parameters don't need to export it.
+    let param_migrations = unsafe {
+        lib.get::<*const ParamIdMigrations>(PARAM_MIGRATIONS_SYMBOL_NAME.to_bytes_with_nul())
+    }
+    .map(|symbol| unsafe { (**symbol).read() })
+    .unwrap_or_default();
+
     let bundle = unsafe {
         PluginBundle::load_from_symbol_in_library(self_path, lib, WRAPPED_ENTRY_SYMBOL_NAME)
     }
     .map_err(|_| EntryLoadError)?;
 
-    Ok(Some(bundle))
+    Ok(Some(LoadedBundle {
+        bundle,
+        param_migrations,
+        file_hash: None,
+    }))
+}
+
+/// Returns the path of the binary of a bundle.
+///
+/// CLAP bundles can either be a single file, or a directory containing the binary in
+/// `Contents/<platform>/`, like macOS bundles. This returns the given path as-is if it is not a
+/// directory, or if no binary could be found in it.
+pub fn bundle_executable_path(bundle_path: &Path) -> PathBuf {
+    if !bundle_path.is_dir() {
+        return bundle_path.to_path_buf();
+    }
+
+    let contents = bundle_path.join("Contents");
+    let platform_dirs = [
+        String::from("MacOS"),
+        format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        String::from(std::env::consts::OS),
+    ];
+
+    let Some(stem) = bundle_path.file_stem() else {
+        return bundle_path.to_path_buf();
+    };
+
+    for platform_dir in platform_dirs {
+        let dir = contents.join(platform_dir);
+
+        if !dir.is_dir() {
+            continue;
+        }
+
+        // Prefer a binary named after the bundle, but pick the only file if there is just one.
+        let mut candidates = ["", ".so", ".dylib", ".dll", ".clap"]
+            .iter()
+            .map(|extension| {
+                let mut file_name = stem.to_os_string();
+                file_name.push(extension);
+                dir.join(file_name)
+            });
+
+        if let Some(binary) = candidates.find(|path| path.is_file()) {
+            return binary;
+        }
+
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+
+        let files: Vec<_> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+
+        if let [binary] = files.as_slice() {
+            return binary.clone();
+        }
+    }
+
+    bundle_path.to_path_buf()
 }
@@ -1,65 +1,231 @@
-use crate::watcher::event_thread::WatcherEventThread;
+use crate::config::WatcherConfig;
+use crate::log::log;
+use crate::util::LoadedBundle;
+use crate::watcher::commands::{create_control_file, ControlSocket};
+use crate::watcher::event_thread::{
+    EventThreadHandler, ReloadRequest, ReloadThread, SharedEventThread, WatcherEventThread,
+};
 use crate::watcher::symlinks::{BundleSymlinkedPath, WatchResults};
-use clack_host::bundle::*;
-use notify_debouncer_full::notify::RecommendedWatcher;
-use notify_debouncer_full::{new_debouncer, Debouncer, FileIdMap};
+use notify_debouncer_full::notify::{PollWatcher, RecommendedWatcher, Watcher};
+use notify_debouncer_full::{new_debouncer_opt, notify, Debouncer, FileIdMap};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 // TODO: bikeshed
+mod commands;
 mod event_thread;
+mod history;
+mod readiness;
 mod symlinks;
 
 // TODO: bikeshed
 mod fanout;
 pub use fanout::*;
 
+/// The poll interval used when the OS's file notifications aren't available.
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+enum AnyDebouncer {
+    Native(Debouncer<RecommendedWatcher, FileIdMap>),
+    Poll(Debouncer<PollWatcher, FileIdMap>),
+}
+
+impl AnyDebouncer {
+    fn start(
+        debounce: Duration,
+        poll_interval: Option<Duration>,
+        handler: EventThreadHandler,
+    ) -> notify::Result<Self> {
+        match poll_interval {
+            None => new_debouncer_opt(
+                debounce,
+                None,
+                handler,
+                FileIdMap::new(),
+                notify::Config::default(),
+            )
+            .map(Self::Native),
+            Some(interval) => new_debouncer_opt(
+                debounce,
+                None,
+                handler,
+                FileIdMap::new(),
+                notify::Config::default().with_poll_interval(interval),
+            )
+            .map(Self::Poll),
+        }
+    }
+
+    fn watcher(&mut self) -> &mut dyn Watcher {
+        match self {
+            AnyDebouncer::Native(d) => d.watcher(),
+            AnyDebouncer::Poll(d) => d.watcher(),
+        }
+    }
+
+    fn stop(self) {
+        match self {
+            AnyDebouncer::Native(d) => d.stop(),
+            AnyDebouncer::Poll(d) => d.stop(),
+        }
+    }
+}
+
+/// The file watcher, shared with the event thread so it can update the watched paths.
+type SharedDebouncer = Arc<Mutex<Option<AnyDebouncer>>>;
+
 // TODO: bikeshed
 pub struct WatcherMaster {
-    notifier: Option<Debouncer<RecommendedWatcher, FileIdMap>>,
+    notifier: SharedDebouncer,
+    is_stopping: Arc<AtomicBool>,
     factory: BundleReceiverFactory,
+    _control_socket: Option<ControlSocket>,
+    _reload_thread: ReloadThread,
 }
 
 impl WatcherMaster {
-    pub fn new(initial_bundle: PluginBundle, bundle_path: &Path) -> Option<Self> {
-        let mut path = BundleSymlinkedPath::get_info(bundle_path.to_path_buf());
+    pub fn new(
+        initial_bundle: LoadedBundle,
+        bundle_path: &Path,
+        config: &WatcherConfig,
+    ) -> Option<Self> {
+        let mut results = WatchResults::empty();
+        let path = BundleSymlinkedPath::get_info(bundle_path.to_path_buf(), &mut results);
+        let extra_paths: Vec<_> = config
+            .extra_paths
+            .iter()
+            .map(|p| BundleSymlinkedPath::get_info(p.clone(), &mut results))
+            .collect();
+
+        let control_file = config.control_file.as_ref().and_then(|control_file| {
+            match create_control_file(control_file) {
+                Ok(()) => Some(BundleSymlinkedPath::get_info(
+                    control_file.clone(),
+                    &mut results,
+                )),
+                Err(e) => {
+                    log!(
+                        Error,
+                        Watch,
+                        "Failed to create control file {control_file:?}: {e}"
+                    );
+                    None
+                }
+            }
+        });
+
+        results.log_errors();
 
         let (producer, factory) = new_bundle_fanout(initial_bundle.clone());
+        let shared_notifier = SharedDebouncer::default();
+        let is_stopping = Arc::new(AtomicBool::new(false));
+        let (reload_requests, reload_receiver) = crossbeam_channel::unbounded();
 
-        let notifier = new_debouncer(
-            Duration::from_millis(250),
-            None,
-            WatcherEventThread::new(path.clone(), initial_bundle, producer),
-        );
+        let event_thread: SharedEventThread = Arc::new(Mutex::new(WatcherEventThread::new(
+            path.clone(),
+            extra_paths.clone(),
+            control_file.as_ref().map(|f| f.path().to_path_buf()),
+            config.history_size,
+            shared_notifier.clone(),
+            reload_requests.clone(),
+            initial_bundle,
+            producer,
+        )));
 
-        let notifier = match notifier {
-            Ok(mut w) => {
-                let mut results = WatchResults::empty();
-                path.watch_all(w.watcher(), &mut results);
-                results.log_errors();
+        // Roll back automatically if new instances fail. This is called from the plugin's main
+        // thread, which must not wait for the event thread: it may be busy loading a bundle.
+        factory.set_failure_handler(Box::new(move |failed_bundle| {
+            let _ = reload_requests.send(ReloadRequest::BundleFailed(failed_bundle.clone()));
+        }));
 
-                results.has_any_success().then_some(w)?
-            }
+        let reload_thread = match ReloadThread::start(
+            Arc::downgrade(&event_thread),
+            reload_receiver,
+            is_stopping.clone(),
+        ) {
+            Ok(thread) => thread,
             Err(e) => {
-                eprintln!("[CLAP PLUGIN HOT RELOADER] Failed to start file watcher: {e}");
+                log!(Error, Watch, "Failed to start reload thread: {e}");
                 return None;
             }
         };
 
+        let start = |poll_interval: Option<Duration>| {
+            let handler = EventThreadHandler(event_thread.clone());
+
+            let mut notifier = match AnyDebouncer::start(config.debounce, poll_interval, handler) {
+                Ok(notifier) => notifier,
+                Err(e) => {
+                    log!(Error, Watch, "Failed to start file watcher: {e}");
+                    return None;
+                }
+            };
+
+            let mut results = WatchResults::empty();
+            for path in [&path].into_iter().chain(&extra_paths).chain(&control_file) {
+                path.clone().watch_all(notifier.watcher(), &mut results);
+            }
+            results.log_errors();
+
+            if results.has_any_success() {
+                Some(notifier)
+            } else {
+                notifier.stop();
+                None
+            }
+        };
+
+        let notifier = match config.poll_interval {
+            Some(interval) => start(Some(interval))?,
+            None => start(None).or_else(|| {
+                log!(
+                    Warn,
+                    Watch,
+                    "File notifications unavailable, polling the bundle instead."
+                );
+                start(Some(FALLBACK_POLL_INTERVAL))
+            })?,
+        };
+
+        *shared_notifier.lock().unwrap() = Some(notifier);
+
+        let control_socket = config.control_socket.as_ref().and_then(|socket_path| {
+            ControlSocket::start(socket_path.clone(), event_thread, is_stopping.clone())
+                .inspect_err(|e| {
+                    log!(
+                        Error,
+                        Watch,
+                        "Failed to open control socket {socket_path:?}: {e}"
+                    )
+                })
+                .ok()
+        });
+
         Some(Self {
-            notifier: Some(notifier),
+            notifier: shared_notifier,
+            is_stopping,
             factory,
+            _control_socket: control_socket,
+            _reload_thread: reload_thread,
         })
     }
 
-    pub fn new_receiver(&self) -> BundleReceiver {
-        self.factory.new_receiver()
+    pub fn new_receiver(&self, notifier: Option<BundleNotifier>) -> BundleReceiver {
+        self.factory.new_receiver(notifier)
     }
 }
 
 impl Drop for WatcherMaster {
     fn drop(&mut self) {
-        if let Some(notifier) = self.notifier.take() {
+        // Interrupt any pending readiness check, so that stopping doesn't block.
+        self.is_stopping.store(true, Ordering::Relaxed);
+
+        // Don't hold the lock while stopping: the event thread may be waiting on it.
+        let notifier = self.notifier.lock().ok().and_then(|mut n| n.take());
+
+        if let Some(notifier) = notifier {
             notifier.stop()
         }
     }
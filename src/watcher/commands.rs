@@ -0,0 +1,192 @@
+use crate::log::log;
+use crate::watcher::event_thread::SharedEventThread;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// A command sent to the watcher through the control file or socket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatcherCommand {
+    /// Reloads the bundle as soon as it is ready, even if it didn't change.
+    Reload,
+    /// Stops reloading on file changes, e.g. while a build is in progress.
+    Pause,
+    /// Starts reloading on file changes again, and reloads if the bundle changed in the meantime.
+    Resume,
+    /// Goes back to the previously loaded bundle.
+    Revert,
+}
+
+impl WatcherCommand {
+    /// Parses a command. An empty command is a reload, so that touching the control file is
+    /// enough to trigger one.
+    pub fn parse(command: &str) -> Option<Self> {
+        match command.trim().to_ascii_lowercase().as_str() {
+            "" | "reload" => Some(Self::Reload),
+            "pause" => Some(Self::Pause),
+            "resume" => Some(Self::Resume),
+            "revert" => Some(Self::Revert),
+            _ => None,
+        }
+    }
+}
+
+/// Creates the control file if it doesn't exist, so that it can be watched and touched.
+pub fn create_control_file(path: &Path) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(|_| ())
+}
+
+/// A local socket accepting one command per line, and answering `ok` or an error message.
+pub struct ControlSocket {
+    path: PathBuf,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlSocket {
+    #[cfg(unix)]
+    pub fn start(
+        path: PathBuf,
+        event_thread: SharedEventThread,
+        is_stopping: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        use std::os::unix::net::UnixListener;
+
+        // Remove a stale socket from a previous session
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        let thread = std::thread::Builder::new()
+            .name("clap-hot-reload-control".into())
+            .spawn(move || unix::run(listener, event_thread, is_stopping))?;
+
+        Ok(Self {
+            path,
+            thread: Some(thread),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn start(
+        _path: PathBuf,
+        _event_thread: SharedEventThread,
+        _is_stopping: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Control sockets are only supported on Unix",
+        ))
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        // The thread stops on its own once the watcher is stopping.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    /// How often the socket checks whether the watcher is stopping, while idle.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    /// How long a client can stay idle before being disconnected.
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn run(
+        listener: UnixListener,
+        event_thread: SharedEventThread,
+        is_stopping: Arc<AtomicBool>,
+    ) {
+        while !is_stopping.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = handle_client(stream, &event_thread, &is_stopping) {
+                        log!(Warn, Watch, "Control socket error: {e}");
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+                Err(e) => {
+                    log!(Warn, Watch, "Control socket error: {e}");
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+            }
+        }
+    }
+
+    fn handle_client(
+        stream: UnixStream,
+        event_thread: &SharedEventThread,
+        is_stopping: &AtomicBool,
+    ) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        // Reads time out quickly, so that an idle client doesn't delay stopping the watcher.
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        let mut last_activity = Instant::now();
+
+        while !is_stopping.load(Ordering::Relaxed) {
+            // On timeouts, the partially read line is kept and completed by the next read.
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => last_activity = Instant::now(),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if last_activity.elapsed() > CLIENT_TIMEOUT {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Client is idle"));
+                    }
+
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            let result = match WatcherCommand::parse(&line) {
+                Some(command) => match event_thread.lock() {
+                    Ok(mut event_thread) => event_thread.handle_command(command),
+                    Err(_) => Err(io::Error::other("Watcher is unavailable")),
+                },
+                None => Err(io::Error::other(format!(
+                    "Unknown command: {}",
+                    line.trim()
+                ))),
+            };
+
+            line.clear();
+
+            match result {
+                Ok(()) => writeln!(writer, "ok")?,
+                Err(e) => writeln!(writer, "error: {e}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,25 +1,33 @@
-use crate::util::load_if_different_bundle;
-use crate::watcher::symlinks::BundleSymlinkedPath;
-use crate::watcher::BundleProducer;
+use crate::log::log;
+use crate::util::{bundle_executable_path, load_if_different_bundle, LoadedBundle};
+use crate::watcher::commands::WatcherCommand;
+use crate::watcher::history::BundleHistory;
+use crate::watcher::readiness::wait_until_ready;
+use crate::watcher::symlinks::{BundleSymlinkedPath, WatchResults};
+use crate::watcher::{BundleProducer, SharedDebouncer};
 use blake3::{Hash, Hasher};
-use clack_host::prelude::PluginBundle;
 use clack_plugin::prelude::EntryDescriptor;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use notify_debouncer_full::notify::Error;
 use notify_debouncer_full::{DebounceEventHandler, DebounceEventResult, DebouncedEvent};
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use tempfile::NamedTempFile;
 
 struct PluginBundleFile {
-    bundle: PluginBundle,
+    bundle: LoadedBundle,
     file_hash: Option<Hash>,
     temp_file: Option<NamedTempFile>,
 }
 
 impl PluginBundleFile {
-    pub fn new_fileless(bundle: PluginBundle) -> Self {
+    pub fn new_fileless(bundle: LoadedBundle) -> Self {
         Self {
             bundle,
             file_hash: None,
@@ -44,7 +52,7 @@ impl PluginBundleFile {
                 Some(h)
             }
             Err(e) => {
-                eprintln!("Failed to compute hash for {path:?}: {e}");
+                log!(Warn, Reload, "Failed to compute hash for {path:?}: {e}");
                 None
             }
         };
@@ -52,18 +60,24 @@ impl PluginBundleFile {
         // Now copy to a tempfile
         let tempfile = create_tempfile_copy(path)?;
 
-        let bundle = match load_if_different_bundle(current_entry, tempfile.path()) {
+        let mut bundle = match load_if_different_bundle(current_entry, tempfile.path()) {
             Ok(Some(bundle)) => bundle,
             Ok(None) => {
-                println!("File changed but points to same bundle, not reloading plugins.");
+                log!(
+                    Info,
+                    Reload,
+                    "File changed but points to same bundle, not reloading plugins."
+                );
                 return Ok(None);
             }
             Err(e) => {
-                eprintln!("Failed to hot-load new bundle : {e}");
+                log!(Error, Reload, "Failed to hot-load new bundle: {e}");
                 return Err(io::Error::other(e));
             }
         };
 
+        bundle.file_hash = file_hash.map(|hash| *hash.as_bytes());
+
         Ok(Some(Self {
             bundle,
             file_hash,
@@ -77,7 +91,7 @@ impl Drop for PluginBundleFile {
         if let Some(file) = self.temp_file.take() {
             let path = file.path().to_path_buf();
             if let Err(e) = file.close() {
-                eprintln!("Failed to remove temp file {path:?}: {e}")
+                log!(Warn, Reload, "Failed to remove temp file {path:?}: {e}")
             }
         }
     }
@@ -85,15 +99,37 @@ impl Drop for PluginBundleFile {
 
 pub struct WatcherEventThread {
     bundle_path: BundleSymlinkedPath,
+    /// Additional paths that trigger a check for a new bundle.
+    extra_paths: Vec<BundleSymlinkedPath>,
+    /// A file containing a command to run whenever it is modified.
+    control_file: Option<PathBuf>,
+    notifier: SharedDebouncer,
+    /// Reloads waiting for the reload thread.
+    reload_requests: Sender<ReloadRequest>,
     current_bundle: PluginBundleFile,
+    /// Previously loaded bundles that can be rolled back to.
+    history: BundleHistory<PluginBundleFile>,
     producer: BundleProducer,
+    is_paused: bool,
+    /// Set if the bundle changed while reloading was paused.
+    has_pending_changes: bool,
 }
 
-impl DebounceEventHandler for WatcherEventThread {
+/// The event thread state is shared with the control socket, so that commands and file
+/// events are handled one at a time.
+pub type SharedEventThread = Arc<Mutex<WatcherEventThread>>;
+
+pub struct EventThreadHandler(pub SharedEventThread);
+
+impl DebounceEventHandler for EventThreadHandler {
     fn handle_event(&mut self, event: DebounceEventResult) {
+        let Ok(mut event_thread) = self.0.lock() else {
+            return;
+        };
+
         match event {
-            Ok(events) => self.handle_updates(events),
-            Err(errors) => self.handle_errors(errors),
+            Ok(events) => event_thread.handle_updates(events),
+            Err(errors) => event_thread.handle_errors(errors),
         }
     }
 }
@@ -101,24 +137,122 @@ impl DebounceEventHandler for WatcherEventThread {
 impl WatcherEventThread {
     pub fn new(
         bundle_path: BundleSymlinkedPath,
-        initial_bundle: PluginBundle,
+        extra_paths: Vec<BundleSymlinkedPath>,
+        control_file: Option<PathBuf>,
+        history_size: usize,
+        notifier: SharedDebouncer,
+        reload_requests: Sender<ReloadRequest>,
+        initial_bundle: LoadedBundle,
         producer: BundleProducer,
     ) -> Self {
-        println!("New event thread reload started");
         Self {
             bundle_path,
+            extra_paths,
+            control_file,
+            notifier,
+            reload_requests,
             current_bundle: PluginBundleFile::new_fileless(initial_bundle),
+            history: BundleHistory::new(history_size),
             producer,
+            is_paused: false,
+            has_pending_changes: false,
+        }
+    }
+
+    pub fn handle_command(&mut self, command: WatcherCommand) -> io::Result<()> {
+        log!(Info, Watch, "Received command: {command:?}");
+
+        match command {
+            WatcherCommand::Reload => {
+                self.request_reload(true);
+                Ok(())
+            }
+            WatcherCommand::Pause => {
+                self.is_paused = true;
+                Ok(())
+            }
+            WatcherCommand::Resume => {
+                self.is_paused = false;
+
+                if core::mem::take(&mut self.has_pending_changes) {
+                    self.request_reload(false);
+                }
+
+                Ok(())
+            }
+            WatcherCommand::Revert => self.roll_back(),
+        }
+    }
+
+    /// Goes back to the most recent bundle in the history, discarding the current one.
+    fn roll_back(&mut self) -> io::Result<()> {
+        let Some(previous_bundle) = self.history.pop() else {
+            return Err(io::Error::other("No previous bundle to revert to"));
+        };
+
+        log!(
+            Info,
+            Reload,
+            "Rolling back to previous bundle. {} more in history.",
+            self.history.len()
+        );
+
+        self.current_bundle = previous_bundle;
+        self.producer.produce(&self.current_bundle.bundle);
+
+        Ok(())
+    }
+
+    /// Rolls back if the bundle that failed is still the current one. All instances report the
+    /// same failure, but only the first report is acted upon.
+    pub fn handle_bundle_failure(&mut self, failed_bundle: &LoadedBundle) {
+        if !failed_bundle.is_same_bundle(&self.current_bundle.bundle) {
+            return;
+        }
+
+        if let Err(e) = self.roll_back() {
+            log!(Error, Reload, "Failed to roll back: {e}");
+        }
+    }
+
+    fn handle_control_file_update(&mut self, control_file: &Path) {
+        let command = match std::fs::read_to_string(control_file) {
+            Ok(contents) => contents,
+            // The file was probably just removed.
+            Err(_) => return,
+        };
+
+        let result = match WatcherCommand::parse(&command) {
+            Some(command) => self.handle_command(command),
+            None => Err(io::Error::other(format!(
+                "Unknown command: {}",
+                command.trim()
+            ))),
+        };
+
+        if let Err(e) = result {
+            log!(Error, Watch, "Failed to run command from control file: {e}");
         }
     }
 
     fn handle_errors(&mut self, errors: Vec<Error>) {
-        // TODO
-        eprintln!("Watcher errors: {errors:?}")
+        for error in errors {
+            log!(Error, Watch, "Watcher error: {error}");
+        }
     }
 
     fn handle_updates(&mut self, updates: Vec<DebouncedEvent>) {
-        // TODO: for now, we ignore updating the symlink list, we just watch for events.
+        if let Some(control_file) = self.control_file.clone() {
+            let control_file_updated = updates
+                .iter()
+                .filter(|event| event.kind.is_create() || event.kind.is_modify())
+                .flat_map(|event| &event.paths)
+                .any(|event_path| *event_path == control_file);
+
+            if control_file_updated {
+                self.handle_control_file_update(&control_file);
+            }
+        }
 
         let something_updated = updates
             .iter()
@@ -126,41 +260,208 @@ impl WatcherEventThread {
             .any(|event_path| {
                 self.bundle_path
                     .iter()
-                    .any(|watched_path| watched_path.path() == event_path)
+                    .chain(self.extra_paths.iter().flat_map(|p| p.iter()))
+                    .any(|watched_path| watched_path.contains(event_path))
             });
 
         if !something_updated {
             return;
         }
 
-        println!("File changed! : {updates:?}");
+        log!(Debug, Watch, "File changed: {updates:?}");
+
+        if self.is_paused {
+            self.has_pending_changes = true;
+            return;
+        }
+
+        self.request_reload(false);
+    }
 
-        let bundle_file = self.bundle_path.path();
+    /// Queues a reload on the reload thread. Unless `force` is set, the bundle is only reloaded
+    /// if its file changed.
+    fn request_reload(&self, force: bool) {
+        // The reload thread only stops once the watcher is stopping.
+        let _ = self.reload_requests.send(ReloadRequest::Reload { force });
+    }
+
+    /// Returns the path of the bundle binary, after following any retargeted symlink.
+    fn bundle_file(&mut self) -> PathBuf {
+        self.refresh_symlink_chain();
+
+        // Bundles may be directories: only hash and copy the actual binary.
+        bundle_executable_path(self.bundle_path.path())
+    }
+
+    /// Checks for a new bundle, and sends it to all plugin instances if there is one.
+    ///
+    /// Unless `force` is set, the bundle is only reloaded if its file changed.
+    fn reload(&mut self, bundle_file: &Path, force: bool) -> io::Result<()> {
+        let current_hash = if force {
+            None
+        } else {
+            self.current_bundle.file_hash
+        };
 
         let new_bundle = PluginBundleFile::new_compare_to_hash(
             bundle_file,
-            self.current_bundle.file_hash,
+            current_hash,
             self.current_bundle.bundle.raw_entry(),
         );
 
         let new_bundle = match new_bundle {
             Ok(Some(bundle)) => bundle,
             Ok(None) => {
-                println!("File changed but points to same bundle, not reloading plugins.");
-                return;
+                log!(
+                    Info,
+                    Reload,
+                    "File changed but points to same bundle, not reloading plugins."
+                );
+                return Ok(());
             }
             Err(e) => {
-                eprintln!("Failed to hot-load new bundle: {e}");
-                return;
+                log!(Error, Reload, "Failed to hot-load new bundle: {e}");
+                return Err(e);
             }
         };
 
-        println!(
-            "New bundle found.  CLAP version: {}",
+        log!(
+            Info,
+            Reload,
+            "New bundle found. CLAP version: {}",
             new_bundle.bundle.version()
         );
-        self.current_bundle = new_bundle;
+        let previous_bundle = core::mem::replace(&mut self.current_bundle, new_bundle);
+
+        self.history.push(previous_bundle);
+
         self.producer.produce(&self.current_bundle.bundle);
+
+        Ok(())
+    }
+
+    /// Rebuilds the symlink chain in case one of its links was retargeted, and updates the
+    /// watched paths accordingly.
+    fn refresh_symlink_chain(&mut self) {
+        let mut results = WatchResults::empty();
+        let mut new_path =
+            BundleSymlinkedPath::get_info(self.bundle_path.path().to_path_buf(), &mut results);
+
+        if new_path.has_same_chain(&self.bundle_path) {
+            return;
+        }
+
+        let Ok(mut notifier) = self.notifier.lock() else {
+            return;
+        };
+
+        // Not fully started yet, or shutting down.
+        let Some(notifier) = notifier.as_mut() else {
+            return;
+        };
+
+        self.bundle_path.unwatch_all(notifier.watcher());
+        new_path.watch_all(notifier.watcher(), &mut results);
+        results.log_errors();
+
+        self.bundle_path = new_path;
+    }
+}
+
+/// Work for the [`ReloadThread`].
+pub enum ReloadRequest {
+    /// Checks for a new bundle. Unless `force` is set, it is only reloaded if its file changed.
+    Reload { force: bool },
+    /// Plugin instances failed with the given bundle, rolling back from it if it's still current.
+    BundleFailed(LoadedBundle),
+}
+
+/// How often the reload thread checks whether the watcher is stopping, while idle.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A thread waiting for bundle binaries to be ready before reloading them.
+///
+/// Waiting for a build can take minutes, so it doesn't happen on the file watcher's thread nor
+/// with the event thread locked: file events and commands are still handled in the meantime.
+pub struct ReloadThread {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReloadThread {
+    pub fn start(
+        event_thread: Weak<Mutex<WatcherEventThread>>,
+        requests: Receiver<ReloadRequest>,
+        is_stopping: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let thread = std::thread::Builder::new()
+            .name("clap-hot-reload-reload".into())
+            .spawn(move || run_reload_thread(event_thread, requests, is_stopping))?;
+
+        Ok(Self {
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ReloadThread {
+    fn drop(&mut self) {
+        // The thread stops on its own once the watcher is stopping.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_reload_thread(
+    event_thread: Weak<Mutex<WatcherEventThread>>,
+    requests: Receiver<ReloadRequest>,
+    is_stopping: Arc<AtomicBool>,
+) {
+    while !is_stopping.load(Ordering::Relaxed) {
+        let request = match requests.recv_timeout(STOP_CHECK_INTERVAL) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let Some(event_thread) = event_thread.upgrade() else {
+            return;
+        };
+
+        // Requests made while waiting are all handled by a single reload.
+        let mut reload = None;
+        for request in std::iter::once(request).chain(requests.try_iter()) {
+            match request {
+                ReloadRequest::Reload { force } => reload = Some(force || reload == Some(true)),
+                ReloadRequest::BundleFailed(failed_bundle) => {
+                    if let Ok(mut event_thread) = event_thread.lock() {
+                        event_thread.handle_bundle_failure(&failed_bundle);
+                    }
+                }
+            }
+        }
+
+        let Some(force) = reload else {
+            continue;
+        };
+
+        let Some(bundle_file) = event_thread.lock().ok().map(|mut t| t.bundle_file()) else {
+            return;
+        };
+
+        // Don't pick up a binary the build is still writing.
+        if let Err(e) = wait_until_ready(&bundle_file, &is_stopping) {
+            log!(
+                Warn,
+                Reload,
+                "Bundle is not ready, not reloading plugins: {e}"
+            );
+            continue;
+        }
+
+        if let Ok(mut event_thread) = event_thread.lock() {
+            let _ = event_thread.reload(&bundle_file, force);
+        }
     }
 }
 
@@ -1,11 +1,29 @@
-use clack_host::prelude::PluginBundle;
+use crate::util::LoadedBundle;
 use crossbeam_channel::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 
+/// A callback notifying a receiver that a new bundle is available.
+///
+/// This is called from the watcher thread.
+pub type BundleNotifier = Box<dyn Fn() + Send>;
+
+/// A callback notifying the watcher that a bundle could not be instantiated or activated.
+///
+/// This is called from the main thread of the plugin instance that failed.
+pub type BundleFailureHandler = Box<dyn Fn(&LoadedBundle) + Send>;
+
+struct ReceiverEntry {
+    id: u64,
+    sender: Sender<LoadedBundle>,
+    notifier: Option<BundleNotifier>,
+}
+
 // TODO: bikeshed
 struct BundleFanoutInner {
-    current_bundle: PluginBundle,
-    senders: Vec<Sender<PluginBundle>>,
+    current_bundle: LoadedBundle,
+    receivers: Vec<ReceiverEntry>,
+    next_receiver_id: u64,
+    failure_handler: Option<BundleFailureHandler>,
 }
 
 pub struct BundleProducer {
@@ -13,14 +31,20 @@ pub struct BundleProducer {
 }
 
 impl BundleProducer {
-    pub fn produce(&mut self, new_bundle: &PluginBundle) {
+    pub fn produce(&mut self, new_bundle: &LoadedBundle) {
         let mut inner = self.inner.lock().unwrap();
         inner.current_bundle = new_bundle.clone();
 
         // Remove disconnected senders
         inner
-            .senders
-            .retain_mut(|sender| sender.send(new_bundle.clone()).is_ok());
+            .receivers
+            .retain_mut(|receiver| receiver.sender.send(new_bundle.clone()).is_ok());
+
+        for receiver in &inner.receivers {
+            if let Some(notifier) = &receiver.notifier {
+                notifier()
+            }
+        }
     }
 }
 
@@ -29,14 +53,27 @@ pub struct BundleReceiverFactory {
 }
 
 impl BundleReceiverFactory {
-    pub fn new_receiver(&self) -> BundleReceiver {
+    pub fn set_failure_handler(&self, handler: BundleFailureHandler) {
+        self.inner.lock().unwrap().failure_handler = Some(handler);
+    }
+
+    pub fn new_receiver(&self, notifier: Option<BundleNotifier>) -> BundleReceiver {
         let mut inner = self.inner.lock().unwrap();
 
         let current_bundle = inner.current_bundle.clone();
         let (sender, receiver) = crossbeam_channel::unbounded();
-        inner.senders.push(sender);
+
+        let id = inner.next_receiver_id;
+        inner.next_receiver_id += 1;
+        inner.receivers.push(ReceiverEntry {
+            id,
+            sender,
+            notifier,
+        });
 
         BundleReceiver {
+            id,
+            inner: self.inner.clone(),
             current_bundle,
             receiver,
         }
@@ -44,31 +81,59 @@ impl BundleReceiverFactory {
 }
 
 pub struct BundleReceiver {
-    current_bundle: PluginBundle,
-    receiver: Receiver<PluginBundle>,
+    id: u64,
+    inner: Arc<Mutex<BundleFanoutInner>>,
+    /// The bundle the plugin instance is currently running.
+    current_bundle: LoadedBundle,
+    receiver: Receiver<LoadedBundle>,
 }
 
 impl BundleReceiver {
-    pub fn current_bundle(&self) -> &PluginBundle {
+    pub fn current_bundle(&self) -> &LoadedBundle {
         &self.current_bundle
     }
 
-    pub fn receive_new_bundle(&mut self) -> bool {
-        let mut has_received = false;
+    /// Marks the given bundle as the one the plugin instance is now running.
+    pub fn set_current_bundle(&mut self, bundle: LoadedBundle) {
+        self.current_bundle = bundle;
+    }
 
-        while let Ok(bundle) = self.receiver.try_recv() {
-            self.current_bundle = bundle;
-            has_received = true;
+    /// Reports that the given bundle could not be used, so that the watcher can roll back to a
+    /// previous one.
+    pub fn report_failure(&self, failed_bundle: &LoadedBundle) {
+        if let Ok(inner) = self.inner.lock() {
+            if let Some(handler) = &inner.failure_handler {
+                handler(failed_bundle)
+            }
         }
+    }
+
+    /// Returns the latest bundle produced by the watcher, if it isn't the one currently running.
+    ///
+    /// A bundle that failed to load isn't kept around: it is only retried if the watcher produces
+    /// it again.
+    pub fn receive_new_bundle(&mut self) -> Option<LoadedBundle> {
+        let latest = self.receiver.try_iter().last()?;
 
-        has_received
+        (!latest.is_same_bundle(&self.current_bundle)).then_some(latest)
+    }
+}
+
+impl Drop for BundleReceiver {
+    fn drop(&mut self) {
+        // Unregister right away, so that the notifier is never called past this point.
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.receivers.retain(|r| r.id != self.id);
+        }
     }
 }
 
-pub fn new_bundle_fanout(initial_bundle: PluginBundle) -> (BundleProducer, BundleReceiverFactory) {
+pub fn new_bundle_fanout(initial_bundle: LoadedBundle) -> (BundleProducer, BundleReceiverFactory) {
     let inner = Arc::new(Mutex::new(BundleFanoutInner {
         current_bundle: initial_bundle,
-        senders: Vec::new(),
+        receivers: Vec::new(),
+        next_receiver_id: 0,
+        failure_handler: None,
     }));
 
     (
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+/// Previously loaded bundles that can be rolled back to, up to a maximum count.
+pub struct BundleHistory<T> {
+    /// The most recent last.
+    entries: VecDeque<T>,
+    max_size: usize,
+}
+
+impl<T> BundleHistory<T> {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(max_size),
+            max_size,
+        }
+    }
+
+    /// Adds a bundle that was just replaced, dropping the oldest one if the history is full.
+    pub fn push(&mut self, previous_bundle: T) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.max_size {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(previous_bundle);
+    }
+
+    /// Removes the most recent bundle from the history, to roll back to it.
+    pub fn pop(&mut self) -> Option<T> {
+        self.entries.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_back_to_the_most_recent_bundle_first() {
+        let mut history = BundleHistory::new(4);
+        history.push(1);
+        history.push(2);
+        history.push(3);
+
+        assert_eq!(history.pop(), Some(3));
+        assert_eq!(history.pop(), Some(2));
+
+        // Reloading after a rollback keeps the older bundles.
+        history.push(4);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop(), Some(4));
+        assert_eq!(history.pop(), Some(1));
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn drops_the_oldest_bundles_when_full() {
+        let mut history = BundleHistory::new(2);
+        for bundle in 1..=5 {
+            history.push(bundle);
+        }
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop(), Some(5));
+        assert_eq!(history.pop(), Some(4));
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn empty_history_keeps_nothing() {
+        let mut history = BundleHistory::new(0);
+        history.push(1);
+
+        assert_eq!(history.len(), 0);
+        assert_eq!(history.pop(), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Checks that a bundle binary is completely written before trying to load it.
+//!
+//! Build tools usually write large binaries in multiple chunks, and file change events can be
+//! received long before they are done.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Time between two samples of the file's metadata.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+/// How many consecutive samples must be identical for the file to be considered stable.
+const STABLE_SAMPLE_COUNT: usize = 3;
+/// How long to wait for a build to complete before giving up.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Cargo holds a lock on this file in the output directory while building.
+const CARGO_LOCK_FILE_NAME: &str = ".cargo-lock";
+/// Appended to the binary's file name. Build scripts can create it while they are writing the
+/// bundle, and remove it when they are done.
+const BUILD_MARKER_EXTENSION: &str = "building";
+
+/// Blocks until the binary at the given path is ready to be loaded.
+///
+/// This returns an error if the file isn't ready before the timeout, if it is not a valid binary
+/// once stable, or if `is_stopping` is set in the meantime.
+pub fn wait_until_ready(path: &Path, is_stopping: &AtomicBool) -> io::Result<()> {
+    let deadline = Instant::now() + READINESS_TIMEOUT;
+    let mut last_sample = None;
+    let mut stable_samples = 0;
+
+    loop {
+        if is_stopping.load(Ordering::Relaxed) {
+            return Err(io::Error::other("Watcher is stopping"));
+        }
+
+        if Instant::now() > deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for the build to complete",
+            ));
+        }
+
+        // The build may remove the binary before writing it again.
+        let sample = match FileSample::read(path) {
+            Ok(sample) => Some(sample),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if sample.is_none() || is_build_in_progress(path) || last_sample != sample {
+            stable_samples = 0;
+        } else {
+            stable_samples += 1;
+        }
+
+        last_sample = sample;
+
+        if stable_samples + 1 >= STABLE_SAMPLE_COUNT {
+            return validate_binary(path);
+        }
+
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct FileSample {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileSample {
+    fn read(path: &Path) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+fn is_build_in_progress(path: &Path) -> bool {
+    let mut marker = path.as_os_str().to_os_string();
+    marker.push(".");
+    marker.push(BUILD_MARKER_EXTENSION);
+
+    if Path::new(&marker).exists() {
+        return true;
+    }
+
+    let Some(cargo_lock) = path.parent().map(|p| p.join(CARGO_LOCK_FILE_NAME)) else {
+        return false;
+    };
+
+    // The lock file is never removed by Cargo, only unlocked.
+    let Ok(file) = File::open(cargo_lock) else {
+        return false;
+    };
+
+    match file.try_lock_shared() {
+        Ok(()) => {
+            let _ = file.unlock();
+            false
+        }
+        Err(_) => true,
+    }
+}
+
+/// Checks the headers of the binary, if its format is known.
+///
+/// For now, only ELF binaries are checked: their section table is written last, so a valid
+/// section table is a good sign the whole file was written.
+fn validate_binary(path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut header = [0; 64];
+    let header_len = read_up_to(&mut file, &mut header)?;
+
+    if header_len < 4 || header[..4] != *b"\x7fELF" {
+        return Ok(());
+    }
+
+    let header = ElfHeader::parse(&header[..header_len]).ok_or_else(|| invalid("header"))?;
+
+    let program_headers_end = header
+        .program_header_entry_size
+        .checked_mul(header.program_header_count)
+        .and_then(|size| header.program_header_offset.checked_add(size));
+
+    if !program_headers_end.is_some_and(|end| end <= file_len) {
+        return Err(invalid("program header table"));
+    }
+
+    if header.section_header_count == 0 {
+        return Err(invalid("section header table"));
+    }
+
+    let section_headers_len = header
+        .section_header_entry_size
+        .checked_mul(header.section_header_count)
+        .ok_or_else(|| invalid("section header table"))?;
+
+    if !header
+        .section_header_offset
+        .checked_add(section_headers_len)
+        .is_some_and(|end| end <= file_len)
+    {
+        return Err(invalid("section header table"));
+    }
+
+    let mut section_headers = vec![0; section_headers_len as usize];
+    file.seek(SeekFrom::Start(header.section_header_offset))?;
+    file.read_exact(&mut section_headers)?;
+
+    for section in section_headers.chunks_exact(header.section_header_entry_size as usize) {
+        let (offset, size) = header
+            .section_bounds(section)
+            .ok_or_else(|| invalid("section header"))?;
+
+        if !offset.checked_add(size).is_some_and(|end| end <= file_len) {
+            return Err(invalid("section"));
+        }
+    }
+
+    Ok(())
+}
+
+struct ElfHeader {
+    is_64bits: bool,
+    is_little_endian: bool,
+    program_header_offset: u64,
+    program_header_entry_size: u64,
+    program_header_count: u64,
+    section_header_offset: u64,
+    section_header_entry_size: u64,
+    section_header_count: u64,
+}
+
+impl ElfHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let is_64bits = match bytes.get(4)? {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+
+        let is_little_endian = match bytes.get(5)? {
+            1 => true,
+            2 => false,
+            _ => return None,
+        };
+
+        let reader = ElfReader { is_little_endian };
+
+        let header = if is_64bits {
+            Self {
+                is_64bits,
+                is_little_endian,
+                program_header_offset: reader.read(bytes, 0x20, 8)?,
+                program_header_entry_size: reader.read(bytes, 0x36, 2)?,
+                program_header_count: reader.read(bytes, 0x38, 2)?,
+                section_header_offset: reader.read(bytes, 0x28, 8)?,
+                section_header_entry_size: reader.read(bytes, 0x3A, 2)?,
+                section_header_count: reader.read(bytes, 0x3C, 2)?,
+            }
+        } else {
+            Self {
+                is_64bits,
+                is_little_endian,
+                program_header_offset: reader.read(bytes, 0x1C, 4)?,
+                program_header_entry_size: reader.read(bytes, 0x2A, 2)?,
+                program_header_count: reader.read(bytes, 0x2C, 2)?,
+                section_header_offset: reader.read(bytes, 0x20, 4)?,
+                section_header_entry_size: reader.read(bytes, 0x2E, 2)?,
+                section_header_count: reader.read(bytes, 0x30, 2)?,
+            }
+        };
+
+        let min_section_header_size = if is_64bits { 0x28 } else { 0x18 };
+        if header.section_header_entry_size < min_section_header_size {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    /// Returns the offset and size of a section in the file, from its section header.
+    fn section_bounds(&self, section_header: &[u8]) -> Option<(u64, u64)> {
+        const SHT_NOBITS: u64 = 8;
+
+        let reader = ElfReader {
+            is_little_endian: self.is_little_endian,
+        };
+
+        // Sections like .bss don't take any space in the file.
+        if reader.read(section_header, 0x04, 4)? == SHT_NOBITS {
+            return Some((0, 0));
+        }
+
+        if self.is_64bits {
+            Some((
+                reader.read(section_header, 0x18, 8)?,
+                reader.read(section_header, 0x20, 8)?,
+            ))
+        } else {
+            Some((
+                reader.read(section_header, 0x10, 4)?,
+                reader.read(section_header, 0x14, 4)?,
+            ))
+        }
+    }
+}
+
+struct ElfReader {
+    is_little_endian: bool,
+}
+
+impl ElfReader {
+    fn read(&self, bytes: &[u8], offset: usize, size: usize) -> Option<u64> {
+        let bytes = bytes.get(offset..offset + size)?;
+
+        let value = if self.is_little_endian {
+            bytes.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64)
+        } else {
+            bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+        };
+
+        Some(value)
+    }
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(read)
+}
+
+fn invalid(part: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid or incomplete ELF {part}"),
+    )
+}
@@ -1,10 +1,16 @@
-use notify_debouncer_full::notify::{RecursiveMode, Watcher};
+use crate::log::log;
+use notify_debouncer_full::notify::{Error, RecursiveMode, Watcher};
 use std::path::{Path, PathBuf};
 
+/// The maximum number of links to follow, to match what most OSes allow.
+const MAX_SYMLINK_DEPTH: usize = 32;
+
 #[derive(Clone)]
 pub struct BundleSymlinkedPath {
     path: PathBuf,
     _is_symlink: bool, // TODO
+    /// Set if this is a bundle directory, rather than a single file or a link.
+    is_directory: bool,
     is_watched: bool,
     is_parent_watched: bool,
     symlink_target: Option<Box<BundleSymlinkedPath>>,
@@ -15,34 +21,31 @@ impl BundleSymlinkedPath {
         &self.path
     }
 
-    // TODO: handle symlink loops
-    pub fn get_info(path: PathBuf) -> Self {
-        dbg!("Scanning path:  ", &path);
-
-        let (is_symlink, symlink_target) = match std::fs::symlink_metadata(&path) {
-            Ok(metadata) => {
-                if !metadata.is_symlink() {
-                    (false, None)
-                } else {
-                    match std::fs::read_link(&path) {
-                        Ok(path) => (true, Some(Box::new(BundleSymlinkedPath::get_info(path)))),
-                        Err(_) => {
-                            // TODO: log error
-                            (true, None)
-                        }
-                    }
-                }
-            }
-            Err(_) => {
-                // TODO: log error
-                match std::fs::read_link(&path) {
-                    Ok(path) => (true, Some(Box::new(BundleSymlinkedPath::get_info(path)))),
-                    Err(_) => (false, None),
-                }
-            }
+    /// Resolves the whole symlink chain starting at the given path.
+    ///
+    /// Broken links, loops and chains that are too deep are reported in `results`, and end the
+    /// chain.
+    pub fn get_info(path: PathBuf, results: &mut WatchResults) -> Self {
+        let mut visited = Vec::new();
+        Self::resolve(path, &mut visited, results)
+    }
+
+    fn resolve(path: PathBuf, visited: &mut Vec<PathBuf>, results: &mut WatchResults) -> Self {
+        let (is_symlink, symlink_target) = match Self::read_link_target(&path, visited, results) {
+            Some(target) => (
+                true,
+                Some(Box::new(BundleSymlinkedPath::resolve(
+                    target, visited, results,
+                ))),
+            ),
+            None => (
+                std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()),
+                None,
+            ),
         };
 
         Self {
+            is_directory: !is_symlink && path.is_dir(),
             path,
             is_watched: false,
             is_parent_watched: false,
@@ -51,15 +54,85 @@ impl BundleSymlinkedPath {
         }
     }
 
-    pub fn watch_all(&mut self, watcher: &mut impl Watcher, results: &mut WatchResults) {
-        match watcher.watch(&self.path, RecursiveMode::NonRecursive) {
+    /// Returns the path this link points to, or `None` if it isn't a link or if it shouldn't be
+    /// followed.
+    fn read_link_target(
+        path: &Path,
+        visited: &mut Vec<PathBuf>,
+        results: &mut WatchResults,
+    ) -> Option<PathBuf> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_symlink() => {}
+            Ok(_) => return None,
+            Err(e) => {
+                results
+                    .errors
+                    .push(Error::io(e).add_path(path.to_path_buf()));
+                return None;
+            }
+        }
+
+        let normalized = normalize_link_path(path);
+        if visited.contains(&normalized) {
+            results
+                .errors
+                .push(Error::generic("Symlink loop detected").add_path(path.to_path_buf()));
+            return None;
+        }
+
+        if visited.len() >= MAX_SYMLINK_DEPTH {
+            results
+                .errors
+                .push(Error::generic("Too many levels of symlinks").add_path(path.to_path_buf()));
+            return None;
+        }
+
+        visited.push(normalized);
+
+        let target = match std::fs::read_link(path) {
+            Ok(target) => target,
+            Err(e) => {
+                results
+                    .errors
+                    .push(Error::io(e).add_path(path.to_path_buf()));
+                return None;
+            }
+        };
+
+        // Relative targets are relative to the link's directory, not to the current one.
+        if target.is_relative() {
+            if let Some(parent) = path.parent() {
+                return Some(parent.join(target));
+            }
+        }
+
+        Some(target)
+    }
+
+    /// Returns true if the given path is this path, or is contained in it if it's a directory.
+    pub fn contains(&self, path: &Path) -> bool {
+        if self.is_directory {
+            path.starts_with(&self.path)
+        } else {
+            self.path == path
+        }
+    }
+
+    pub fn watch_all(&mut self, watcher: &mut dyn Watcher, results: &mut WatchResults) {
+        // Bundle directories must be watched recursively: the binary is deep inside.
+        let mode = if self.is_directory {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+
+        match watcher.watch(&self.path, mode) {
             Ok(()) => {
-                println!("Started watching path {:?}", &self.path);
+                log!(Debug, Watch, "Started watching path {:?}", &self.path);
                 self.is_watched = true;
                 results.success_count += 1;
             }
             Err(e) => {
-                eprintln!("Failed to watch path {:?}", &self.path);
                 self.is_watched = false;
                 results.errors.push(e)
             }
@@ -68,12 +141,16 @@ impl BundleSymlinkedPath {
         if let Some(parent_path) = self.path.parent() {
             match watcher.watch(parent_path, RecursiveMode::NonRecursive) {
                 Ok(()) => {
-                    println!("Started watching parent path {:?}", parent_path);
+                    log!(
+                        Debug,
+                        Watch,
+                        "Started watching parent path {:?}",
+                        parent_path
+                    );
                     self.is_parent_watched = true;
                     results.success_count += 1;
                 }
                 Err(e) => {
-                    eprintln!("Failed to watch parent path {:?}", parent_path);
                     self.is_parent_watched = false;
                     results.errors.push(e)
                 }
@@ -85,6 +162,31 @@ impl BundleSymlinkedPath {
         }
     }
 
+    /// Stops watching all the paths of the chain.
+    ///
+    /// This doesn't rely on the watch flags, as this chain may be a copy of the one that was
+    /// actually watched.
+    pub fn unwatch_all(&mut self, watcher: &mut dyn Watcher) {
+        let _ = watcher.unwatch(&self.path);
+        self.is_watched = false;
+
+        if let Some(parent_path) = self.path.parent() {
+            let _ = watcher.unwatch(parent_path);
+        }
+        self.is_parent_watched = false;
+
+        if let Some(target) = self.symlink_target.as_mut() {
+            target.unwatch_all(watcher)
+        }
+    }
+
+    /// Returns true if both chains are made of the same paths.
+    pub fn has_same_chain(&self, other: &BundleSymlinkedPath) -> bool {
+        self.iter()
+            .map(|p| p.path())
+            .eq(other.iter().map(|p| p.path()))
+    }
+
     pub fn iter(&self) -> BundleSymlinkedPathIter {
         BundleSymlinkedPathIter {
             current: Some(self),
@@ -112,7 +214,7 @@ impl<'a> Iterator for BundleSymlinkedPathIter<'a> {
 
 pub struct WatchResults {
     success_count: usize,
-    errors: Vec<notify_debouncer_full::notify::Error>,
+    errors: Vec<Error>,
 }
 
 impl WatchResults {
@@ -128,6 +230,105 @@ impl WatchResults {
     }
 
     pub fn log_errors(&self) {
-        // TODO
+        for error in &self.errors {
+            log!(Error, Watch, "Failed to watch bundle path: {error}");
+        }
+    }
+}
+
+/// Makes equivalent link paths comparable, by resolving the directory the link is in.
+fn normalize_link_path(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => match std::fs::canonicalize(parent) {
+            Ok(parent) => parent.join(file_name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn chain_paths(path: &BundleSymlinkedPath) -> Vec<PathBuf> {
+        path.iter().map(|p| p.path().to_path_buf()).collect()
+    }
+
+    #[test]
+    fn follows_symlink_chains() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("plugin.clap");
+        let first = dir.path().join("first.clap");
+        let second = dir.path().join("second.clap");
+
+        std::fs::write(&file, b"").unwrap();
+        symlink(&second, &first).unwrap();
+        // Relative targets are relative to the link's directory.
+        symlink("plugin.clap", &second).unwrap();
+
+        let mut results = WatchResults::empty();
+        let path = BundleSymlinkedPath::get_info(first.clone(), &mut results);
+
+        assert!(results.errors.is_empty());
+        assert_eq!(chain_paths(&path), [first, second, file]);
+    }
+
+    #[test]
+    fn detects_symlink_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.clap");
+        let second = dir.path().join("second.clap");
+
+        symlink(&second, &first).unwrap();
+        symlink(&first, &second).unwrap();
+
+        let mut results = WatchResults::empty();
+        let path = BundleSymlinkedPath::get_info(first.clone(), &mut results);
+
+        assert_eq!(results.errors.len(), 1);
+        assert_eq!(chain_paths(&path), [first.clone(), second, first]);
+    }
+
+    #[test]
+    fn stops_following_chains_that_are_too_deep() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = |i: usize| dir.path().join(format!("link-{i}.clap"));
+        let link_count = MAX_SYMLINK_DEPTH + 8;
+
+        std::fs::write(link(link_count), b"").unwrap();
+        for i in 0..link_count {
+            symlink(link(i + 1), link(i)).unwrap();
+        }
+
+        let mut results = WatchResults::empty();
+        let path = BundleSymlinkedPath::get_info(link(0), &mut results);
+
+        assert_eq!(results.errors.len(), 1);
+        assert_eq!(
+            chain_paths(&path),
+            (0..=MAX_SYMLINK_DEPTH).map(link).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn detects_retargeted_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("plugin.clap");
+        let old_target = dir.path().join("old.clap");
+        let new_target = dir.path().join("new.clap");
+
+        symlink(&old_target, &link).unwrap();
+
+        let mut results = WatchResults::empty();
+        let old_path = BundleSymlinkedPath::get_info(link.clone(), &mut results);
+        assert!(old_path.has_same_chain(&BundleSymlinkedPath::get_info(link.clone(), &mut results)));
+
+        std::fs::remove_file(&link).unwrap();
+        symlink(&new_target, &link).unwrap();
+
+        let new_path = BundleSymlinkedPath::get_info(link, &mut results);
+        assert!(!old_path.has_same_chain(&new_path));
     }
 }
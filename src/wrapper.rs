@@ -1,22 +1,34 @@
+use crate::config::{CrossfadeConfig, HotReloadConfig};
+use crate::log::{log, log_event, LogLevel, LogStage};
+use crate::reload_events::{HostReloadEvents, PreviousBundleInfo};
+use crate::util::LoadedBundle;
 use crate::watcher::BundleReceiver;
-use clack_extensions::audio_ports::HostAudioPorts;
+use clack_extensions::audio_ports::{HostAudioPorts, RescanType};
 use clack_extensions::gui::HostGui;
 use clack_extensions::latency::HostLatency;
+use clack_extensions::log::HostLog;
+use clack_extensions::note_ports::{HostNotePorts, NotePortRescanFlags};
 use clack_extensions::params::{HostParams, ParamRescanFlags};
-use clack_extensions::timer::PluginTimer;
+use clack_extensions::timer::{HostTimer, PluginTimer};
 use clack_host::prelude::*;
 use clack_plugin::prelude::*;
 use clack_plugin::prelude::{HostMainThreadHandle, HostSharedHandle};
 use std::ffi::{CStr, CString};
-use std::sync::OnceLock;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 
 mod audio_processor;
 mod channel;
 mod extensions;
 mod requests;
+#[cfg(test)]
+mod test_plugin;
 
 use audio_processor::*;
+pub(crate) use channel::BoundedQueue;
 use channel::*;
+pub use extensions::OuterHostCapabilities;
 use extensions::*;
 use requests::*;
 
@@ -27,20 +39,18 @@ impl WrapperHost {
         host: &HostMainThreadHandle,
         bundle: &PluginBundle,
         instantiated_plugin_id: &CStr,
-    ) -> PluginInstance<Self> {
+        host_capabilities: OuterHostCapabilities,
+        previous_bundle: Option<PreviousBundleInfo>,
+    ) -> Result<PluginInstance<Self>, PluginInstanceError> {
         let info = HostInfo::from_plugin(host);
 
-        // TODO: unwrap
-        let instance = PluginInstance::<WrapperHost>::new(
+        PluginInstance::<WrapperHost>::new(
             |_| WrapperHostShared::new(),
-            |s| WrapperHostMainThread::new(s),
+            |s| WrapperHostMainThread::new(s, host_capabilities, previous_bundle),
             bundle,
             instantiated_plugin_id,
             &info,
         )
-        .unwrap();
-
-        instance
     }
 
     pub fn activate_instance(
@@ -63,12 +73,22 @@ impl HostHandlers for WrapperHost {
         builder.register::<HostAudioPorts>();
         builder.register::<HostGui>();
         builder.register::<HostLatency>();
+        builder.register::<HostLog>();
+        builder.register::<HostNotePorts>();
+        builder.register::<HostReloadEvents>();
+        builder.register::<HostTimer>();
     }
 }
 
 pub struct WrapperHostShared {
     pub(crate) plugin: OnceLock<WrappedPluginExtensions>,
     requests: PluginSharedRequests,
+    is_swapped_out: AtomicBool,
+    param_id_map: OnceLock<Arc<ParamIdMap>>,
+    is_quarantined: AtomicBool,
+    is_quarantine_reported: AtomicBool,
+    dsp_handoff_buffer: Slot<Vec<u8>>,
+    note_dialects: OnceLock<Box<[InputNoteDialects]>>,
 }
 
 impl WrapperHostShared {
@@ -76,12 +96,77 @@ impl WrapperHostShared {
         Self {
             plugin: OnceLock::new(),
             requests: PluginSharedRequests::new(),
+            is_swapped_out: AtomicBool::new(false),
+            param_id_map: OnceLock::new(),
+            is_quarantined: AtomicBool::new(false),
+            is_quarantine_reported: AtomicBool::new(false),
+            dsp_handoff_buffer: Slot::new(),
+            note_dialects: OnceLock::new(),
         }
     }
 
     pub fn wrapped_plugin(&self) -> &WrappedPluginExtensions {
         self.plugin.get().unwrap() // FIXME: unwrap
     }
+
+    /// Marks this instance as replaced by a newer one. Its restart requests will be ignored.
+    pub fn mark_swapped_out(&self) {
+        self.is_swapped_out.store(true, Ordering::Relaxed)
+    }
+
+    /// Sets the parameter IDs to rewrite for this instance. This must be done before activating it.
+    pub fn set_param_id_map(&self, map: ParamIdMap) {
+        let _ = self.param_id_map.set(Arc::new(map));
+    }
+
+    /// Returns the parameter IDs to rewrite for this instance, if any.
+    pub fn param_id_map(&self) -> Option<Arc<ParamIdMap>> {
+        self.param_id_map
+            .get()
+            .filter(|map| !map.is_empty())
+            .cloned()
+    }
+
+    /// Marks this instance as having panicked or failed. It won't be called into anymore.
+    pub fn quarantine(&self) {
+        self.is_quarantined.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.is_quarantined.load(Ordering::Relaxed)
+    }
+
+    /// Sets the dialects of the input note ports of this instance. This must be done before
+    /// activating it.
+    pub fn set_note_dialects(&self, dialects: Box<[InputNoteDialects]>) {
+        let _ = self.note_dialects.set(dialects);
+    }
+
+    /// Returns the dialects held notes are replayed in when this instance is swapped in.
+    pub fn note_dialects(&self) -> &[InputNoteDialects] {
+        self.note_dialects.get().map_or(&[], |d| d)
+    }
+
+    /// Sets the buffer the previous instance exports its DSP state into, once this one replaces
+    /// it on the audio thread.
+    pub fn set_dsp_handoff_buffer(&self, buffer: Vec<u8>) {
+        let _ = self.dsp_handoff_buffer.try_put(buffer);
+    }
+
+    /// Takes the DSP handoff buffer, if there is one. It must be given back afterward, so that it
+    /// isn't deallocated on the audio thread.
+    pub fn take_dsp_handoff_buffer(&self) -> Option<Vec<u8>> {
+        self.dsp_handoff_buffer.try_take()
+    }
+
+    pub fn return_dsp_handoff_buffer(&self, buffer: Vec<u8>) {
+        let _ = self.dsp_handoff_buffer.try_put(buffer);
+    }
+
+    /// Returns true only the first time this is called after the instance was quarantined.
+    fn should_report_quarantine(&self) -> bool {
+        self.is_quarantined() && !self.is_quarantine_reported.swap(true, Ordering::Relaxed)
+    }
 }
 
 impl<'a> SharedHandler<'a> for WrapperHostShared {
@@ -90,11 +175,16 @@ impl<'a> SharedHandler<'a> for WrapperHostShared {
     }
 
     fn request_restart(&self) {
-        todo!()
+        // Restarting would only affect the live instance, not this one
+        if self.is_swapped_out.load(Ordering::Relaxed) {
+            return;
+        }
+
+        self.requests.request_restart()
     }
 
     fn request_process(&self) {
-        todo!()
+        self.requests.request_process()
     }
 
     fn request_callback(&self) {
@@ -106,14 +196,29 @@ pub struct WrapperHostMainThread<'a> {
     shared: &'a WrapperHostShared,
     plugin: Option<InitializedPluginHandle<'a>>,
     requests: PluginMainThreadRequests,
+    host_capabilities: OuterHostCapabilities,
+    audio_ports_rescan: RescanType,
+    note_ports_rescan: NotePortRescanFlags,
+    timer_requests: InnerTimerRequests,
+    /// The bundle of the instance this one replaces, if any.
+    previous_bundle: Option<PreviousBundleInfo>,
 }
 
 impl<'a> WrapperHostMainThread<'a> {
-    pub fn new(shared: &'a WrapperHostShared) -> Self {
+    pub fn new(
+        shared: &'a WrapperHostShared,
+        host_capabilities: OuterHostCapabilities,
+        previous_bundle: Option<PreviousBundleInfo>,
+    ) -> Self {
         Self {
             shared,
             plugin: None,
             requests: PluginMainThreadRequests::new(),
+            host_capabilities,
+            audio_ports_rescan: RescanType::empty(),
+            note_ports_rescan: NotePortRescanFlags::empty(),
+            timer_requests: InnerTimerRequests::new(),
+            previous_bundle,
         }
     }
 
@@ -163,10 +268,16 @@ pub struct WrapperPluginShared<'a> {
     _host: HostSharedHandle<'a>,
     reported_extensions: ReportedExtensions,
     host_extensions: OuterHostExtensions,
+    crossfade: CrossfadeConfig,
+    stable_param_list: bool,
 }
 
 impl<'a> WrapperPluginShared<'a> {
-    pub fn new(host: HostSharedHandle<'a>, plugin_handle: &PluginInstance<WrapperHost>) -> Self {
+    pub fn new(
+        host: HostSharedHandle<'a>,
+        plugin_handle: &PluginInstance<WrapperHost>,
+        config: &HotReloadConfig,
+    ) -> Self {
         let reported_extensions =
             plugin_handle.access_shared_handler(|h| h.wrapped_plugin().report());
 
@@ -174,6 +285,8 @@ impl<'a> WrapperPluginShared<'a> {
             host_extensions: OuterHostExtensions::new(&host),
             _host: host,
             reported_extensions,
+            crossfade: config.crossfade,
+            stable_param_list: config.stable_param_list,
         }
     }
 }
@@ -189,25 +302,35 @@ pub struct WrapperPluginMainThread<'a> {
     audio_processor_channel: Option<MainThreadChannel>,
     plugin_id: CString,
     current_audio_config: Option<PluginAudioConfiguration>,
+    host_capabilities: OuterHostCapabilities,
     audio_ports_info: PluginAudioPortsInfo,
+    note_ports_info: NotePortInfoCache,
     param_info_cache: ParamInfoCache,
     gui: WrapperGui,
 }
 
 impl<'a> PluginMainThread<'a, WrapperPluginShared<'a>> for WrapperPluginMainThread<'a> {
     fn on_main_thread(&mut self) {
-        //self.timers.init(&mut self.host);
+        self.timers.init(&mut self.host);
+
+        crate::log::drain_audio_events();
+        self.revert_if_quarantined();
 
-        /* if let Some(new_bundle) = self.shared.watcher_handle.check_new_bundle_available() {
-            todo!()
-        } */
+        // The watcher thread requests a callback when a new bundle is available, in case the
+        // host doesn't support timers.
+        self.check_for_new_bundles();
 
         if let Some(channel) = &mut self.audio_processor_channel {
-            channel.destroy_awaiting()
+            channel.destroy_awaiting();
+
+            if channel.has_undelivered_processor() {
+                self.host.shared().request_callback(); // Try again soon
+            }
         }
 
         // TODO: make sure the plugin has requested one
-        self.plugin_instance.call_on_main_thread_callback()
+        self.plugin_instance.call_on_main_thread_callback();
+        self.process_requests();
     }
 }
 
@@ -218,16 +341,14 @@ impl<'a> WrapperPluginMainThread<'a> {
         mut plugin_instance: PluginInstance<WrapperHost>,
         bundle_receiver: Option<BundleReceiver>,
         plugin_id: CString,
+        host_capabilities: OuterHostCapabilities,
     ) -> Result<Self, PluginError> {
-        // host.shared().request_callback(); // To finish configuring timers. TODO: Bitwig bug?
-        let audio_ports = host.get_extension();
+        host.shared().request_callback(); // To finish configuring timers, see WrapperTimerHandler::init
         Ok(Self {
-            audio_ports_info: PluginAudioPortsInfo::new(
-                &mut plugin_instance,
-                &mut host,
-                audio_ports,
-            ),
-            param_info_cache: ParamInfoCache::new(&mut plugin_instance),
+            host_capabilities,
+            audio_ports_info: PluginAudioPortsInfo::new(&mut plugin_instance),
+            note_ports_info: NotePortInfoCache::new(&mut plugin_instance),
+            param_info_cache: ParamInfoCache::new(&mut plugin_instance, shared.stable_param_list),
             gui: WrapperGui::new(&host),
 
             host,
@@ -251,62 +372,247 @@ impl<'a> WrapperPluginMainThread<'a> {
         self.plugin_instance.plugin_handle()
     }
 
+    /// Calls into the wrapped instance, quarantining it if it panics.
+    ///
+    /// The wrapped plugin catches its own panics: they only show up as a failed call, reported as
+    /// misbehaving through the log extension, which quarantines the instance.
+    ///
+    /// Once quarantined, an instance isn't called into anymore: this returns an error right away.
+    pub(crate) fn call_wrapped<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> Result<T, PluginError> {
+        if self.is_quarantined() {
+            return Err(PluginError::Message("The wrapped plugin has panicked"));
+        }
+
+        let value = f(self);
+
+        if self.is_quarantined() {
+            self.host.shared().request_callback(); // To revert to the previous bundle
+            return Err(PluginError::Message("The wrapped plugin panicked"));
+        }
+
+        Ok(value)
+    }
+
+    fn is_quarantined(&self) -> bool {
+        self.plugin_instance
+            .access_shared_handler(|h| h.is_quarantined())
+    }
+
+    /// Goes back to the previous bundle if the current instance panicked, either here or on the
+    /// audio thread.
+    fn revert_if_quarantined(&mut self) {
+        if !self
+            .plugin_instance
+            .access_shared_handler(|h| h.should_report_quarantine())
+        {
+            return;
+        }
+
+        log!(
+            Error,
+            Swap,
+            "The wrapped plugin panicked, reverting to the previous bundle."
+        );
+
+        if let Some(receiver) = &self.bundle_receiver {
+            receiver.report_failure(receiver.current_bundle());
+        }
+    }
+
     fn check_for_new_bundles(&mut self) {
         let Some(receiver) = self.bundle_receiver.as_mut() else {
             return;
         };
 
-        if !receiver.receive_new_bundle() {
+        let Some(bundle) = receiver.receive_new_bundle() else {
+            return;
+        };
+
+        log!(Info, Reload, "Received a new bundle.");
+
+        let has_plugin = bundle.get_plugin_factory().is_some_and(|f| {
+            f.plugin_descriptors()
+                .any(|d| d.id() == Some(self.plugin_id.as_c_str()))
+        });
+
+        if !has_plugin {
+            log!(
+                Warn,
+                Reload,
+                "Plugin {:?} is not in the new bundle anymore, keeping the current instance.",
+                self.plugin_id
+            );
             return;
         }
 
-        println!("Received new bundle!!");
+        self.notify_will_swap_out();
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| self.swap_to_bundle(&bundle)))
+            .unwrap_or(Err(ReloadError::Panic));
+
+        match result {
+            Ok(()) => {
+                log!(Info, Swap, "Swapped to the new plugin instance.");
 
-        let mut new_instance =
-            WrapperHost::new_instance(&self.host, receiver.current_bundle(), &self.plugin_id);
+                if let Some(receiver) = &mut self.bundle_receiver {
+                    receiver.set_current_bundle(bundle);
+                }
+            }
+            Err(e) => {
+                log_event(
+                    LogLevel::Error,
+                    e.stage(),
+                    format_args!("Failed to reload plugin, keeping the previous instance: {e}"),
+                );
 
-        if let Err(e) = transfer_state(&mut self.plugin_instance, &mut new_instance) {
-            eprintln!("Could not transfer state to new hot-loaded instance: {e}");
+                self.notify_swap_out_cancelled();
+
+                if let Some(receiver) = &self.bundle_receiver {
+                    receiver.report_failure(&bundle);
+                }
+            }
         }
+    }
 
-        let mut old_instance = core::mem::replace(&mut self.plugin_instance, new_instance);
+    /// Replaces the current instance with a new one from the given bundle.
+    ///
+    /// The new instance is fully set up (and activated, if needed) before being swapped in. If any
+    /// step fails, it is discarded and the current instance is left untouched. The only exception
+    /// is the state transfer, which falls back to keeping the parameter values.
+    fn swap_to_bundle(&mut self, bundle: &LoadedBundle) -> Result<(), ReloadError> {
+        let previous_bundle = self
+            .bundle_receiver
+            .as_ref()
+            .map(|r| previous_bundle_info(r.current_bundle(), &self.plugin_id))
+            .unwrap_or_default();
+
+        let mut new_instance = WrapperHost::new_instance(
+            &self.host,
+            bundle,
+            &self.plugin_id,
+            self.host_capabilities,
+            Some(previous_bundle),
+        )
+        .map_err(ReloadError::Instantiation)?;
+
+        // A quarantined instance can't be trusted with saving its state or parameter values.
+        let is_quarantined = self.is_quarantined();
+
+        let state_transferred = !is_quarantined
+            && match transfer_state(&mut self.plugin_instance, &mut new_instance) {
+                Ok(true) => true,
+                Ok(false) => {
+                    log!(
+                        Debug,
+                        StateTransfer,
+                        "The plugin doesn't support state transfer, only keeping parameter values."
+                    );
+                    false
+                }
+                // The new build may not be able to read the previous one's state: that
+                // shouldn't prevent reloading it.
+                Err(e) => {
+                    log!(
+                        Warn,
+                        StateTransfer,
+                        "Failed to transfer the plugin's state, only keeping parameter values: {e}"
+                    );
+                    false
+                }
+            };
+
+        // Only commit the parameter changes once the swap happens.
+        let mut param_info_cache = self.param_info_cache.clone();
+        let required_rescan = param_info_cache.update(&mut new_instance, &bundle.param_migrations);
+
+        let id_map = param_info_cache.id_map();
+        new_instance.access_shared_handler(|h| h.set_param_id_map(id_map));
+
+        if !state_transferred && !is_quarantined {
+            // At least keep the parameter values
+            let values = self.param_info_cache.read_values(&mut self.plugin_instance);
+            param_info_cache.write_values(&mut new_instance, &values);
+        }
 
-        // TODO: handle the function crashing here and ending up with a partial swap?
-        let required_rescan = self.param_info_cache.update(&mut self.plugin_instance);
+        if !is_quarantined {
+            transfer_hot_state(&mut self.plugin_instance, &mut new_instance);
+        }
 
-        if let Some(host_params) = self.shared.host_extensions.params {
-            // Always rescan text renderings, we can never really know if it changed or not
-            host_params.rescan(&mut self.host, required_rescan | ParamRescanFlags::TEXT)
+        let new_audio_ports = AudioPortLayout::read_from(&mut new_instance);
+        let new_note_ports = NotePortList::read_from(&mut new_instance);
+        new_instance
+            .access_shared_handler(|h| h.set_note_dialects(new_note_ports.input_dialects()));
+
+        let needs_restart = required_rescan.requires_restart()
+            || self.audio_ports_require_restart(&new_audio_ports)
+            || self.note_ports_require_restart(&new_note_ports);
+
+        // If there's no channel, we aren't active or processing. If a restart is needed, don't
+        // bother activating the new instance yet.
+        let audio_processor = match (&self.audio_processor_channel, self.current_audio_config) {
+            (Some(_), Some(config)) if !needs_restart => Some(
+                WrapperHost::activate_instance(&mut new_instance, config)
+                    .map_err(ReloadError::Activation)?,
+            ),
+            _ => None,
+        };
+
+        if audio_processor.is_some() && !is_quarantined {
+            prepare_dsp_handoff(&mut self.plugin_instance, &mut new_instance);
         }
 
         if let Err(e) =
             self.gui
-                .transfer_gui(&mut old_instance, &mut self.plugin_instance, &mut self.host)
+                .transfer_gui(&mut self.plugin_instance, &mut new_instance, &mut self.host)
         {
-            eprintln!("{e}"); // TODO: handle errors(?)
+            // Bring the GUI back on the current instance.
+            if let Err(e) =
+                self.gui
+                    .transfer_gui(&mut new_instance, &mut self.plugin_instance, &mut self.host)
+            {
+                log!(Error, GuiTransfer, "Failed to restore the plugin GUI: {e}");
+            }
+
+            if let Some(audio_processor) = audio_processor {
+                new_instance.deactivate(audio_processor);
+            }
+
+            return Err(ReloadError::Gui(e));
         }
 
-        // If there's no channel, we aren't active or processing. No need to keep the old instance around.
-        let Some(channel) = &mut self.audio_processor_channel else {
-            drop(old_instance);
-            return;
-        };
+        // Nothing can fail past this point.
+        let old_instance = core::mem::replace(&mut self.plugin_instance, new_instance);
+        old_instance.access_shared_handler(|h| h.mark_swapped_out());
+        self.timers.orphan_instance_timers();
+        self.param_info_cache = param_info_cache;
 
-        let needs_restart = required_rescan.requires_restart();
+        if let Some(host_params) = self.shared.host_extensions.params {
+            // Always rescan text renderings, we can never really know if it changed or not
+            host_params.rescan(&mut self.host, required_rescan | ParamRescanFlags::TEXT)
+        }
 
-        if needs_restart {
-            // Don't bother activating the new instance yet.
-            channel.defer_destroy_if_active(old_instance);
-        } else {
-            let config = self.current_audio_config.unwrap(); // TODO: this should always be the case if channel exists (checked above).
+        self.update_audio_ports(new_audio_ports);
+        self.update_note_ports(new_note_ports);
+
+        // The new instance may have registered its own timers, possibly reusing the old ones.
+        self.process_timer_requests();
 
-            // TODO: unwrap
-            let audio_processor =
-                WrapperHost::activate_instance(&mut self.plugin_instance, config).unwrap();
+        match (&mut self.audio_processor_channel, audio_processor) {
+            (Some(channel), Some(audio_processor)) => {
+                channel.send_new_audio_processor(audio_processor, old_instance);
 
-            // TODO: handle errors
-            let _ = channel.send_new_audio_processor(audio_processor, old_instance);
+                if channel.has_undelivered_processor() {
+                    self.host.shared().request_callback(); // See on_main_thread
+                }
+            }
+            (Some(channel), None) => channel.defer_destroy_if_active(old_instance),
+            (None, _) => drop(old_instance),
         }
+
+        Ok(())
     }
 
     fn deactivate_wrapped_instance(
@@ -327,6 +633,39 @@ impl<'a> WrapperPluginMainThread<'a> {
     pub fn process_requests(&mut self) {
         self.plugin_instance.access_handler_mut(|h| {
             h.process_requests(&mut self.host, &self.shared.host_extensions)
-        })
+        });
+
+        self.process_audio_ports_requests();
+        self.process_note_ports_requests();
+        self.process_timer_requests();
+    }
+}
+
+/// A step of a reload that failed, leaving the current instance in place.
+enum ReloadError {
+    Instantiation(PluginInstanceError),
+    Activation(PluginInstanceError),
+    Gui(clack_plugin::plugin::PluginError),
+    Panic,
+}
+
+impl ReloadError {
+    fn stage(&self) -> LogStage {
+        match self {
+            ReloadError::Instantiation(_) => LogStage::Reload,
+            ReloadError::Activation(_) | ReloadError::Panic => LogStage::Swap,
+            ReloadError::Gui(_) => LogStage::GuiTransfer,
+        }
+    }
+}
+
+impl core::fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReloadError::Instantiation(e) => write!(f, "could not instantiate the plugin: {e}"),
+            ReloadError::Activation(e) => write!(f, "could not activate the plugin: {e}"),
+            ReloadError::Gui(e) => write!(f, "could not transfer the plugin's GUI: {e}"),
+            ReloadError::Panic => write!(f, "the plugin panicked"),
+        }
     }
 }
@@ -1,9 +1,11 @@
-use crate::wrapper::audio_processor::note_tracker::NoteTracker;
+use crate::log::{log_from_audio_thread, LogLevel};
+use crate::wrapper::audio_processor::note_tracker::{NoteTracker, MAX_RECOVERED_EVENTS};
 use crate::wrapper::*;
 use clack_host::prelude::ProcessStatus;
 use clack_plugin::host::HostAudioProcessorHandle;
 use clack_plugin::plugin::{PluginAudioProcessor, PluginError};
 use clack_plugin::prelude::{Audio, Events, PluginAudioConfiguration, Process};
+use std::panic::AssertUnwindSafe;
 
 mod cross_fader;
 use cross_fader::*;
@@ -12,7 +14,9 @@ mod output_buffers;
 
 use output_buffers::*;
 
-const CROSSFADE_TIME: f64 = 0.25;
+/// How many events per block can go through the wrapper's own buffers without them having to
+/// grow. Blocks with more events than this allocate on the audio thread.
+const EVENT_BUFFER_CAPACITY: usize = 1024;
 
 pub struct WrapperPluginAudioProcessor<'a> {
     host: HostAudioProcessorHandle<'a>,
@@ -21,41 +25,391 @@ pub struct WrapperPluginAudioProcessor<'a> {
     fade_out_audio_processor: Option<clack_host::process::PluginAudioProcessor<WrapperHost>>,
     channel: AudioProcessorChannel,
     input_event_buffer: EventBuffer,
+    output_event_buffer: EventBuffer,
+    param_event_buffers: ParamEventBuffers,
     note_tracker: NoteTracker,
     cross_fader: CrossFader,
     output_buffers: OutputBuffers,
+    /// Set once the outgoing instance is gone, while the incoming one is still fading in.
+    is_fading_in_alone: bool,
+    /// Set if the held notes must be replayed to the new instance once it starts processing.
+    is_note_recovery_pending: bool,
 }
 
 impl<'a> WrapperPluginAudioProcessor<'a> {
-    fn swap_if_needed(&mut self) -> bool {
+    fn swap_if_needed(&mut self) {
         // TODO: properly handle cookies
         if let Some(new_processor) = self.channel.move_to_latest_new_processor() {
-            println!("Audio processor received new update. Hot-swapping.");
-            let old_processor =
+            let mut old_processor =
                 core::mem::replace(&mut self.current_audio_processor, new_processor.into());
 
-            self.fade_out_audio_processor = Some(old_processor);
+            // If the DSP state could be handed over, the new instance can take over right away.
+            let handed_off = !old_processor.access_shared_handler(|h| h.is_quarantined())
+                && self
+                    .call_wrapped(|p| Ok(p.hand_off_dsp_state(&mut old_processor)))
+                    .unwrap_or(false);
+
+            // Never fade out from an instance that panicked: it won't be called into anymore.
+            // This is checked after the handoff, which may have made it panic.
+            let is_quarantined = old_processor.access_shared_handler(|h| h.is_quarantined());
+
+            if handed_off {
+                log_from_audio_thread(
+                    LogLevel::Debug,
+                    "Handed the DSP state over to the new plugin instance.",
+                );
+            }
 
-            // Recover notes
-            self.input_event_buffer.clear();
-            self.note_tracker
-                .recover_all_current_notes(&mut self.input_event_buffer);
+            log_from_audio_thread(LogLevel::Debug, "Switched to the new plugin instance.");
+
+            if self.cross_fader.is_instant() || is_quarantined || handed_off {
+                self.channel.send_for_disposal(old_processor.into_stopped());
+            } else {
+                self.fade_out_audio_processor = Some(old_processor);
+            }
 
-            println!("Note buffer : {:?}", &self.input_event_buffer);
+            // Recover notes, unless they were handed over with the DSP state
+            self.is_note_recovery_pending = !handed_off;
 
             self.cross_fader.reset(); // Prepare for cross-fading
-            true
-        } else {
-            false
         }
     }
 
+    /// Prepares the held notes to replay, if the new instance is about to process its first
+    /// block. Returns true if they must be delivered during this block.
+    fn recover_notes_if_needed(&mut self) -> bool {
+        if !self.is_note_recovery_pending || self.is_incoming_waiting() {
+            return false;
+        }
+
+        self.is_note_recovery_pending = false;
+        self.input_event_buffer.clear();
+        self.current_audio_processor.access_shared_handler(|h| {
+            self.note_tracker
+                .recover_all_current_notes(&mut self.input_event_buffer, h.note_dialects())
+        });
+
+        true
+    }
+
     fn process_requests(&mut self) {
         self.current_audio_processor.access_shared_handler(|h| {
             h.requests
                 .process_requests(&self.host, &self.shared.host_extensions)
         })
     }
+
+    /// Calls into the current wrapped instance, quarantining it if it fails.
+    ///
+    /// This only works for wrapped plugins that catch their own panics, as unwinding can't cross
+    /// the CLAP ABI: they then show up as an error (e.g. a processing error), or as a misbehaving
+    /// report through the log extension. Panics of the wrapper itself are handled by [`process`].
+    ///
+    /// [`process`]: PluginAudioProcessor::process
+    ///
+    /// Once quarantined, an instance isn't called into anymore: this returns `None` right away.
+    pub(crate) fn call_wrapped<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, PluginError>,
+    ) -> Option<T> {
+        if self.is_quarantined() {
+            return None;
+        }
+
+        match f(self) {
+            Ok(value) if !self.is_quarantined() => Some(value),
+            _ => {
+                self.quarantine();
+                None
+            }
+        }
+    }
+
+    fn is_quarantined(&self) -> bool {
+        self.current_audio_processor
+            .access_shared_handler(|h| h.is_quarantined())
+    }
+
+    fn quarantine(&mut self) {
+        self.current_audio_processor
+            .access_shared_handler(|h| h.quarantine());
+
+        log_from_audio_thread(
+            LogLevel::Error,
+            "The wrapped plugin failed while processing, silencing it.",
+        );
+
+        // Nothing is output until the main thread reverts, the outgoing instance isn't needed.
+        if let Some(old_processor) = self.fade_out_audio_processor.take() {
+            self.channel.send_for_disposal(old_processor.into_stopped());
+        }
+        self.is_fading_in_alone = false;
+
+        // The main thread reverts to the previous bundle.
+        self.host.shared().request_callback();
+    }
+
+    /// Processes the current instance(s), rewriting parameter IDs if needed.
+    fn process_mapped(
+        &mut self,
+        process: Process,
+        audio: &mut Audio,
+        input_events: &InputEvents,
+        output_events: &mut OutputEvents,
+        replay_notes: bool,
+    ) -> Result<ProcessStatus, PluginError> {
+        let current_ids = self
+            .current_audio_processor
+            .access_shared_handler(|h| h.param_id_map());
+        let fade_out_ids = self
+            .fade_out_audio_processor
+            .as_ref()
+            .and_then(|p| p.access_shared_handler(|h| h.param_id_map()));
+
+        if current_ids.is_none() && fade_out_ids.is_none() {
+            return self.process_wrapped(
+                process,
+                audio,
+                input_events,
+                input_events,
+                output_events,
+                replay_notes,
+            );
+        }
+
+        // Some parameters were renumbered by a reload: rewrite their IDs both ways.
+        // The buffers are put back even if processing panics, so that they never reallocate.
+        let mut buffers = core::mem::take(&mut self.param_event_buffers);
+        let status = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            self.process_remapped(
+                &mut buffers,
+                current_ids.as_deref(),
+                fade_out_ids.as_deref(),
+                process,
+                audio,
+                input_events,
+                output_events,
+                replay_notes,
+            )
+        }));
+
+        self.param_event_buffers = buffers;
+        status.unwrap_or(Err(PluginError::Message("Panicked while processing")))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_remapped(
+        &mut self,
+        buffers: &mut ParamEventBuffers,
+        current_ids: Option<&ParamIdMap>,
+        fade_out_ids: Option<&ParamIdMap>,
+        process: Process,
+        audio: &mut Audio,
+        input_events: &InputEvents,
+        output_events: &mut OutputEvents,
+        replay_notes: bool,
+    ) -> Result<ProcessStatus, PluginError> {
+        buffers.clear();
+
+        if let Some(ids) = current_ids {
+            let mut input = OutputEvents::from_buffer(&mut buffers.input);
+            ids.map_events(input_events, &mut input, true);
+        }
+
+        if let Some(ids) = fade_out_ids {
+            let mut input = OutputEvents::from_buffer(&mut buffers.fade_out_input);
+            ids.map_events(input_events, &mut input, true);
+        }
+
+        let current_input;
+        let current_input = if current_ids.is_some() {
+            current_input = InputEvents::from_buffer(&buffers.input);
+            &current_input
+        } else {
+            input_events
+        };
+
+        let fade_out_input;
+        let fade_out_input = if fade_out_ids.is_some() {
+            fade_out_input = InputEvents::from_buffer(&buffers.fade_out_input);
+            &fade_out_input
+        } else {
+            input_events
+        };
+
+        let status = self.process_wrapped(
+            process,
+            audio,
+            current_input,
+            fade_out_input,
+            &mut OutputEvents::from_buffer(&mut buffers.output),
+            replay_notes,
+        );
+
+        let output = InputEvents::from_buffer(&buffers.output);
+        match current_ids {
+            Some(ids) => ids.map_events(&output, output_events, false),
+            None => {
+                for event in &output {
+                    let _ = output_events.try_push(event);
+                }
+            }
+        }
+
+        status
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_wrapped(
+        &mut self,
+        process: Process,
+        audio: &mut Audio,
+        input_events: &InputEvents,
+        fade_out_input_events: &InputEvents,
+        output_events: &mut OutputEvents,
+        replay_notes: bool,
+    ) -> Result<ProcessStatus, PluginError> {
+        let combined;
+        let in_events;
+
+        // Deliver the recovered notes on the first block the new instance processes
+        let in_events = if replay_notes {
+            combined = (&self.input_event_buffer, input_events);
+            in_events = InputEvents::from_buffer(&combined);
+            &in_events
+        } else {
+            input_events
+        };
+
+        if self.fade_out_audio_processor.is_none() && !self.is_fading_in_alone {
+            let (audio_inputs, mut audio_outputs) = AudioPorts::from_plugin_audio_mut(audio);
+
+            return Ok(self
+                .current_audio_processor
+                .ensure_processing_started()?
+                .process(
+                    &audio_inputs,
+                    &mut audio_outputs,
+                    in_events,
+                    output_events,
+                    process.steady_time,
+                    process.transport,
+                )?);
+        }
+
+        self.output_buffers.update_precisions(audio)?;
+        let audio_inputs = InputAudioBuffers::from_plugin_audio(audio);
+
+        let main_status = if self.is_incoming_waiting() {
+            self.output_buffers.silence_main();
+            None
+        } else {
+            let mut audio_outputs = self.output_buffers.output_buffers_for(true, audio);
+
+            let main_status = self
+                .current_audio_processor
+                .ensure_processing_started()?
+                .process(
+                    &audio_inputs,
+                    &mut audio_outputs,
+                    in_events,
+                    output_events,
+                    process.steady_time,
+                    process.transport,
+                )?;
+
+            // Update the constant masks for all outputs
+            for (mut output, info) in audio.output_ports().zip(audio_outputs.port_infos()) {
+                output.set_constant_mask(info.constant_mask())
+            }
+
+            Some(main_status)
+        };
+
+        let fade_out_status = match &mut self.fade_out_audio_processor {
+            Some(fade_out_audio_processor) => {
+                let mut audio_outputs = self.output_buffers.output_buffers_for(false, audio);
+
+                let fade_out_status = fade_out_audio_processor
+                    .ensure_processing_started()
+                    .and_then(|p| {
+                        p.process(
+                            &audio_inputs,
+                            &mut audio_outputs,
+                            fade_out_input_events,
+                            &mut OutputEvents::void(), // Ignore all output events from the instance being faded out
+                            process.steady_time,
+                            process.transport,
+                        )
+                    })
+                    .ok()
+                    .filter(|_| {
+                        !fade_out_audio_processor.access_shared_handler(|h| h.is_quarantined())
+                    });
+
+                if fade_out_status.is_none() {
+                    // The outgoing instance failed: fade in from silence instead of its leftovers.
+                    fade_out_audio_processor.access_shared_handler(|h| h.quarantine());
+                    self.output_buffers.silence_fading_out();
+
+                    log_from_audio_thread(
+                        LogLevel::Warn,
+                        "The plugin instance being faded out failed, dropping it.",
+                    );
+                }
+
+                fade_out_status
+            }
+            None => None,
+        };
+
+        self.output_buffers
+            .output_crossfade(&mut self.cross_fader, audio)?;
+
+        let fade_out_is_over = fade_out_status.is_none() || self.cross_fader.is_fade_out_done();
+
+        // Without overlap, the outgoing instance is stopped as soon as it is faded out, before
+        // the incoming one starts.
+        let fade_out_status = match self.fade_out_audio_processor.take() {
+            Some(old_processor) if fade_out_is_over => {
+                self.channel.send_for_disposal(old_processor.into_stopped()); // Byee
+                self.is_fading_in_alone = !self.cross_fader.is_done();
+
+                if self.is_fading_in_alone {
+                    self.output_buffers.silence_fading_out();
+                }
+
+                // We don't care about if the older instance still wanted to process, we
+                // already faded it away
+                None
+            }
+            old_processor => {
+                self.fade_out_audio_processor = old_processor;
+                fade_out_status
+            }
+        };
+
+        if self.cross_fader.is_done() {
+            self.is_fading_in_alone = false;
+        }
+
+        let status = match (main_status, fade_out_status) {
+            (Some(main_status), Some(fade_out_status)) => {
+                main_status.combined_with(fade_out_status)
+            }
+            (Some(status), None) | (None, Some(status)) => status,
+            // The incoming instance has yet to start.
+            (None, None) => ProcessStatus::Continue,
+        };
+
+        Ok(status)
+    }
+
+    /// Returns true while the incoming instance must not be processed yet, see
+    /// [`CrossfadeConfig::without_overlap`](crate::CrossfadeConfig::without_overlap).
+    fn is_incoming_waiting(&self) -> bool {
+        self.fade_out_audio_processor.is_some() && self.cross_fader.is_fading_out_only()
+    }
 }
 
 impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThread<'a>>
@@ -67,7 +421,7 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
         shared: &'a WrapperPluginShared<'a>,
         audio_config: PluginAudioConfiguration,
     ) -> Result<Self, PluginError> {
-        main_thread.timers.init(&mut main_thread.host); // Do it now I guess... (TODO: fixme)
+        main_thread.timers.init(&mut main_thread.host); // In case on_main_thread wasn't called yet
 
         let audio_processor =
             WrapperHost::activate_instance(&mut main_thread.plugin_instance, audio_config)?;
@@ -90,13 +444,17 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
             current_audio_processor: audio_processor.into(),
             fade_out_audio_processor: None,
             channel: audio_processor_channel,
-            input_event_buffer: EventBuffer::with_capacity(64),
+            input_event_buffer: EventBuffer::with_capacity(MAX_RECOVERED_EVENTS),
+            output_event_buffer: EventBuffer::with_capacity(EVENT_BUFFER_CAPACITY),
+            param_event_buffers: ParamEventBuffers::with_capacity(EVENT_BUFFER_CAPACITY),
             note_tracker: NoteTracker::new(),
-            cross_fader: CrossFader::new(audio_config.sample_rate, CROSSFADE_TIME),
+            cross_fader: CrossFader::new(audio_config.sample_rate, shared.crossfade),
             output_buffers: OutputBuffers::new_from_config(
                 &main_thread.audio_ports_info,
                 audio_config,
             ),
+            is_fading_in_alone: false,
+            is_note_recovery_pending: false,
         })
     }
 
@@ -106,88 +464,55 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
         mut audio: Audio,
         events: Events,
     ) -> Result<ProcessStatus, PluginError> {
+        #[cfg(feature = "realtime-alloc-check")]
+        let _guard = crate::alloc_check::RealtimeScope::enter();
+
         self.note_tracker.handle_note_events(events.input);
 
         // Hot swap! (but only if we're not already crossfading two instances)
-        let swapped = if self.fade_out_audio_processor.is_some() {
-            false
-        } else {
-            self.swap_if_needed()
-        };
-
-        let status = if let Some(fade_out_audio_processor) = &mut self.fade_out_audio_processor {
-            let audio_inputs = InputAudioBuffers::from_plugin_audio(&audio);
-
-            let mut audio_outputs = self.output_buffers.output_buffers_for(true, &audio);
-
-            let combined;
-            let in_events;
-
-            let in_events = if swapped {
-                combined = (&self.input_event_buffer, events.input);
-                in_events = InputEvents::from_buffer(&combined);
-                &in_events
-            } else {
-                events.input
-            };
-
-            let main_status = self
-                .current_audio_processor
-                .ensure_processing_started()?
-                .process(
-                    &audio_inputs,
-                    &mut audio_outputs,
-                    in_events,
-                    events.output,
-                    process.steady_time,
-                    process.transport,
-                )?;
+        if self.fade_out_audio_processor.is_none() && !self.is_fading_in_alone {
+            self.swap_if_needed();
+        }
 
-            // Update the constant masks for all outputs
-            for (mut output, info) in audio.output_ports().zip(audio_outputs.port_infos()) {
-                output.set_constant_mask(info.constant_mask())
-            }
+        let replay_notes = self.recover_notes_if_needed();
 
-            let mut audio_outputs = self.output_buffers.output_buffers_for(false, &audio);
+        // Go through our own buffer, to see which voices the plugin ended.
+        // It is put back even if processing panics, so that it never reallocates.
+        let mut output_buffer = core::mem::take(&mut self.output_event_buffer);
+        output_buffer.clear();
 
-            let fade_out_status = fade_out_audio_processor
-                .ensure_processing_started()?
-                .process(
-                    &audio_inputs,
-                    &mut audio_outputs,
+        let status = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            self.call_wrapped(|p| {
+                p.process_mapped(
+                    process,
+                    &mut audio,
                     events.input,
-                    &mut OutputEvents::void(), // Ignore all output events from the instance being faded out
-                    process.steady_time,
-                    process.transport,
-                )?;
+                    &mut OutputEvents::from_buffer(&mut output_buffer),
+                    replay_notes,
+                )
+            })
+        }));
+
+        let output = InputEvents::from_buffer(&output_buffer);
+        self.note_tracker.handle_output_events(&output);
+        for event in &output {
+            let _ = events.output.try_push(event);
+        }
 
-            self.output_buffers
-                .output_crossfade(&mut self.cross_fader, &mut audio)?;
+        self.output_event_buffer = output_buffer;
 
-            if self.cross_fader.is_done() {
-                // PANIC: we just checked above if the audio processor was there
-                let old_processor = self.fade_out_audio_processor.take().unwrap();
-                self.channel.send_for_disposal(old_processor.into_stopped()); // Byee
+        let status = match status {
+            Ok(Some(status)) => status,
+            failed => {
+                // Processing panicked halfway, the instance can't be trusted anymore.
+                if failed.is_err() && !self.is_quarantined() {
+                    self.quarantine();
+                }
 
-                // We don't care about if the older instance still wanted to process, we already
-                // faded it away
-                main_status
-            } else {
-                main_status.combined_with(fade_out_status)
+                // The instance failed: stay silent until the main thread swaps it out.
+                output_silence(&mut audio)?;
+                ProcessStatus::Continue
             }
-        } else {
-            let (audio_inputs, mut audio_outputs) = AudioPorts::from_plugin_audio_mut(&mut audio);
-
-            self.current_audio_processor
-                .ensure_processing_started()?
-                .process(
-                    &audio_inputs,
-                    &mut audio_outputs,
-                    events.input,
-                    events.output,
-                    process.steady_time,
-                    process.transport,
-                )?
         };
 
         self.process_requests();
@@ -195,7 +520,20 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
         Ok(status)
     }
 
-    fn deactivate(self, main_thread: &mut WrapperPluginMainThread<'a>) {
+    fn deactivate(mut self, main_thread: &mut WrapperPluginMainThread<'a>) {
+        if let Some(undelivered) = self.channel.take_undelivered_processor() {
+            main_thread.deactivate_wrapped_instance(undelivered);
+        }
+
+        let undelivered = main_thread
+            .audio_processor_channel
+            .as_mut()
+            .and_then(|c| c.take_undelivered_processor());
+
+        if let Some(undelivered) = undelivered {
+            main_thread.deactivate_wrapped_instance(undelivered);
+        }
+
         // Can happen if we swapped but audio processor didn't (yet?)
         main_thread.deactivate_wrapped_instance(self.current_audio_processor.into_stopped());
 
@@ -206,6 +544,9 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
         if let Some(channel) = main_thread.audio_processor_channel.take() {
             channel.consume(self.channel)
         }
+
+        main_thread.apply_pending_audio_ports();
+        main_thread.apply_pending_note_ports();
     }
 
     fn reset(&mut self) {
@@ -243,3 +584,80 @@ impl<'a> PluginAudioProcessor<'a, WrapperPluginShared<'a>, WrapperPluginMainThre
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapper::test_plugin::{
+        new_wrapper_instance, process_empty_block, test_bundle, TEST_AUDIO_CONFIG,
+    };
+    use clack_plugin::extensions::wrapper::PluginWrapper;
+
+    /// Calls into the hot-reloader's own plugin, behind the wrapper instance.
+    #[allow(unsafe_code)]
+    fn with_wrapper<T>(
+        wrapper: &mut PluginInstance<WrapperHost>,
+        f: impl FnOnce(&mut WrapperPluginMainThread, &mut WrapperPluginAudioProcessor) -> T,
+    ) -> T {
+        let plugin = wrapper.plugin_handle().as_raw();
+
+        // SAFETY: the instance is active, and only ever used from this thread.
+        unsafe {
+            PluginWrapper::<WrapperPlugin>::handle(plugin, |p| {
+                Ok(f(p.main_thread().as_mut(), p.audio_processor()?.as_mut()))
+            })
+        }
+        .unwrap()
+    }
+
+    fn is_processing_current_instance(wrapper: &mut PluginInstance<WrapperHost>) -> bool {
+        with_wrapper(wrapper, |main_thread, audio_processor| {
+            let current = main_thread
+                .plugin_instance
+                .access_shared_handler(|h| h as *const WrapperHostShared);
+
+            let processed = audio_processor
+                .current_audio_processor
+                .access_shared_handler(|h| h as *const WrapperHostShared);
+
+            core::ptr::eq(current, processed)
+        })
+    }
+
+    #[test]
+    fn swapping_while_processing_does_not_allocate() {
+        let mut wrapper = new_wrapper_instance();
+        let mut processor: clack_host::process::PluginAudioProcessor<WrapperHost> =
+            WrapperHost::activate_instance(&mut wrapper, TEST_AUDIO_CONFIG)
+                .unwrap()
+                .into();
+
+        process_empty_block(&mut processor);
+
+        let bundle = test_bundle(false);
+        let swapped = with_wrapper(&mut wrapper, |main_thread, _| {
+            main_thread.swap_to_bundle(&bundle).is_ok()
+        });
+        assert!(swapped);
+        assert!(!is_processing_current_instance(&mut wrapper));
+
+        // The test plugin hands its DSP state over, so the new instance takes over right away.
+        // Allocations are only counted with the realtime-alloc-check feature. Other tests may run
+        // concurrently, but none of them allocate in a realtime scope.
+        #[cfg(feature = "realtime-alloc-check")]
+        let allocations_before = crate::alloc_check::realtime_allocation_count();
+
+        process_empty_block(&mut processor);
+        process_empty_block(&mut processor);
+
+        #[cfg(feature = "realtime-alloc-check")]
+        assert_eq!(
+            crate::alloc_check::realtime_allocation_count(),
+            allocations_before
+        );
+
+        assert!(is_processing_current_instance(&mut wrapper));
+
+        wrapper.deactivate(processor.into_stopped());
+    }
+}
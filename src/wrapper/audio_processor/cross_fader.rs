@@ -1,15 +1,55 @@
+use crate::config::{CrossfadeConfig, CrossfadeCurve};
+
+/// A sample type that can be cross-faded, in either direction.
+pub trait Sample: Copy {
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Sample for f32 {
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Sample for f64 {
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
 pub struct CrossFader {
     remaining_fade_time_samples: u32,
     fade_time_samples: u32,
+    curve: CrossfadeCurve,
+    overlap: bool,
 }
 
 impl CrossFader {
-    pub fn new(sample_rate: f64, fade_time_secs: f64) -> Self {
-        let fade_time_samples = (fade_time_secs * sample_rate).floor() as u32;
+    pub fn new(sample_rate: f64, config: CrossfadeConfig) -> Self {
+        let fade_time_samples = if config.curve == CrossfadeCurve::Instant {
+            0
+        } else {
+            (config.duration.as_secs_f64() * sample_rate).floor() as u32
+        };
 
         Self {
             fade_time_samples,
             remaining_fade_time_samples: fade_time_samples,
+            curve: config.curve,
+            overlap: config.overlap,
         }
     }
 
@@ -17,37 +57,153 @@ impl CrossFader {
         self.remaining_fade_time_samples = self.fade_time_samples
     }
 
-    pub fn apply_crossfade(&self, fade_in: &[f32], fade_out: &[f32], output: &mut [f32]) {
+    /// Returns true if the switch to the new instance happens immediately, without any fade.
+    pub fn is_instant(&self) -> bool {
+        self.fade_time_samples == 0
+    }
+
+    pub fn apply_crossfade<I: Sample, O: Sample>(
+        &self,
+        fade_in: &[I],
+        fade_out: &[I],
+        output: &mut [O],
+    ) {
         assert_eq!(fade_in.len(), output.len()); // To help with compiler optimizations a bit
         assert_eq!(fade_out.len(), output.len());
 
+        if self.is_instant() {
+            for (fade_in, output) in fade_in.iter().zip(output) {
+                *output = O::from_f64(fade_in.to_f64());
+            }
+            return;
+        }
+
         let mut remaining_ratio =
-            self.remaining_fade_time_samples as f32 / self.fade_time_samples as f32;
-        let step_per_sample = 1.0 / self.fade_time_samples as f32;
-        dbg!(
-            remaining_ratio,
-            step_per_sample,
-            self.remaining_fade_time_samples,
-            self.fade_time_samples
-        );
+            self.remaining_fade_time_samples as f64 / self.fade_time_samples as f64;
+        let step_per_sample = 1.0 / self.fade_time_samples as f64;
 
         for ((fade_in, fade_out), output) in fade_in.iter().zip(fade_out).zip(output) {
-            let fade_out_ratio = remaining_ratio;
-            let fade_in_ratio = 1.0 - fade_out_ratio;
+            let (fade_in_gain, fade_out_gain) = self.gains(1.0 - remaining_ratio);
 
-            *output = (fade_in * fade_in_ratio) + (fade_out * fade_out_ratio);
+            *output = O::from_f64(
+                (fade_in.to_f64() * fade_in_gain) + (fade_out.to_f64() * fade_out_gain),
+            );
 
             remaining_ratio = (remaining_ratio - step_per_sample).max(0.0);
         }
     }
 
+    /// Computes the gains of the incoming and outgoing instances, given the progress of the
+    /// whole transition (from 0 to 1).
+    #[inline]
+    fn gains(&self, progress: f64) -> (f64, f64) {
+        if self.overlap {
+            return (
+                self.curve.fade_in_gain(progress),
+                self.curve.fade_in_gain(1.0 - progress),
+            );
+        }
+
+        // Fade the outgoing instance out during the first half, then the incoming one in.
+        if progress < 0.5 {
+            (0.0, self.curve.fade_in_gain(1.0 - progress * 2.0))
+        } else {
+            (self.curve.fade_in_gain(progress * 2.0 - 1.0), 0.0)
+        }
+    }
+
     pub fn advance(&mut self, sample_count: u32) {
+        let was_fading_out_only = self.is_fading_out_only();
+
         self.remaining_fade_time_samples = self
             .remaining_fade_time_samples
             .saturating_sub(sample_count);
+
+        // Without overlap, the fade-in starts on a block boundary: the incoming instance can't
+        // have processed the end of the block the fade-out finished in.
+        if was_fading_out_only {
+            self.remaining_fade_time_samples = self
+                .remaining_fade_time_samples
+                .max(self.fade_in_time_samples());
+        }
     }
 
     pub fn is_done(&self) -> bool {
         self.remaining_fade_time_samples == 0
     }
+
+    /// Returns true once the outgoing instance has been faded out completely.
+    pub fn is_fade_out_done(&self) -> bool {
+        if self.overlap {
+            self.is_done()
+        } else {
+            self.remaining_fade_time_samples <= self.fade_in_time_samples()
+        }
+    }
+
+    /// Returns true while only the outgoing instance is audible, i.e. while fading it out
+    /// without overlap.
+    pub fn is_fading_out_only(&self) -> bool {
+        !self.overlap && !self.is_fade_out_done()
+    }
+
+    /// Without overlap, how long the second half of the transition lasts.
+    #[inline]
+    fn fade_in_time_samples(&self) -> u32 {
+        self.fade_time_samples / 2
+    }
+}
+
+impl CrossfadeCurve {
+    /// The gain of a signal being faded in, given the progress of the fade (from 0 to 1).
+    ///
+    /// The gain of the signal being faded out is the same curve, mirrored.
+    #[inline]
+    fn fade_in_gain(self, progress: f64) -> f64 {
+        match self {
+            CrossfadeCurve::Linear => progress,
+            CrossfadeCurve::EqualPower => (progress * std::f64::consts::FRAC_PI_2).sin(),
+            CrossfadeCurve::SCurve => progress * progress * (3.0 - 2.0 * progress),
+            CrossfadeCurve::Instant => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cross_fader(config: CrossfadeConfig) -> CrossFader {
+        // 100 samples
+        CrossFader::new(1000.0, config.with_duration(Duration::from_millis(100)))
+    }
+
+    #[test]
+    fn fade_in_without_overlap_starts_on_a_block_boundary() {
+        let mut cross_fader = cross_fader(CrossfadeConfig::new().without_overlap());
+        assert!(cross_fader.is_fading_out_only());
+
+        cross_fader.advance(30);
+        assert!(cross_fader.is_fading_out_only());
+
+        // This block crosses the middle of the transition.
+        cross_fader.advance(30);
+        assert!(cross_fader.is_fade_out_done());
+        assert!(!cross_fader.is_fading_out_only());
+        assert_eq!(cross_fader.remaining_fade_time_samples, 50);
+
+        cross_fader.advance(50);
+        assert!(cross_fader.is_done());
+    }
+
+    #[test]
+    fn overlapping_fade_processes_both_instances() {
+        let mut cross_fader = cross_fader(CrossfadeConfig::new());
+        assert!(!cross_fader.is_fading_out_only());
+
+        cross_fader.advance(60);
+        assert!(!cross_fader.is_fade_out_done());
+        assert_eq!(cross_fader.remaining_fade_time_samples, 40);
+    }
 }
@@ -1,87 +1,886 @@
-use clack_host::events::event_types::NoteOnEvent;
+use crate::wrapper::InputNoteDialects;
+use clack_extensions::note_ports::{NoteDialect, NoteDialects};
+use clack_host::events::event_types::{
+    Midi2Event, MidiEvent, NoteExpressionEvent, NoteExpressionType, NoteOnEvent,
+};
 use clack_host::events::spaces::CoreEventSpace;
+use clack_host::events::{Match, UnknownEvent};
 use clack_host::prelude::{EventBuffer, InputEvents, Pckn};
 
+/// How many held notes are tracked at most, across all ports and channels.
+const MAX_ACTIVE_NOTES: usize = 128;
+/// How many port and channel pairs get their controller state tracked at most.
+const MAX_TRACKED_CHANNELS: usize = 64;
+/// How many events replaying the held notes takes at most, expressions included.
+const MAX_RECOVERED_NOTE_EVENTS: usize = MAX_ACTIVE_NOTES * (1 + EXPRESSION_TYPES.len());
+/// How many events are replayed at most when swapping instances. Controller state gets whatever
+/// room the notes leave, so that the buffer holding them never has to grow.
+pub const MAX_RECOVERED_EVENTS: usize = 2 * MAX_RECOVERED_NOTE_EVENTS;
+
+/// The pitch bend range of MPE member channels, and of MIDI 2.0 per-note pitch bend, in
+/// semitones. Both are only defaults, but the actual ranges can't be known here.
+const PER_NOTE_PITCH_BEND_RANGE: f64 = 48.0;
+
+const MIDI_SUSTAIN_PEDAL: u8 = 64;
+const MIDI_BRIGHTNESS: u8 = 74;
+const MIDI_ALL_SOUND_OFF: u8 = 120;
+const MIDI_RESET_ALL_CONTROLLERS: u8 = 121;
+const MIDI_ALL_NOTES_OFF: u8 = 123;
+
+const EXPRESSION_TYPES: [NoteExpressionType; 7] = [
+    NoteExpressionType::Volume,
+    NoteExpressionType::Pan,
+    NoteExpressionType::Tuning,
+    NoteExpressionType::Vibrato,
+    NoteExpressionType::Expression,
+    NoteExpressionType::Brightness,
+    NoteExpressionType::Pressure,
+];
+
+/// The latest value of each note expression of a note.
+#[derive(Copy, Clone, Debug, Default)]
+struct NoteExpressions {
+    values: [f64; EXPRESSION_TYPES.len()],
+    is_set: [bool; EXPRESSION_TYPES.len()],
+}
+
+impl NoteExpressions {
+    fn set(&mut self, expression_type: NoteExpressionType, value: f64) {
+        if let Some(index) = EXPRESSION_TYPES.iter().position(|t| *t == expression_type) {
+            self.values[index] = value;
+            self.is_set[index] = true;
+        }
+    }
+
+    fn get(&self, expression_type: NoteExpressionType) -> Option<f64> {
+        let index = EXPRESSION_TYPES
+            .iter()
+            .position(|t| *t == expression_type)?;
+        self.is_set[index].then_some(self.values[index])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (NoteExpressionType, f64)> + '_ {
+        EXPRESSION_TYPES
+            .iter()
+            .zip(self.values.iter().zip(&self.is_set))
+            .filter(|(_, (_, is_set))| **is_set)
+            .map(|(expression_type, (value, _))| (*expression_type, *value))
+    }
+}
+
 #[derive(Debug)]
 struct ActiveNote {
     port_index: u16,
+    /// For MIDI 2.0 notes, this also includes the group: `group * 16 + channel`.
     channel: u16,
     key: u16,
-    note_id: u32,
-    velocity: f64, // TODO: all the other note stuff
+    /// Some hosts won't populate note IDs, and MIDI notes don't have any.
+    note_id: Option<u32>,
+    velocity: f64,
+    expressions: NoteExpressions,
+    /// Released while the sustain pedal was down: the note keeps sounding until it goes up.
+    is_released: bool,
 }
 
 impl ActiveNote {
-    fn to_note_event(&self) -> NoteOnEvent {
-        NoteOnEvent::new(
-            0,
-            Pckn::new(self.port_index, self.channel, self.key, self.note_id),
-            self.velocity,
-        )
-    }
-
     fn from_note_on_event(event: &NoteOnEvent) -> Option<Self> {
         Some(Self {
-            // Some hosts won't populate note_id
-            note_id: event.note_id().into_specific().unwrap_or(0),
+            note_id: event.note_id().into_specific(),
             port_index: event.port_index().into_specific()?,
             channel: event.channel().into_specific()?,
             key: event.key().into_specific()?,
             velocity: event.velocity(),
+            expressions: NoteExpressions::default(),
+            is_released: false,
         })
     }
+
+    fn pckn(&self) -> Pckn {
+        let note_id = match self.note_id {
+            Some(note_id) => Match::Specific(note_id),
+            None => Match::All,
+        };
+
+        Pckn::new(
+            Match::Specific(self.port_index),
+            Match::Specific(self.channel),
+            Match::Specific(self.key),
+            note_id,
+        )
+    }
+
+    fn is_on(&self, port_index: u16, channel: u16, key: u16) -> bool {
+        self.port_index == port_index && self.channel == channel && self.key == key
+    }
+
+    fn push_as_clap(&self, events: &mut RecoveredEvents) {
+        // CLAP events have no MIDI 2.0 groups, only the channels of the first one can be addressed.
+        if self.channel > 0xF {
+            return;
+        }
+
+        events.push(&NoteOnEvent::new(0, self.pckn(), self.velocity));
+
+        for (expression_type, value) in self.expressions.iter() {
+            events.push(&NoteExpressionEvent::new(
+                0,
+                self.pckn(),
+                expression_type,
+                value,
+            ));
+        }
+    }
+
+    fn push_as_midi(&self, events: &mut RecoveredEvents, is_mpe: bool) {
+        // MIDI 1.0 can't address the channels of the other MIDI 2.0 groups.
+        let (Ok(channel), Ok(key)) = (u8::try_from(self.channel), u8::try_from(self.key)) else {
+            return;
+        };
+
+        if channel > 0xF || key > 0x7F {
+            return;
+        }
+
+        // MPE expressions are per-channel, and must be sent before the note starts.
+        if is_mpe {
+            if let Some(tuning) = self.expressions.get(NoteExpressionType::Tuning) {
+                let bend = from_bipolar(tuning / PER_NOTE_PITCH_BEND_RANGE, 14);
+                push_midi(
+                    events,
+                    self.port_index,
+                    [0xE0 | channel, (bend & 0x7F) as u8, (bend >> 7) as u8],
+                );
+            }
+
+            if let Some(brightness) = self.expressions.get(NoteExpressionType::Brightness) {
+                let value = from_unipolar(brightness, 7) as u8;
+                push_midi(
+                    events,
+                    self.port_index,
+                    [0xB0 | channel, MIDI_BRIGHTNESS, value],
+                );
+            }
+
+            if let Some(pressure) = self.expressions.get(NoteExpressionType::Pressure) {
+                let value = from_unipolar(pressure, 7) as u8;
+                push_midi(events, self.port_index, [0xD0 | channel, value, 0]);
+            }
+        }
+
+        // A velocity of 0 would be a note off.
+        let velocity = (from_unipolar(self.velocity, 7) as u8).max(1);
+        push_midi(events, self.port_index, [0x90 | channel, key, velocity]);
+
+        if !is_mpe {
+            if let Some(pressure) = self.expressions.get(NoteExpressionType::Pressure) {
+                let value = from_unipolar(pressure, 7) as u8;
+                push_midi(events, self.port_index, [0xA0 | channel, key, value]);
+            }
+        }
+    }
+
+    fn push_as_midi2(&self, events: &mut RecoveredEvents) {
+        if self.channel > 0xFF || self.key > 0x7F {
+            return;
+        }
+
+        let (group, channel) = ((self.channel >> 4) as u32, (self.channel & 0xF) as u32);
+        let key = self.key as u32;
+        let velocity = from_unipolar(self.velocity, 16);
+
+        push_midi2(
+            events,
+            self.port_index,
+            [midi2_header(group, 0x9, channel, key << 8), velocity << 16],
+        );
+
+        if let Some(tuning) = self.expressions.get(NoteExpressionType::Tuning) {
+            let bend = from_bipolar(tuning / PER_NOTE_PITCH_BEND_RANGE, 32);
+            push_midi2(
+                events,
+                self.port_index,
+                [midi2_header(group, 0x6, channel, key << 8), bend],
+            );
+        }
+
+        if let Some(pressure) = self.expressions.get(NoteExpressionType::Pressure) {
+            push_midi2(
+                events,
+                self.port_index,
+                [
+                    midi2_header(group, 0xA, channel, key << 8),
+                    from_unipolar(pressure, 32),
+                ],
+            );
+        }
+    }
 }
 
 impl PartialEq<Pckn> for ActiveNote {
     fn eq(&self, other: &Pckn) -> bool {
-        other.note_id.matches(self.note_id)
+        let note_id_matches = match self.note_id {
+            Some(note_id) => other.note_id.matches(note_id),
+            None => true,
+        };
+
+        note_id_matches
             && other.port_index.matches(self.port_index)
             && other.channel.matches(self.channel)
             && other.key.matches(self.key)
     }
 }
 
+/// The controller state of a MIDI channel. All values are stored at MIDI 2.0 resolution.
+#[derive(Debug)]
+struct ChannelState {
+    port_index: u16,
+    /// For MIDI 2.0 messages, this also includes the group: `group * 16 + channel`.
+    channel: u16,
+    controllers: [Option<u32>; 128],
+    pitch_bend: Option<u32>,
+    pressure: Option<u32>,
+}
+
+impl ChannelState {
+    fn new(port_index: u16, channel: u16) -> Self {
+        Self {
+            port_index,
+            channel,
+            controllers: [None; 128],
+            pitch_bend: None,
+            pressure: None,
+        }
+    }
+
+    fn is_sustained(&self) -> bool {
+        self.controllers[MIDI_SUSTAIN_PEDAL as usize].is_some_and(is_pedal_down)
+    }
+
+    fn reset_controllers(&mut self) {
+        self.controllers = [None; 128];
+        self.pitch_bend = None;
+        self.pressure = None;
+    }
+
+    fn push_as_midi(&self, events: &mut RecoveredEvents) {
+        let Ok(channel) = u8::try_from(self.channel) else {
+            return;
+        };
+
+        if channel > 0xF {
+            return;
+        }
+
+        for (index, value) in self.controllers.iter().enumerate() {
+            if let Some(value) = value {
+                let value = (value >> 25) as u8;
+                push_midi(
+                    events,
+                    self.port_index,
+                    [0xB0 | channel, index as u8, value],
+                );
+            }
+        }
+
+        if let Some(pitch_bend) = self.pitch_bend {
+            let pitch_bend = pitch_bend >> 18;
+            push_midi(
+                events,
+                self.port_index,
+                [
+                    0xE0 | channel,
+                    (pitch_bend & 0x7F) as u8,
+                    (pitch_bend >> 7) as u8,
+                ],
+            );
+        }
+
+        if let Some(pressure) = self.pressure {
+            push_midi(
+                events,
+                self.port_index,
+                [0xD0 | channel, (pressure >> 25) as u8, 0],
+            );
+        }
+    }
+
+    fn push_as_midi2(&self, events: &mut RecoveredEvents) {
+        if self.channel > 0xFF {
+            return;
+        }
+
+        let (group, channel) = ((self.channel >> 4) as u32, (self.channel & 0xF) as u32);
+
+        for (index, value) in self.controllers.iter().enumerate() {
+            if let Some(value) = value {
+                let index = (index as u32) << 8;
+                push_midi2(
+                    events,
+                    self.port_index,
+                    [midi2_header(group, 0xB, channel, index), *value],
+                );
+            }
+        }
+
+        if let Some(pitch_bend) = self.pitch_bend {
+            push_midi2(
+                events,
+                self.port_index,
+                [midi2_header(group, 0xE, channel, 0), pitch_bend],
+            );
+        }
+
+        if let Some(pressure) = self.pressure {
+            push_midi2(
+                events,
+                self.port_index,
+                [midi2_header(group, 0xD, channel, 0), pressure],
+            );
+        }
+    }
+}
+
+/// Follows the notes held by the host, along with their expressions and the state of the MIDI
+/// channels, so that they can be replayed to a new instance.
+///
+/// All storage is preallocated: this runs on the audio thread.
 pub struct NoteTracker {
     active_notes: Vec<ActiveNote>,
+    channels: Vec<ChannelState>,
 }
 
 impl NoteTracker {
     pub fn new() -> Self {
         NoteTracker {
-            active_notes: Vec::with_capacity(128),
+            active_notes: Vec::with_capacity(MAX_ACTIVE_NOTES),
+            channels: Vec::with_capacity(MAX_TRACKED_CHANNELS),
         }
     }
 
+    /// Follows the events sent to the wrapped plugin.
     pub fn handle_note_events(&mut self, events: &InputEvents) {
         for event in events {
             match event.as_core_event() {
-                // TODO: check duplicates?
                 Some(CoreEventSpace::NoteOn(e)) => {
                     if let Some(active_note) = ActiveNote::from_note_on_event(e) {
-                        self.active_notes.push(active_note)
+                        self.add_note(active_note);
                     }
                 }
                 Some(CoreEventSpace::NoteOff(e)) => {
-                    self.active_notes.retain(|note| *note != e.pckn())
+                    let pckn = e.pckn();
+                    self.release_notes(|n| *n == pckn)
                 }
+                Some(CoreEventSpace::NoteChoke(e)) => self.remove_notes(e.pckn()),
+                Some(CoreEventSpace::NoteExpression(e)) => {
+                    let Some(expression_type) = e.expression_type() else {
+                        continue;
+                    };
+
+                    let pckn = e.pckn();
+                    for note in self.active_notes.iter_mut().filter(|n| **n == pckn) {
+                        note.expressions.set(expression_type, e.value());
+                    }
+                }
+                Some(CoreEventSpace::Midi(e)) => self.handle_midi(e),
+                Some(CoreEventSpace::Midi2(e)) => self.handle_midi2(e),
+                // SysEx messages can't be replayed: their buffers only live as long as the
+                // event, and there's no telling which of them are stateful.
+                Some(CoreEventSpace::MidiSysEx(_)) => {}
                 _ => {}
             }
         }
     }
 
-    pub fn recover_all_current_notes(&self, buffer: &mut EventBuffer) {
-        println!(
-            "Recovering {} active notes: {:?}",
-            self.active_notes.len(),
-            &self.active_notes
-        );
+    /// Follows the events sent by the wrapped plugin, to forget about the voices it ended.
+    pub fn handle_output_events(&mut self, events: &InputEvents) {
+        for event in events {
+            if let Some(CoreEventSpace::NoteEnd(e)) = event.as_core_event() {
+                self.remove_notes(e.pckn())
+            }
+        }
+    }
+
+    /// Replays all held notes, with their expressions and the state of their channels.
+    ///
+    /// Each port gets them in the dialect the plugin prefers on it. Ports the plugin doesn't
+    /// declare get them as CLAP events.
+    ///
+    /// At most [`MAX_RECOVERED_EVENTS`] events are pushed to the buffer.
+    pub fn recover_all_current_notes(
+        &self,
+        buffer: &mut EventBuffer,
+        dialects: &[InputNoteDialects],
+    ) {
+        let dialects_of = |port_index: u16| {
+            dialects
+                .get(port_index as usize)
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut events = RecoveredEvents {
+            buffer,
+            remaining: MAX_RECOVERED_EVENTS - MAX_RECOVERED_NOTE_EVENTS,
+        };
+
+        // Controllers (e.g. the sustain pedal) must be set before the notes start.
+        for channel in &self.channels {
+            let dialects = dialects_of(channel.port_index);
+
+            if dialects.preferred == NoteDialect::Midi2 {
+                channel.push_as_midi2(&mut events);
+            } else if dialects
+                .supported
+                .intersects(NoteDialects::MIDI | NoteDialects::MIDI_MPE)
+            {
+                channel.push_as_midi(&mut events);
+            } else if dialects.supported.contains(NoteDialects::MIDI2) {
+                channel.push_as_midi2(&mut events);
+            }
+        }
+
+        // Held notes always have room, however much controller state was replayed.
+        events.remaining += MAX_RECOVERED_NOTE_EVENTS;
 
         for note in &self.active_notes {
-            buffer.push(&note.to_note_event())
+            match dialects_of(note.port_index).preferred {
+                NoteDialect::Clap => note.push_as_clap(&mut events),
+                NoteDialect::Midi => note.push_as_midi(&mut events, false),
+                NoteDialect::MidiMpe => note.push_as_midi(&mut events, true),
+                NoteDialect::Midi2 => note.push_as_midi2(&mut events),
+            }
         }
     }
 
     pub fn reset(&mut self) {
-        self.active_notes.clear()
+        self.active_notes.clear();
+        self.channels.clear();
+    }
+
+    fn add_note(&mut self, note: ActiveNote) {
+        // A retriggered key replaces the previous note, unless they can be told apart by ID.
+        self.active_notes.retain(|n| {
+            !n.is_on(note.port_index, note.channel, note.key)
+                || (n.note_id.is_some() && n.note_id != note.note_id)
+        });
+
+        // Never grow past the preallocated capacity, this runs on the audio thread.
+        if self.active_notes.len() < self.active_notes.capacity() {
+            self.active_notes.push(note)
+        }
+    }
+
+    fn remove_notes(&mut self, pckn: Pckn) {
+        self.active_notes.retain(|note| *note != pckn)
+    }
+
+    /// Removes the matching notes, or only marks them as released if the sustain pedal of their
+    /// channel is down.
+    fn release_notes(&mut self, mut matches: impl FnMut(&ActiveNote) -> bool) {
+        let channels = &self.channels;
+
+        self.active_notes.retain_mut(|note| {
+            if !matches(note) {
+                return true;
+            }
+
+            let is_sustained = channels
+                .iter()
+                .find(|c| c.port_index == note.port_index && c.channel == note.channel)
+                .is_some_and(|c| c.is_sustained());
+
+            note.is_released = is_sustained;
+            is_sustained
+        })
+    }
+
+    /// Removes the notes that were only held by the sustain pedal of the given channel.
+    fn end_sustained_notes(&mut self, port_index: u16, channel: u16) {
+        self.active_notes
+            .retain(|n| !n.is_released || n.port_index != port_index || n.channel != channel)
+    }
+
+    fn channel_mut(&mut self, port_index: u16, channel: u16) -> Option<&mut ChannelState> {
+        let index = match self
+            .channels
+            .iter()
+            .position(|c| c.port_index == port_index && c.channel == channel)
+        {
+            Some(index) => index,
+            None if self.channels.len() < self.channels.capacity() => {
+                self.channels.push(ChannelState::new(port_index, channel));
+                self.channels.len() - 1
+            }
+            None => return None,
+        };
+
+        self.channels.get_mut(index)
+    }
+
+    fn handle_midi(&mut self, event: &MidiEvent) {
+        let port_index = event.port_index();
+        let [status, data1, data2] = event.data();
+        let channel = (status & 0xF) as u16;
+
+        match status & 0xF0 {
+            0x90 if data2 > 0 => self.add_note(ActiveNote {
+                port_index,
+                channel,
+                key: data1 as u16,
+                note_id: None,
+                velocity: to_unipolar(data2 as u32, 7),
+                expressions: NoteExpressions::default(),
+                is_released: false,
+            }),
+            0x80 | 0x90 => self.release_notes(|n| n.is_on(port_index, channel, data1 as u16)),
+            0xA0 => {
+                for note in self.notes_on_key(port_index, channel, data1 as u16) {
+                    note.expressions
+                        .set(NoteExpressionType::Pressure, to_unipolar(data2 as u32, 7));
+                }
+            }
+            0xB0 => {
+                let value = upscale(data2 as u32 & 0x7F, 7);
+                self.handle_controller(port_index, channel, data1 & 0x7F, value)
+            }
+            0xD0 => {
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.pressure = Some(upscale(data1 as u32, 7));
+                }
+            }
+            0xE0 => {
+                let pitch_bend = ((data2 as u32) << 7) | data1 as u32;
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.pitch_bend = Some(upscale(pitch_bend, 14));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_midi2(&mut self, event: &Midi2Event) {
+        let port_index = event.port_index();
+        let [header, data, ..] = event.data();
+
+        // Only MIDI 2.0 channel voice messages carry note and controller state.
+        if header >> 28 != 0x4 {
+            return;
+        }
+
+        let group = (header >> 24) & 0xF;
+        let channel = ((group << 4) | ((header >> 16) & 0xF)) as u16;
+        let index = ((header >> 8) & 0x7F) as u8;
+
+        match (header >> 20) & 0xF {
+            0x9 => self.add_note(ActiveNote {
+                port_index,
+                channel,
+                key: index as u16,
+                note_id: None,
+                velocity: to_unipolar(data >> 16, 16),
+                expressions: NoteExpressions::default(),
+                is_released: false,
+            }),
+            0x8 => self.release_notes(|n| n.is_on(port_index, channel, index as u16)),
+            0xA => {
+                for note in self.notes_on_key(port_index, channel, index as u16) {
+                    note.expressions
+                        .set(NoteExpressionType::Pressure, to_unipolar(data, 32));
+                }
+            }
+            0x6 => {
+                let tuning = to_bipolar(data, 32) * PER_NOTE_PITCH_BEND_RANGE;
+                for note in self.notes_on_key(port_index, channel, index as u16) {
+                    note.expressions.set(NoteExpressionType::Tuning, tuning);
+                }
+            }
+            0xB => self.handle_controller(port_index, channel, index, data),
+            0xD => {
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.pressure = Some(data);
+                }
+            }
+            0xE => {
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.pitch_bend = Some(data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_controller(&mut self, port_index: u16, channel: u16, controller: u8, value: u32) {
+        match controller {
+            MIDI_ALL_SOUND_OFF => self
+                .active_notes
+                .retain(|n| n.port_index != port_index || n.channel != channel),
+            // Unlike all sound off, this is held by the sustain pedal.
+            MIDI_ALL_NOTES_OFF => {
+                self.release_notes(|n| n.port_index == port_index && n.channel == channel)
+            }
+            MIDI_RESET_ALL_CONTROLLERS => {
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.reset_controllers();
+                }
+
+                self.end_sustained_notes(port_index, channel);
+            }
+            // Channel mode messages aren't state to replay.
+            0x78..=0x7F => {}
+            _ => {
+                if let Some(state) = self.channel_mut(port_index, channel) {
+                    state.controllers[controller as usize] = Some(value);
+                }
+
+                if controller == MIDI_SUSTAIN_PEDAL && !is_pedal_down(value) {
+                    self.end_sustained_notes(port_index, channel);
+                }
+            }
+        }
+    }
+
+    fn notes_on_key(
+        &mut self,
+        port_index: u16,
+        channel: u16,
+        key: u16,
+    ) -> impl Iterator<Item = &mut ActiveNote> {
+        self.active_notes
+            .iter_mut()
+            .filter(move |n| n.is_on(port_index, channel, key))
+    }
+}
+
+/// Scales a MIDI value up to 32 bits, following the MIDI 2.0 specification: the minimum, center
+/// and maximum values are preserved.
+fn upscale(value: u32, bits: u32) -> u32 {
+    let scale_bits = 32 - bits;
+    let shifted = value << scale_bits;
+    let center = 1 << (bits - 1);
+
+    if value <= center {
+        return shifted;
+    }
+
+    let repeat_bits = bits - 1;
+    let mut repeat_value = value & ((1 << repeat_bits) - 1);
+    repeat_value = if scale_bits > repeat_bits {
+        repeat_value << (scale_bits - repeat_bits)
+    } else {
+        repeat_value >> (repeat_bits - scale_bits)
+    };
+
+    let mut result = shifted;
+    while repeat_value != 0 {
+        result |= repeat_value;
+        repeat_value >>= repeat_bits;
+    }
+
+    result
+}
+
+/// Pedals are down from the middle of their range, i.e. 64 for MIDI 1.0 values.
+fn is_pedal_down(value: u32) -> bool {
+    value >= 1 << 31
+}
+
+/// Converts a MIDI value to the `0.0..=1.0` range.
+fn to_unipolar(value: u32, bits: u32) -> f64 {
+    value as f64 / ((1u64 << bits) - 1) as f64
+}
+
+/// Converts a value in the `0.0..=1.0` range to a MIDI value.
+fn from_unipolar(value: f64, bits: u32) -> u32 {
+    (value.clamp(0.0, 1.0) * ((1u64 << bits) - 1) as f64).round() as u32
+}
+
+/// Converts a centered MIDI value (e.g. pitch bend) to the `-1.0..=1.0` range.
+fn to_bipolar(value: u32, bits: u32) -> f64 {
+    let center = (1u64 << (bits - 1)) as f64;
+    (value as f64 - center) / center
+}
+
+/// Converts a value in the `-1.0..=1.0` range to a centered MIDI value.
+fn from_bipolar(value: f64, bits: u32) -> u32 {
+    let center = (1u64 << (bits - 1)) as f64;
+    let max = ((1u64 << bits) - 1) as f64;
+    (value.clamp(-1.0, 1.0) * center + center).round().min(max) as u32
+}
+
+/// The events replayed to a new instance, up to a fixed count.
+struct RecoveredEvents<'a> {
+    buffer: &'a mut EventBuffer,
+    remaining: usize,
+}
+
+impl RecoveredEvents<'_> {
+    #[inline]
+    fn push<E: AsRef<UnknownEvent>>(&mut self, event: &E) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.buffer.push(event.as_ref());
+        }
+    }
+}
+
+#[inline]
+fn midi2_header(group: u32, status: u32, channel: u32, index: u32) -> u32 {
+    (0x4 << 28) | (group << 24) | (status << 20) | (channel << 16) | index
+}
+
+#[inline]
+fn push_midi(events: &mut RecoveredEvents, port_index: u16, data: [u8; 3]) {
+    events.push(&MidiEvent::new(0, port_index, data))
+}
+
+#[inline]
+fn push_midi2(events: &mut RecoveredEvents, port_index: u16, [header, data]: [u32; 2]) {
+    events.push(&Midi2Event::new(0, port_index, [header, data, 0, 0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_midi(tracker: &mut NoteTracker, messages: &[[u8; 3]]) {
+        let mut buffer = EventBuffer::new();
+        for data in messages {
+            buffer.push(&MidiEvent::new(0, 0, *data));
+        }
+
+        tracker.handle_note_events(&InputEvents::from_buffer(&buffer));
+    }
+
+    #[test]
+    fn upscale_preserves_min_center_and_max() {
+        assert_eq!(upscale(0, 7), 0);
+        assert_eq!(upscale(64, 7), 0x8000_0000);
+        assert_eq!(upscale(127, 7), 0xFFFF_FFFF);
+
+        assert_eq!(upscale(0, 14), 0);
+        assert_eq!(upscale(0x2000, 14), 0x8000_0000);
+        assert_eq!(upscale(0x3FFF, 14), 0xFFFF_FFFF);
+
+        // Values below the center are only shifted.
+        assert_eq!(upscale(1, 7), 1 << 25);
+    }
+
+    #[test]
+    fn bipolar_values_are_centered_and_clamped() {
+        assert_eq!(from_bipolar(0.0, 14), 0x2000);
+        assert_eq!(from_bipolar(-1.0, 14), 0);
+        assert_eq!(from_bipolar(1.0, 14), 0x3FFF);
+        assert_eq!(from_bipolar(2.0, 14), 0x3FFF);
+        assert_eq!(from_bipolar(0.0, 32), 0x8000_0000);
+        assert_eq!(from_bipolar(1.0, 32), u32::MAX);
+
+        assert_eq!(to_bipolar(0x2000, 14), 0.0);
+        assert_eq!(to_bipolar(0, 14), -1.0);
+    }
+
+    #[test]
+    fn unipolar_values_round_trip() {
+        for value in 0..128 {
+            assert_eq!(from_unipolar(to_unipolar(value, 7), 7), value);
+        }
+
+        assert_eq!(from_unipolar(-1.0, 7), 0);
+        assert_eq!(from_unipolar(2.0, 16), 0xFFFF);
+    }
+
+    #[test]
+    fn released_notes_are_held_by_the_sustain_pedal() {
+        let mut tracker = NoteTracker::new();
+
+        handle_midi(
+            &mut tracker,
+            &[[0xB0, 64, 127], [0x90, 60, 100], [0x80, 60, 0]],
+        );
+        assert_eq!(tracker.active_notes.len(), 1);
+        assert!(tracker.active_notes[0].is_released);
+
+        // The pedal of another channel doesn't matter.
+        handle_midi(&mut tracker, &[[0x91, 62, 100], [0x81, 62, 0]]);
+        assert_eq!(tracker.active_notes.len(), 1);
+
+        handle_midi(&mut tracker, &[[0xB0, 64, 0]]);
+        assert!(tracker.active_notes.is_empty());
+    }
+
+    #[test]
+    fn notes_held_when_the_pedal_goes_up_keep_sounding() {
+        let mut tracker = NoteTracker::new();
+
+        handle_midi(
+            &mut tracker,
+            &[[0xB0, 64, 127], [0x90, 60, 100], [0x90, 64, 100]],
+        );
+        handle_midi(&mut tracker, &[[0x80, 60, 0], [0xB0, 64, 0]]);
+
+        assert_eq!(tracker.active_notes.len(), 1);
+        assert_eq!(tracker.active_notes[0].key, 64);
+        assert!(!tracker.active_notes[0].is_released);
+    }
+
+    #[test]
+    fn midi2_notes_of_other_groups_are_not_replayed_as_clap() {
+        let mut tracker = NoteTracker::new();
+
+        let mut buffer = EventBuffer::new();
+        for group in [0, 1] {
+            let header = midi2_header(group, 0x9, 2, 60 << 8);
+            buffer.push(&Midi2Event::new(0, 0, [header, 0xFFFF << 16, 0, 0]));
+        }
+        tracker.handle_note_events(&InputEvents::from_buffer(&buffer));
+        assert_eq!(tracker.active_notes.len(), 2);
+
+        let dialects = [InputNoteDialects {
+            preferred: NoteDialect::Clap,
+            supported: NoteDialects::CLAP | NoteDialects::MIDI2,
+        }];
+
+        let mut buffer = EventBuffer::new();
+        tracker.recover_all_current_notes(&mut buffer, &dialects);
+
+        let events = InputEvents::from_buffer(&buffer);
+        assert_eq!((&events).into_iter().count(), 1);
+
+        let event = (&events).into_iter().next().unwrap();
+        let Some(CoreEventSpace::NoteOn(note_on)) = event.as_core_event() else {
+            panic!("The held note wasn't replayed as a CLAP note");
+        };
+        assert_eq!(note_on.channel(), Match::Specific(2));
+        assert_eq!(note_on.key(), Match::Specific(60));
+    }
+
+    #[test]
+    fn recovered_events_are_capped_but_keep_notes() {
+        let mut tracker = NoteTracker::new();
+
+        for channel in 0..16 {
+            let messages: Vec<_> = (0..120).map(|cc| [0xB0 | channel, cc, 1]).collect();
+            handle_midi(&mut tracker, &messages);
+        }
+        handle_midi(&mut tracker, &[[0x90, 60, 100]]);
+
+        let dialects = [InputNoteDialects {
+            preferred: NoteDialect::Midi,
+            supported: NoteDialects::MIDI,
+        }];
+
+        let mut buffer = EventBuffer::new();
+        tracker.recover_all_current_notes(&mut buffer, &dialects);
+
+        let events = InputEvents::from_buffer(&buffer);
+        assert_eq!(
+            (&events).into_iter().count(),
+            MAX_RECOVERED_EVENTS - MAX_RECOVERED_NOTE_EVENTS + 1
+        );
+
+        let last = (&events).into_iter().last().unwrap();
+        let Some(CoreEventSpace::Midi(note_on)) = last.as_core_event() else {
+            panic!("The held note wasn't replayed last");
+        };
+        assert_eq!(note_on.data(), [0x90, 60, 100]);
     }
 }
@@ -1,12 +1,37 @@
-use crate::wrapper::audio_processor::cross_fader::CrossFader;
+use crate::wrapper::audio_processor::cross_fader::{CrossFader, Sample};
 use crate::wrapper::extensions::PluginAudioPortsInfo;
 use clack_host::prelude::{AudioPortBuffer, AudioPortBufferType, AudioPorts, OutputAudioBuffers};
 use clack_plugin::prelude::{Audio, PluginAudioConfiguration, PluginError};
+use clack_plugin::process::audio::SampleType;
+
+/// The buffers of a single output port, for one of the two instances being cross-faded.
+#[derive(Clone)]
+struct PortBuffers {
+    f32: Vec<Vec<f32>>, // 1 per channel
+    f64: Vec<Vec<f64>>, // 1 per channel, empty if the port doesn't support 64-bit samples
+    uses_64bits: bool,
+}
+
+impl PortBuffers {
+    fn new(channel_count: u32, supports_64bits: bool, buffer_frame_count: u32) -> Self {
+        let f64_channel_count = if supports_64bits { channel_count } else { 0 };
+
+        Self {
+            f32: vec![vec![0.0; buffer_frame_count as usize]; channel_count as usize],
+            f64: vec![vec![0.0; buffer_frame_count as usize]; f64_channel_count as usize],
+            uses_64bits: false,
+        }
+    }
+
+    /// Uses 64-bit buffers if the host does, and if the port supports them.
+    fn set_host_precision(&mut self, host_uses_64bits: bool) {
+        self.uses_64bits = host_uses_64bits && !self.f64.is_empty();
+    }
+}
 
-// TODO: handle 64bit buffers
 pub struct OutputBuffers {
-    main_buffers: Vec<Vec<Vec<f32>>>, // 1 per channel per port
-    fading_out_buffers: Vec<Vec<Vec<f32>>>,
+    main_buffers: Vec<PortBuffers>, // 1 per port
+    fading_out_buffers: Vec<PortBuffers>,
     audio_port_buffers: AudioPorts,
     buffer_frame_count: u32,
 }
@@ -17,15 +42,19 @@ impl OutputBuffers {
         audio_configuration: PluginAudioConfiguration,
     ) -> Self {
         let buffer_frame_count = audio_configuration.max_frames_count;
-        let channel_count_per_port = info.output_channels_count_per_port();
+        let ports = info.output_ports();
 
-        let port_count = channel_count_per_port.len();
-        let total_channel_count: u32 = channel_count_per_port.iter().sum();
+        let port_count = ports.len();
+        let total_channel_count: u32 = ports.iter().map(|p| p.channel_count).sum();
 
-        let buffers: Vec<_> = channel_count_per_port
+        let buffers: Vec<_> = ports
             .iter()
-            .map(|channel_count| {
-                vec![vec![0.0; buffer_frame_count as usize]; *channel_count as usize]
+            .map(|port| {
+                PortBuffers::new(
+                    port.channel_count,
+                    port.supports_64bits(),
+                    buffer_frame_count,
+                )
             })
             .collect();
 
@@ -37,6 +66,26 @@ impl OutputBuffers {
         }
     }
 
+    /// Picks the precision of each port's intermediate buffers from the type of the buffers
+    /// the host handed over.
+    ///
+    /// If the host provides 64-bit buffers to a port that didn't declare support for them,
+    /// 32-bit buffers are used, and converted when cross-fading.
+    pub fn update_precisions(&mut self, audio: &mut Audio) -> Result<(), PluginError> {
+        for (mut output_port, (main_port, fade_out_port)) in audio.output_ports().zip(
+            self.main_buffers
+                .iter_mut()
+                .zip(&mut self.fading_out_buffers),
+        ) {
+            let host_uses_64bits = matches!(output_port.channels()?, SampleType::F64(_));
+
+            main_port.set_host_precision(host_uses_64bits);
+            fade_out_port.set_host_precision(host_uses_64bits);
+        }
+
+        Ok(())
+    }
+
     pub fn output_buffers_for(
         &mut self,
         get_main: bool,
@@ -53,13 +102,25 @@ impl OutputBuffers {
             .with_output_buffers(bufs.iter_mut().zip(plugin_audio.output_ports_infos()).map(
                 |(buf, info)| AudioPortBuffer {
                     latency: info.latency(),
-                    channels: AudioPortBufferType::f32_output_only(
-                        buf.iter_mut().map(|buf| buf.as_mut_slice()),
-                    ),
+                    channels: if buf.uses_64bits {
+                        AudioPortBufferType::F64(buf.f64.iter_mut().map(|buf| buf.as_mut_slice()))
+                    } else {
+                        AudioPortBufferType::F32(buf.f32.iter_mut().map(|buf| buf.as_mut_slice()))
+                    },
                 },
             ))
     }
 
+    /// Replaces the output of the instance being faded in with silence.
+    pub fn silence_main(&mut self) {
+        silence_ports(&mut self.main_buffers)
+    }
+
+    /// Replaces the output of the instance being faded out with silence.
+    pub fn silence_fading_out(&mut self) {
+        silence_ports(&mut self.fading_out_buffers)
+    }
+
     pub fn output_crossfade(
         &mut self,
         cross_fader: &mut CrossFader,
@@ -69,13 +130,26 @@ impl OutputBuffers {
             .output_ports()
             .zip(self.main_buffers.iter().zip(&self.fading_out_buffers))
         {
-            let mut output_channels = output_port.channels()?.into_f32().unwrap(); // TODO: handle non-f32, check it matches
+            match output_port.channels()? {
+                SampleType::F32(mut output_channels) => {
+                    for (i, output_channel) in output_channels.iter_mut().enumerate() {
+                        crossfade_channel(cross_fader, main_port, fade_out_port, i, output_channel)
+                    }
+                }
+                SampleType::F64(mut output_channels) => {
+                    for (i, output_channel) in output_channels.iter_mut().enumerate() {
+                        crossfade_channel(cross_fader, main_port, fade_out_port, i, output_channel)
+                    }
+                }
+                SampleType::Both(mut output_channels_f32, mut output_channels_f64) => {
+                    for (i, output_channel) in output_channels_f32.iter_mut().enumerate() {
+                        crossfade_channel(cross_fader, main_port, fade_out_port, i, output_channel)
+                    }
 
-            for (output_channel, (main_channel, fade_out_channel)) in output_channels
-                .iter_mut()
-                .zip(main_port.iter().zip(fade_out_port))
-            {
-                cross_fader.apply_crossfade(main_channel, fade_out_channel, output_channel)
+                    for (i, output_channel) in output_channels_f64.iter_mut().enumerate() {
+                        crossfade_channel(cross_fader, main_port, fade_out_port, i, output_channel)
+                    }
+                }
             }
         }
 
@@ -85,3 +159,150 @@ impl OutputBuffers {
         Ok(())
     }
 }
+
+/// Fills all output channels with silence.
+pub fn output_silence(audio: &mut Audio) -> Result<(), PluginError> {
+    for mut output_port in audio.output_ports() {
+        match output_port.channels()? {
+            SampleType::F32(mut output_channels) => {
+                for output_channel in output_channels.iter_mut() {
+                    output_channel.fill(0.0)
+                }
+            }
+            SampleType::F64(mut output_channels) => {
+                for output_channel in output_channels.iter_mut() {
+                    output_channel.fill(0.0)
+                }
+            }
+            SampleType::Both(mut output_channels_f32, mut output_channels_f64) => {
+                for output_channel in output_channels_f32.iter_mut() {
+                    output_channel.fill(0.0)
+                }
+
+                for output_channel in output_channels_f64.iter_mut() {
+                    output_channel.fill(0.0)
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn silence_ports(ports: &mut [PortBuffers]) {
+    for port in ports {
+        port.f32.iter_mut().for_each(|channel| channel.fill(0.0));
+        port.f64.iter_mut().for_each(|channel| channel.fill(0.0));
+    }
+}
+
+fn crossfade_channel<S: Sample>(
+    cross_fader: &CrossFader,
+    main_port: &PortBuffers,
+    fade_out_port: &PortBuffers,
+    channel_index: usize,
+    output: &mut [S],
+) {
+    if main_port.uses_64bits {
+        let (Some(main), Some(fade_out)) = (
+            main_port.f64.get(channel_index),
+            fade_out_port.f64.get(channel_index),
+        ) else {
+            return;
+        };
+
+        crossfade_slices(cross_fader, main, fade_out, output)
+    } else {
+        let (Some(main), Some(fade_out)) = (
+            main_port.f32.get(channel_index),
+            fade_out_port.f32.get(channel_index),
+        ) else {
+            return;
+        };
+
+        crossfade_slices(cross_fader, main, fade_out, output)
+    }
+}
+
+#[inline]
+fn crossfade_slices<I: Sample, O: Sample>(
+    cross_fader: &CrossFader,
+    main: &[I],
+    fade_out: &[I],
+    output: &mut [O],
+) {
+    // The intermediate buffers are sized for the maximum frame count, the output may be shorter.
+    let len = output.len().min(main.len()).min(fade_out.len());
+    cross_fader.apply_crossfade(&main[..len], &fade_out[..len], &mut output[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CrossfadeConfig, CrossfadeCurve};
+
+    fn port_buffers(supports_64bits: bool) -> PortBuffers {
+        let mut buffers = PortBuffers::new(2, supports_64bits, 4);
+        buffers.f32.iter_mut().for_each(|c| c.fill(0.25));
+        buffers.f64.iter_mut().for_each(|c| c.fill(0.5));
+        buffers
+    }
+
+    fn instant_cross_fader() -> CrossFader {
+        CrossFader::new(
+            44_100.0,
+            CrossfadeConfig::new().with_curve(CrossfadeCurve::Instant),
+        )
+    }
+
+    #[test]
+    fn port_follows_host_precision() {
+        let mut buffers = port_buffers(true);
+        assert!(!buffers.uses_64bits);
+
+        buffers.set_host_precision(true);
+        assert!(buffers.uses_64bits);
+
+        buffers.set_host_precision(false);
+        assert!(!buffers.uses_64bits);
+    }
+
+    #[test]
+    fn port_without_64bit_support_stays_32bit() {
+        let mut buffers = port_buffers(false);
+        assert!(buffers.f64.is_empty());
+
+        buffers.set_host_precision(true);
+        assert!(!buffers.uses_64bits);
+    }
+
+    #[test]
+    fn crossfade_reads_buffers_of_the_current_precision() {
+        let cross_fader = instant_cross_fader();
+        let mut buffers = port_buffers(true);
+        let mut output_f32 = [0.0f32; 4];
+        let mut output_f64 = [0.0f64; 4];
+
+        crossfade_channel(&cross_fader, &buffers, &buffers, 1, &mut output_f32);
+        assert_eq!(output_f32, [0.25; 4]);
+
+        buffers.set_host_precision(true);
+
+        crossfade_channel(&cross_fader, &buffers, &buffers, 1, &mut output_f64);
+        assert_eq!(output_f64, [0.5; 4]);
+
+        // A host may still hand over 32-bit buffers to some ports.
+        crossfade_channel(&cross_fader, &buffers, &buffers, 1, &mut output_f32);
+        assert_eq!(output_f32, [0.5; 4]);
+    }
+
+    #[test]
+    fn crossfade_handles_shorter_outputs() {
+        let cross_fader = instant_cross_fader();
+        let buffers = port_buffers(false);
+        let mut output = [0.0f32; 2];
+
+        crossfade_channel(&cross_fader, &buffers, &buffers, 0, &mut output);
+        assert_eq!(output, [0.25; 2]);
+    }
+}
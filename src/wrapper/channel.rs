@@ -1,70 +1,152 @@
 use crate::wrapper::WrapperHost;
 use clack_host::prelude::*;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::sync::Arc;
 
-// TODO: those channels are not realtime-safe
+mod slot;
+use slot::*;
+pub(crate) use slot::{BoundedQueue, Slot};
+
+/// How many stopped audio processors can await disposal before the audio thread has to hold on
+/// to them itself.
+const DISPOSAL_QUEUE_CAPACITY: usize = 8;
+
+/// How many times the main thread tries to hand a new processor over while the audio thread is
+/// accessing the slot, before leaving it for the next main thread callback.
+const DELIVERY_ATTEMPTS: usize = 64;
+
+struct ChannelShared {
+    new_processor: Slot<StoppedPluginAudioProcessor<WrapperHost>>,
+    disposal_queue: BoundedQueue<StoppedPluginAudioProcessor<WrapperHost>>,
+}
+
+/// The audio thread's side of the channel. None of its operations lock or allocate.
 pub struct AudioProcessorChannel {
-    sender: Sender<StoppedPluginAudioProcessor<WrapperHost>>,
-    receiver: Receiver<StoppedPluginAudioProcessor<WrapperHost>>,
+    shared: Arc<ChannelShared>,
+    pending_disposal: Option<StoppedPluginAudioProcessor<WrapperHost>>,
 }
 
 impl AudioProcessorChannel {
     pub fn move_to_latest_new_processor(
         &mut self,
     ) -> Option<StoppedPluginAudioProcessor<WrapperHost>> {
-        let mut latest = None;
+        // Don't swap to a new processor if we can't get rid of the previous one yet: this ensures
+        // there is never more than one processor waiting for disposal on this side.
+        if !self.flush_pending_disposal() {
+            return None;
+        }
 
-        for processor in self.receiver.try_iter() {
-            if let Some(previous) = latest.take() {
-                let _ = self.sender.send(previous);
-            }
+        self.shared.new_processor.try_take()
+    }
+
+    pub fn send_for_disposal(&mut self, processor: StoppedPluginAudioProcessor<WrapperHost>) {
+        self.flush_pending_disposal();
 
-            latest = Some(processor)
+        if let Err(processor) = self.shared.disposal_queue.try_push(processor) {
+            // The queue is full. Keep the processor around until the main thread catches up.
+            // This can't overwrite another pending processor, see move_to_latest_new_processor.
+            self.pending_disposal = Some(processor);
         }
+    }
 
-        latest
+    /// Takes back a new processor that was sent but never picked up by the audio thread.
+    pub fn take_undelivered_processor(
+        &mut self,
+    ) -> Option<StoppedPluginAudioProcessor<WrapperHost>> {
+        self.shared.new_processor.try_take()
     }
 
-    pub fn send_for_disposal(&mut self, processor: StoppedPluginAudioProcessor<WrapperHost>) {
-        // If the channel is somehow disconnected, we just drop the processor, which will leak it if
-        // the corresponding instance is gone already.
+    fn flush_pending_disposal(&mut self) -> bool {
+        let Some(processor) = self.pending_disposal.take() else {
+            return true;
+        };
 
-        let _ = self.sender.send(processor);
+        match self.shared.disposal_queue.try_push(processor) {
+            Ok(()) => true,
+            Err(processor) => {
+                self.pending_disposal = Some(processor);
+                false
+            }
+        }
     }
 }
 
 pub struct MainThreadChannel {
-    sender: Sender<StoppedPluginAudioProcessor<WrapperHost>>,
-    receiver: Receiver<StoppedPluginAudioProcessor<WrapperHost>>,
+    shared: Arc<ChannelShared>,
     instances_awaiting_destruction: Vec<PluginInstance<WrapperHost>>,
+    undelivered_processor: Option<StoppedPluginAudioProcessor<WrapperHost>>,
 }
 
 impl MainThreadChannel {
     pub fn new_pair() -> (MainThreadChannel, AudioProcessorChannel) {
-        let (sender_main_thread, receiver_audio_processor) = unbounded();
-        let (sender_audio_processor, receiver_main_thread) = unbounded();
+        let shared = Arc::new(ChannelShared {
+            new_processor: Slot::new(),
+            disposal_queue: BoundedQueue::with_capacity(DISPOSAL_QUEUE_CAPACITY),
+        });
 
         (
             MainThreadChannel {
-                sender: sender_main_thread,
-                receiver: receiver_main_thread,
+                shared: shared.clone(),
                 instances_awaiting_destruction: Vec::new(),
+                undelivered_processor: None,
             },
             AudioProcessorChannel {
-                sender: sender_audio_processor,
-                receiver: receiver_audio_processor,
+                shared,
+                pending_disposal: None,
             },
         )
     }
 
+    /// Hands a new processor over to the audio thread.
+    ///
+    /// If the audio thread is busy with the previous one, delivery is retried by
+    /// [`destroy_awaiting`](Self::destroy_awaiting), see
+    /// [`has_undelivered_processor`](Self::has_undelivered_processor).
     pub fn send_new_audio_processor(
         &mut self,
         processor: StoppedPluginAudioProcessor<WrapperHost>,
         previous_instance: PluginInstance<WrapperHost>,
-    ) -> Result<(), StoppedPluginAudioProcessor<WrapperHost>> {
+    ) {
         self.instances_awaiting_destruction.push(previous_instance);
 
-        self.sender.send(processor).map_err(|e| e.0)
+        // A processor that never got delivered is superseded by this one.
+        if let Some(superseded) = self.undelivered_processor.take() {
+            self.deactivate_old_instance(superseded);
+        }
+
+        self.deliver(processor);
+    }
+
+    fn deliver(&mut self, mut processor: StoppedPluginAudioProcessor<WrapperHost>) {
+        for _ in 0..DELIVERY_ATTEMPTS {
+            match self.shared.new_processor.replace(processor) {
+                // The audio thread never picked up the previous processor. Take it back.
+                Ok(Some(replaced)) => {
+                    self.deactivate_old_instance(replaced);
+                    return;
+                }
+                Ok(None) => return,
+                // The audio thread is taking the previous processor out of the slot right now.
+                // This only takes a few instructions, so we can just try again.
+                Err(p) => {
+                    processor = p;
+                    std::hint::spin_loop();
+                }
+            }
+        }
+
+        // Don't keep the main thread spinning if the audio thread got preempted mid-access.
+        self.undelivered_processor = Some(processor);
+    }
+
+    pub fn has_undelivered_processor(&self) -> bool {
+        self.undelivered_processor.is_some()
+    }
+
+    /// Takes back the new processor that couldn't be handed over to the audio thread yet.
+    pub fn take_undelivered_processor(
+        &mut self,
+    ) -> Option<StoppedPluginAudioProcessor<WrapperHost>> {
+        self.undelivered_processor.take()
     }
 
     pub fn defer_destroy_if_active(&mut self, instance: PluginInstance<WrapperHost>) {
@@ -89,36 +171,72 @@ impl MainThreadChannel {
         instance.deactivate(processor);
     }
 
+    /// Deactivates the instances the audio thread is done with, and retries delivering the new
+    /// processor if the previous attempt failed.
     pub fn destroy_awaiting(&mut self) {
-        for processor in self.receiver.try_iter() {
-            let Some(matching_index) = self
-                .instances_awaiting_destruction
-                .iter()
-                .position(|i| processor.matches(i))
-            else {
-                drop(processor);
-                return;
-            };
-
-            let mut instance = self.instances_awaiting_destruction.remove(matching_index);
-            instance.deactivate(processor);
+        let shared = self.shared.clone();
+
+        if let Some(processor) = self.undelivered_processor.take() {
+            self.deliver(processor);
+        }
+
+        for processor in shared.disposal_queue.drain() {
+            self.deactivate_old_instance(processor);
         }
     }
 
     pub fn consume(mut self, audio_processor_channel: AudioProcessorChannel) {
-        for audio_processor in audio_processor_channel.receiver.try_iter() {
-            let Some(matching_index) = self
-                .instances_awaiting_destruction
-                .iter()
-                .position(|i| audio_processor.matches(i))
-            else {
-                return;
-            };
-
-            let mut instance = self.instances_awaiting_destruction.remove(matching_index);
-            instance.deactivate(audio_processor);
+        // The audio thread is gone, we now have both ends of the channel.
+        if let Some(audio_processor) = audio_processor_channel.pending_disposal {
+            self.deactivate_old_instance(audio_processor);
         }
 
         self.destroy_awaiting();
     }
 }
+
+#[cfg(all(test, feature = "realtime-alloc-check"))]
+mod tests {
+    use super::*;
+    use crate::alloc_check::{realtime_allocation_count, RealtimeScope};
+    use crate::wrapper::test_plugin::{new_test_instance, process_empty_block, TEST_AUDIO_CONFIG};
+    use clack_host::process::PluginAudioProcessor;
+
+    #[test]
+    fn swapping_processors_does_not_allocate() {
+        let mut old_instance = new_test_instance(false);
+        let mut new_instance = new_test_instance(false);
+        let (mut main_thread, mut audio_thread) = MainThreadChannel::new_pair();
+
+        let mut current: PluginAudioProcessor<WrapperHost> =
+            WrapperHost::activate_instance(&mut old_instance, TEST_AUDIO_CONFIG)
+                .unwrap()
+                .into();
+
+        let new_processor =
+            WrapperHost::activate_instance(&mut new_instance, TEST_AUDIO_CONFIG).unwrap();
+        main_thread.send_new_audio_processor(new_processor, old_instance);
+        assert!(!main_thread.has_undelivered_processor());
+
+        // Other tests may run concurrently, but none of them allocate in a realtime scope.
+        let allocations_before = realtime_allocation_count();
+        {
+            let _scope = RealtimeScope::enter();
+
+            process_empty_block(&mut current);
+
+            let new_processor = audio_thread.move_to_latest_new_processor().unwrap();
+            let old_processor = core::mem::replace(&mut current, new_processor.into());
+            audio_thread.send_for_disposal(old_processor.into_stopped());
+
+            process_empty_block(&mut current);
+        }
+        assert_eq!(realtime_allocation_count(), allocations_before);
+
+        // The old instance gets deactivated on the main thread.
+        main_thread.destroy_awaiting();
+        assert!(main_thread.instances_awaiting_destruction.is_empty());
+
+        new_instance.deactivate(current.into_stopped());
+    }
+}
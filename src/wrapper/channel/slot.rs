@@ -0,0 +1,139 @@
+#![allow(unsafe_code)] // Needed for the lock-free slot storage
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const EMPTY: u8 = 0;
+const BUSY: u8 = 1;
+const FULL: u8 = 2;
+
+/// A single, preallocated storage cell that can be handed between two threads without locking
+/// or allocating.
+///
+/// Every operation is a single compare-and-swap attempt followed by a bounded amount of work, so
+/// all of them are wait-free. If the other side is currently accessing the slot, the operation
+/// fails immediately instead of waiting for it.
+pub struct Slot<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: access to the value is always guarded by the state, which makes sure only one thread
+// can read or write the value at a time.
+unsafe impl<T: Send> Send for Slot<T> {}
+unsafe impl<T: Send> Sync for Slot<T> {}
+
+impl<T> Slot<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Puts the given value in the slot, only if it is empty.
+    ///
+    /// The value is handed back if the slot is either full or being accessed by the other side.
+    pub fn try_put(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(EMPTY, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        // SAFETY: the BUSY state gives us exclusive access to the value.
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(FULL, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Takes the value out of the slot, if there is one and the other side isn't accessing it.
+    pub fn try_take(&self) -> Option<T> {
+        if self
+            .state
+            .compare_exchange(FULL, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+
+        // SAFETY: the BUSY state gives us exclusive access to the value, and the FULL state
+        // guarantees it was initialized.
+        let value = unsafe { (*self.value.get()).assume_init_read() };
+        self.state.store(EMPTY, Ordering::Release);
+
+        Some(value)
+    }
+
+    /// Puts the given value in the slot, handing back any value that was there before.
+    ///
+    /// The given value is handed back as an error if the other side is currently accessing
+    /// the slot.
+    pub fn replace(&self, value: T) -> Result<Option<T>, T> {
+        match self.try_put(value) {
+            Ok(()) => Ok(None),
+            Err(value) => {
+                if self
+                    .state
+                    .compare_exchange(FULL, BUSY, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    return Err(value);
+                }
+
+                // SAFETY: the BUSY state gives us exclusive access to the value, and the FULL
+                // state guarantees it was initialized.
+                let previous = unsafe { (*self.value.get()).assume_init_read() };
+                unsafe { (*self.value.get()).write(value) };
+                self.state.store(FULL, Ordering::Release);
+
+                Ok(Some(previous))
+            }
+        }
+    }
+}
+
+impl<T> Drop for Slot<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == FULL {
+            // SAFETY: the FULL state guarantees the value was initialized, and we have exclusive
+            // access through &mut self.
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+/// A fixed-capacity queue made of preallocated [`Slot`]s.
+///
+/// Pushing and popping only ever touches the existing slots, and never allocates.
+pub struct BoundedQueue<T> {
+    slots: Box<[Slot<T>]>,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| Slot::new()).collect(),
+        }
+    }
+
+    /// Pushes the value in the first available slot, or hands it back if the queue is full.
+    pub fn try_push(&self, mut value: T) -> Result<(), T> {
+        for slot in self.slots.iter() {
+            match slot.try_put(value) {
+                Ok(()) => return Ok(()),
+                Err(v) => value = v,
+            }
+        }
+
+        Err(value)
+    }
+
+    pub fn drain(&self) -> impl Iterator<Item = T> + '_ {
+        self.slots.iter().filter_map(|slot| slot.try_take())
+    }
+}
@@ -1,46 +1,66 @@
+use crate::dsp_handoff::PluginDspHandoff;
+use crate::hot_state::PluginHotState;
+use crate::reload_events::PluginReloadEvents;
 use crate::wrapper::WrapperPlugin;
-use clack_extensions::audio_ports::PluginAudioPorts;
+use clack_extensions::audio_ports::{HostAudioPorts, PluginAudioPorts, RescanType};
 use clack_extensions::gui::{HostGui, PluginGui};
 use clack_extensions::latency::{HostLatency, PluginLatency};
-use clack_extensions::note_ports::PluginNotePorts;
+use clack_extensions::note_ports::{HostNotePorts, NoteDialects, PluginNotePorts};
 use clack_extensions::params::{HostParams, PluginParams};
 use clack_extensions::state::PluginState;
+use clack_extensions::timer::{HostTimer, PluginTimer};
 use clack_host::prelude::*;
 use clack_plugin::prelude::*;
 
 mod audio_ports;
+mod dsp_handoff;
 mod gui;
+mod hot_state;
 mod latency;
+mod log;
 mod note_ports;
 mod params;
+mod reload_events;
 mod state;
 mod timer;
 
 pub use audio_ports::*;
+pub use dsp_handoff::*;
 pub use gui::*;
+pub use hot_state::*;
 pub use latency::*;
+pub use note_ports::*;
 pub use params::*;
+pub use reload_events::*;
 pub use state::*;
 pub use timer::*;
 
 pub struct WrappedPluginExtensions {
     audio_ports: Option<PluginAudioPorts>,
+    dsp_handoff: Option<PluginDspHandoff>,
     gui: Option<PluginGui>,
+    hot_state: Option<PluginHotState>,
     latency: Option<PluginLatency>,
     note_ports: Option<PluginNotePorts>,
     params: Option<PluginParams>,
+    reload_events: Option<PluginReloadEvents>,
     state: Option<PluginState>,
+    timer: Option<PluginTimer>,
 }
 
 impl WrappedPluginExtensions {
     pub fn new(handle: InitializingPluginHandle) -> Self {
         Self {
             audio_ports: handle.get_extension(),
+            dsp_handoff: handle.get_extension(),
             gui: handle.get_extension(),
+            hot_state: handle.get_extension(),
             latency: handle.get_extension(),
             note_ports: handle.get_extension(),
             params: handle.get_extension(),
+            reload_events: handle.get_extension(),
             state: handle.get_extension(),
+            timer: handle.get_extension(),
         }
     }
 
@@ -87,17 +107,47 @@ impl ReportedExtensions {
 }
 
 pub struct OuterHostExtensions {
+    pub audio_ports: Option<HostAudioPorts>,
     pub latency: Option<HostLatency>,
+    pub note_ports: Option<HostNotePorts>,
     pub params: Option<HostParams>,
     pub gui: Option<HostGui>,
+    pub timer: Option<HostTimer>,
 }
 
 impl OuterHostExtensions {
     pub fn new(host: &HostSharedHandle) -> Self {
         Self {
+            audio_ports: host.get_extension(),
             latency: host.get_extension(),
+            note_ports: host.get_extension(),
             params: host.get_extension(),
             gui: host.get_extension(),
+            timer: host.get_extension(),
+        }
+    }
+}
+
+/// What the outer host supports, as reported to the wrapped plugin instances when they ask.
+#[derive(Copy, Clone)]
+pub struct OuterHostCapabilities {
+    pub audio_ports_rescan: RescanType,
+    pub note_dialects: NoteDialects,
+    pub timer: bool,
+}
+
+impl OuterHostCapabilities {
+    pub fn new(host: &mut HostMainThreadHandle) -> Self {
+        let audio_ports: Option<HostAudioPorts> = host.get_extension();
+        let note_ports: Option<HostNotePorts> = host.get_extension();
+        let timer: Option<HostTimer> = host.get_extension();
+
+        Self {
+            audio_ports_rescan: get_host_supported_rescan_types(host, audio_ports),
+            note_dialects: note_ports
+                .map(|n| n.supported_dialects(host))
+                .unwrap_or(NoteDialects::empty()),
+            timer: timer.is_some(),
         }
     }
 }
@@ -1,90 +1,292 @@
+use crate::log::log;
 use crate::wrapper::*;
 use clack_extensions::audio_ports::*;
+use clack_plugin::utils::ClapId;
+use std::ffi::CString;
 
-// TODO: handle rescan
-pub struct PluginAudioPortsInfo {
-    output_channels_count_per_port: Vec<u32>,
-
-    host_supported_rescan_flags: RescanType,
+#[derive(Clone, Eq, PartialEq)]
+pub struct CachedAudioPortInfo {
+    id: ClapId,
+    name: String,
+    pub channel_count: u32,
+    flags: AudioPortFlags,
+    port_type: Option<CString>,
+    in_place_pair: Option<ClapId>,
 }
 
-impl PluginAudioPortsInfo {
-    pub fn new(
-        plugin: &mut PluginInstance<WrapperHost>,
-        host: &mut HostMainThreadHandle,
-        ext: Option<HostAudioPorts>,
-    ) -> Self {
-        let mut info = Self {
-            output_channels_count_per_port: Vec::new(),
-            host_supported_rescan_flags: get_host_supported_rescan_types(host, ext),
-        };
-        info.update(plugin);
-        info
+impl CachedAudioPortInfo {
+    fn from_info(info: &AudioPortInfo) -> Self {
+        Self {
+            id: info.id,
+            name: String::from_utf8_lossy(info.name).into_owned(),
+            channel_count: info.channel_count,
+            flags: info.flags,
+            port_type: info.port_type.map(|t| t.0.to_owned()),
+            in_place_pair: info.in_place_pair,
+        }
+    }
+
+    #[inline]
+    fn as_info(&self) -> AudioPortInfo {
+        AudioPortInfo {
+            id: self.id,
+            name: self.name.as_bytes(),
+            channel_count: self.channel_count,
+            flags: self.flags,
+            port_type: self.port_type.as_deref().map(AudioPortType),
+            in_place_pair: self.in_place_pair,
+        }
+    }
+
+    pub fn supports_64bits(&self) -> bool {
+        self.flags.contains(AudioPortFlags::SUPPORTS_64BITS)
     }
 
-    pub fn output_channels_count_per_port(&self) -> &[u32] {
-        &self.output_channels_count_per_port
+    fn diff_for_rescan(&self, other: &Self) -> RescanType {
+        let mut flags = RescanType::empty();
+
+        if self.name != other.name {
+            flags |= RescanType::NAMES;
+        }
+
+        if self.flags != other.flags {
+            flags |= RescanType::FLAGS;
+        }
+
+        if self.channel_count != other.channel_count {
+            flags |= RescanType::CHANNEL_COUNT;
+        }
+
+        if self.port_type != other.port_type {
+            flags |= RescanType::PORT_TYPE;
+        }
+
+        if self.in_place_pair != other.in_place_pair {
+            flags |= RescanType::IN_PLACE_PAIR;
+        }
+
+        flags
     }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub(crate) struct AudioPortLayout {
+    inputs: Vec<CachedAudioPortInfo>,
+    outputs: Vec<CachedAudioPortInfo>,
+}
 
-    pub fn update(&mut self, plugin: &mut PluginInstance<WrapperHost>) {
+impl AudioPortLayout {
+    pub(crate) fn read_from(plugin: &mut PluginInstance<WrapperHost>) -> Self {
         let Some(audio_ports) = plugin.access_shared_handler(|h| h.wrapped_plugin().audio_ports)
         else {
             // Use default, single port stereo config
-            self.output_channels_count_per_port.push(2);
-            return;
+            return Self {
+                inputs: vec![],
+                outputs: vec![CachedAudioPortInfo {
+                    id: ClapId::new(0),
+                    name: String::from("Output"),
+                    channel_count: 2,
+                    flags: AudioPortFlags::IS_MAIN,
+                    port_type: None,
+                    in_place_pair: None,
+                }],
+            };
         };
 
         let mut plugin = plugin.plugin_handle();
-        self.output_channels_count_per_port.clear();
+        let mut buf = AudioPortInfoBuffer::new();
 
-        let output_port_count = audio_ports.count(&mut plugin, false);
+        let mut read_ports = |is_input: bool| {
+            let port_count = audio_ports.count(&mut plugin, is_input);
+            let mut ports = Vec::with_capacity(port_count as usize);
 
-        let mut buf = AudioPortInfoBuffer::new();
-        for i in 0..output_port_count {
-            let Some(data) = audio_ports.get(&mut plugin, i, false, &mut buf) else {
+            for i in 0..port_count {
+                if let Some(data) = audio_ports.get(&mut plugin, i, is_input, &mut buf) {
+                    ports.push(CachedAudioPortInfo::from_info(&data));
+                }
+            }
+
+            ports
+        };
+
+        Self {
+            inputs: read_ports(true),
+            outputs: read_ports(false),
+        }
+    }
+
+    fn ports(&self, is_input: bool) -> &[CachedAudioPortInfo] {
+        if is_input {
+            &self.inputs
+        } else {
+            &self.outputs
+        }
+    }
+
+    fn diff_for_rescan(&self, new: &Self) -> RescanType {
+        let mut flags = RescanType::empty();
+
+        for is_input in [true, false] {
+            let (old_ports, new_ports) = (self.ports(is_input), new.ports(is_input));
+
+            if old_ports.len() != new_ports.len()
+                || old_ports.iter().zip(new_ports).any(|(o, n)| o.id != n.id)
+            {
+                flags |= RescanType::LIST;
                 continue;
-            };
+            }
 
-            self.output_channels_count_per_port.push(data.channel_count);
+            for (old_port, new_port) in old_ports.iter().zip(new_ports) {
+                flags |= old_port.diff_for_rescan(new_port);
+            }
+        }
+
+        flags
+    }
+}
+
+pub struct PluginAudioPortsInfo {
+    layout: AudioPortLayout,
+    /// A layout from a reloaded instance, waiting for the plugin to be deactivated to be applied.
+    pending_layout: Option<(AudioPortLayout, RescanType)>,
+}
+
+impl PluginAudioPortsInfo {
+    pub fn new(plugin: &mut PluginInstance<WrapperHost>) -> Self {
+        Self {
+            layout: AudioPortLayout::read_from(plugin),
+            pending_layout: None,
+        }
+    }
+
+    pub fn output_ports(&self) -> &[CachedAudioPortInfo] {
+        &self.layout.outputs
+    }
+}
+
+/// Returns true if the host must deactivate the plugin before these changes can be applied.
+fn requires_restart(flags: RescanType) -> bool {
+    !flags.difference(RescanType::NAMES).is_empty()
+}
+
+impl<'a> WrapperPluginMainThread<'a> {
+    /// Returns true if switching to the given layout requires the host to restart the plugin.
+    pub(crate) fn audio_ports_require_restart(&self, new_layout: &AudioPortLayout) -> bool {
+        let changes = self.audio_ports_info.layout.diff_for_rescan(new_layout);
+
+        !changes.is_empty() && requires_restart(changes) && self.audio_processor_channel.is_some()
+    }
+
+    /// Compares the audio port layout of a freshly reloaded instance to the one the host knows
+    /// about.
+    ///
+    /// Changes that can be applied live are sent to the host right away. Otherwise, this returns
+    /// true: the host needs to restart the plugin, and the new layout will only be applied once
+    /// it is deactivated.
+    pub(crate) fn update_audio_ports(&mut self, new_layout: AudioPortLayout) -> bool {
+        let info = &mut self.audio_ports_info;
+        let changes = info.layout.diff_for_rescan(&new_layout);
+
+        if changes.is_empty() {
+            info.pending_layout = None;
+            return false;
+        }
+
+        if requires_restart(changes) && self.audio_processor_channel.is_some() {
+            info.pending_layout = Some((new_layout, changes));
+            self.host.shared().request_restart();
+            return true;
+        }
+
+        info.layout = new_layout;
+        info.pending_layout = None;
+        self.notify_audio_ports_changes(changes);
+
+        false
+    }
+
+    /// Applies the layout of a reloaded instance that was waiting for the plugin to be
+    /// deactivated.
+    pub(crate) fn apply_pending_audio_ports(&mut self) {
+        if let Some((layout, changes)) = self.audio_ports_info.pending_layout.take() {
+            self.audio_ports_info.layout = layout;
+            self.notify_audio_ports_changes(changes);
+        }
+    }
+
+    /// Forwards the rescan requests the wrapped plugin made on its own to the host.
+    pub(crate) fn process_audio_ports_requests(&mut self) {
+        let requested = self.plugin_instance.access_handler_mut(|h| {
+            core::mem::replace(&mut h.audio_ports_rescan, RescanType::empty())
+        });
+
+        if requested.is_empty() {
+            return;
+        }
+
+        self.audio_ports_info.layout = AudioPortLayout::read_from(&mut self.plugin_instance);
+        self.audio_ports_info.pending_layout = None;
+        self.notify_audio_ports_changes(requested);
+    }
+
+    fn notify_audio_ports_changes(&mut self, changes: RescanType) {
+        let Some(host_audio_ports) = self.shared.host_extensions.audio_ports else {
+            return;
+        };
+
+        let supported = self.host_capabilities.audio_ports_rescan;
+
+        if supported.contains(changes) {
+            host_audio_ports.rescan(&mut self.host, changes);
+        } else if supported.contains(RescanType::LIST) {
+            // The list rescan implies all the others.
+            host_audio_ports.rescan(&mut self.host, RescanType::LIST);
+        } else {
+            log!(
+                Warn,
+                Swap,
+                "Host cannot rescan audio ports, ignoring changes."
+            );
         }
     }
 }
 
 impl<'a> HostAudioPortsImpl for WrapperHostMainThread<'a> {
     fn is_rescan_flag_supported(&self, flag: RescanType) -> bool {
-        todo!()
+        self.host_capabilities.audio_ports_rescan.contains(flag)
     }
 
     fn rescan(&mut self, flag: RescanType) {
-        todo!()
+        self.audio_ports_rescan |= flag;
     }
 }
 
 impl<'a> PluginAudioPortsImpl for WrapperPluginMainThread<'a> {
     fn count(&mut self, is_input: bool) -> u32 {
-        let host = self.plugin_instance.access_shared_handler(|h| h);
-        let Some(audio_ports) = host.wrapped_plugin().audio_ports else {
+        if !self.shared.reported_extensions.audio_ports {
             return 0;
-        };
+        }
 
-        audio_ports.count(&mut self.plugin_handle(), is_input)
+        self.audio_ports_info.layout.ports(is_input).len() as u32
     }
 
     fn get(&mut self, index: u32, is_input: bool, writer: &mut AudioPortInfoWriter) {
-        let host = self.plugin_instance.access_shared_handler(|h| h);
-        let Some(audio_ports) = host.wrapped_plugin().audio_ports else {
+        if !self.shared.reported_extensions.audio_ports {
             return;
-        };
-
-        let mut buf = AudioPortInfoBuffer::new();
+        }
 
-        if let Some(data) = audio_ports.get(&mut self.plugin_handle(), index, is_input, &mut buf) {
-            writer.set(&data)
+        if let Some(port) = self
+            .audio_ports_info
+            .layout
+            .ports(is_input)
+            .get(index as usize)
+        {
+            writer.set(&port.as_info())
         }
     }
 }
 
-fn get_host_supported_rescan_types(
+pub fn get_host_supported_rescan_types(
     host: &mut HostMainThreadHandle,
     ext: Option<HostAudioPorts>,
 ) -> RescanType {
@@ -102,3 +304,75 @@ fn get_host_supported_rescan_types(
 
     supported
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(id: u32, channel_count: u32) -> CachedAudioPortInfo {
+        CachedAudioPortInfo {
+            id: ClapId::new(id),
+            name: format!("Port {id}"),
+            channel_count,
+            flags: AudioPortFlags::empty(),
+            port_type: None,
+            in_place_pair: None,
+        }
+    }
+
+    fn layout(outputs: Vec<CachedAudioPortInfo>) -> AudioPortLayout {
+        AudioPortLayout {
+            inputs: vec![port(0, 2)],
+            outputs,
+        }
+    }
+
+    #[test]
+    fn same_layout_has_no_changes() {
+        let old = layout(vec![port(1, 2), port(2, 2)]);
+
+        assert!(old.diff_for_rescan(&old.clone()).is_empty());
+    }
+
+    #[test]
+    fn renaming_ports_does_not_require_a_restart() {
+        let old = layout(vec![port(1, 2)]);
+        let mut new = old.clone();
+        new.outputs[0].name = String::from("Renamed");
+
+        let changes = old.diff_for_rescan(&new);
+        assert_eq!(changes, RescanType::NAMES);
+        assert!(!requires_restart(changes));
+    }
+
+    #[test]
+    fn port_changes_are_combined() {
+        let old = layout(vec![port(1, 2), port(2, 2)]);
+        let mut new = old.clone();
+        new.outputs[0].channel_count = 1;
+        new.outputs[1].flags = AudioPortFlags::SUPPORTS_64BITS;
+        new.outputs[1].port_type = Some(CString::new("mono").unwrap());
+
+        let changes = old.diff_for_rescan(&new);
+        assert_eq!(
+            changes,
+            RescanType::CHANNEL_COUNT | RescanType::FLAGS | RescanType::PORT_TYPE
+        );
+        assert!(requires_restart(changes));
+    }
+
+    #[test]
+    fn adding_or_reordering_ports_changes_the_list() {
+        let old = layout(vec![port(1, 2), port(2, 2)]);
+
+        let added = layout(vec![port(1, 2), port(2, 2), port(3, 2)]);
+        assert_eq!(old.diff_for_rescan(&added), RescanType::LIST);
+
+        let reordered = layout(vec![port(2, 2), port(1, 2)]);
+        assert_eq!(old.diff_for_rescan(&reordered), RescanType::LIST);
+
+        let mut new_input = old.clone();
+        new_input.inputs[0].id = ClapId::new(5);
+        assert_eq!(old.diff_for_rescan(&new_input), RescanType::LIST);
+    }
+}
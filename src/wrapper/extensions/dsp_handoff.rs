@@ -0,0 +1,169 @@
+use crate::wrapper::*;
+
+/// Allocates the buffer `src` exports its DSP state into when `dst` replaces it on the audio
+/// thread, if both support the DSP handoff extension. `src` must be active.
+pub fn prepare_dsp_handoff(
+    src: &mut PluginInstance<WrapperHost>,
+    dst: &mut PluginInstance<WrapperHost>,
+) {
+    let Some(src_handoff) = src.access_shared_handler(|h| h.wrapped_plugin().dsp_handoff) else {
+        return;
+    };
+
+    if dst
+        .access_shared_handler(|h| h.wrapped_plugin().dsp_handoff)
+        .is_none()
+    {
+        return;
+    }
+
+    let max_size = src_handoff.max_size(&mut src.plugin_handle());
+    if max_size == 0 {
+        return;
+    }
+
+    dst.access_shared_handler(|h| h.set_dsp_handoff_buffer(vec![0; max_size]));
+}
+
+impl<'a> WrapperPluginAudioProcessor<'a> {
+    /// Moves the DSP state of the previous processor into the current one. This returns false if
+    /// either doesn't support it, or if the current one declined it.
+    pub(crate) fn hand_off_dsp_state(
+        &mut self,
+        old_processor: &mut clack_host::process::PluginAudioProcessor<WrapperHost>,
+    ) -> bool {
+        let Some(mut buffer) = self
+            .current_audio_processor
+            .access_shared_handler(|h| h.take_dsp_handoff_buffer())
+        else {
+            return false;
+        };
+
+        let handed_off = self.transfer_dsp_state(old_processor, &mut buffer);
+
+        // Give the buffer back, so that it is deallocated on the main thread with the instance.
+        self.current_audio_processor
+            .access_shared_handler(|h| h.return_dsp_handoff_buffer(buffer));
+
+        handed_off
+    }
+
+    fn transfer_dsp_state(
+        &mut self,
+        old_processor: &mut clack_host::process::PluginAudioProcessor<WrapperHost>,
+        buffer: &mut [u8],
+    ) -> bool {
+        let Some(src_handoff) =
+            old_processor.access_shared_handler(|h| h.wrapped_plugin().dsp_handoff)
+        else {
+            return false;
+        };
+
+        let Some(dst_handoff) = self
+            .current_audio_processor
+            .access_shared_handler(|h| h.wrapped_plugin().dsp_handoff)
+        else {
+            return false;
+        };
+
+        let Some((size, version)) =
+            src_handoff.export_state(&mut old_processor.plugin_handle(), buffer)
+        else {
+            return false;
+        };
+
+        dst_handoff.import_state(
+            &mut self.current_audio_processor.plugin_handle(),
+            &buffer[..size],
+            version,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapper::test_plugin::{
+        new_test_instance, TEST_AUDIO_CONFIG, TEST_DSP_STATE_VERSION,
+    };
+
+    type AudioProcessor = clack_host::process::PluginAudioProcessor<WrapperHost>;
+
+    fn activate(instance: &mut PluginInstance<WrapperHost>) -> AudioProcessor {
+        WrapperHost::activate_instance(instance, TEST_AUDIO_CONFIG)
+            .unwrap()
+            .into()
+    }
+
+    fn handoff(processor: &AudioProcessor) -> PluginDspHandoff {
+        processor
+            .access_shared_handler(|h| h.wrapped_plugin().dsp_handoff)
+            .unwrap()
+    }
+
+    #[test]
+    fn handoff_buffer_is_sized_by_the_outgoing_instance() {
+        let mut src = new_test_instance(false);
+        let mut dst = new_test_instance(false);
+        let _src_processor = activate(&mut src);
+
+        prepare_dsp_handoff(&mut src, &mut dst);
+
+        let buffer = dst.access_shared_handler(|h| h.take_dsp_handoff_buffer());
+        assert_eq!(buffer.map(|b| b.len()), Some(size_of::<u32>()));
+    }
+
+    #[test]
+    fn dsp_state_round_trips() {
+        let mut src = new_test_instance(false);
+        let mut dst = new_test_instance(false);
+        let mut src_processor = activate(&mut src);
+        let mut dst_processor = activate(&mut dst);
+        let (src_handoff, dst_handoff) = (handoff(&src_processor), handoff(&dst_processor));
+
+        let state = 42u32.to_le_bytes();
+        assert!(src_handoff.import_state(
+            &mut src_processor.plugin_handle(),
+            &state,
+            TEST_DSP_STATE_VERSION
+        ));
+
+        let mut buffer = [0; 4];
+        let (size, version) = src_handoff
+            .export_state(&mut src_processor.plugin_handle(), &mut buffer)
+            .unwrap();
+        assert_eq!(
+            (&buffer[..size], version),
+            (&state[..], TEST_DSP_STATE_VERSION)
+        );
+
+        assert!(dst_handoff.import_state(
+            &mut dst_processor.plugin_handle(),
+            &buffer[..size],
+            version
+        ));
+
+        let mut imported = [0; 4];
+        dst_handoff.export_state(&mut dst_processor.plugin_handle(), &mut imported);
+        assert_eq!(imported, state);
+    }
+
+    #[test]
+    fn unknown_versions_and_short_buffers_are_rejected() {
+        let mut instance = new_test_instance(false);
+        let mut processor = activate(&mut instance);
+        let handoff = handoff(&processor);
+
+        assert!(!handoff.import_state(
+            &mut processor.plugin_handle(),
+            &[0; 4],
+            TEST_DSP_STATE_VERSION + 1
+        ));
+
+        let mut buffer = [0; 2];
+        assert_eq!(
+            handoff.export_state(&mut processor.plugin_handle(), &mut buffer),
+            None
+        );
+    }
+}
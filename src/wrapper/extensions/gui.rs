@@ -140,13 +140,16 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return false;
         };
 
-        gui.is_api_supported(&mut self.plugin_handle(), configuration)
+        self.call_wrapped(|w| gui.is_api_supported(&mut w.plugin_handle(), configuration))
+            .unwrap_or(false)
     }
 
     fn get_preferred_api(&mut self) -> Option<GuiConfiguration> {
         let gui = self.plugin_instance_gui()?;
 
-        let config = gui.get_preferred_api(&mut self.plugin_handle())?;
+        let config = self
+            .call_wrapped(|w| gui.get_preferred_api(&mut w.plugin_handle()))
+            .ok()??;
 
         // TODO: add helper to standard api config
         Some(GuiConfiguration {
@@ -165,7 +168,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             is_floating: configuration.is_floating,
         };
 
-        gui.create(&mut self.plugin_handle(), configuration)?;
+        self.call_wrapped(|w| gui.create(&mut w.plugin_handle(), configuration))??;
         self.gui.status = Status::Created(configuration);
         Ok(())
     }
@@ -175,7 +178,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return;
         };
 
-        gui.destroy(&mut self.plugin_handle());
+        let _ = self.call_wrapped(|w| gui.destroy(&mut w.plugin_handle()));
 
         self.gui.reset();
     }
@@ -185,7 +188,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return Err(PluginError::Message("Plugin does not support GUI"));
         };
 
-        gui.set_scale(&mut self.plugin_handle(), scale)?;
+        self.call_wrapped(|w| gui.set_scale(&mut w.plugin_handle(), scale))??;
         self.gui.scale = Some(scale);
 
         Ok(())
@@ -194,7 +197,9 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
     fn get_size(&mut self) -> Option<GuiSize> {
         let gui = self.plugin_instance_gui()?;
 
-        gui.get_size(&mut self.plugin_handle())
+        self.call_wrapped(|w| gui.get_size(&mut w.plugin_handle()))
+            .ok()
+            .flatten()
     }
 
     fn can_resize(&mut self) -> bool {
@@ -202,19 +207,24 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return false;
         };
 
-        gui.can_resize(&mut self.plugin_handle())
+        self.call_wrapped(|w| gui.can_resize(&mut w.plugin_handle()))
+            .unwrap_or(false)
     }
 
     fn get_resize_hints(&mut self) -> Option<GuiResizeHints> {
         let gui = self.plugin_instance_gui()?;
 
-        gui.get_resize_hints(&mut self.plugin_handle())
+        self.call_wrapped(|w| gui.get_resize_hints(&mut w.plugin_handle()))
+            .ok()
+            .flatten()
     }
 
     fn adjust_size(&mut self, size: GuiSize) -> Option<GuiSize> {
         let gui = self.plugin_instance_gui()?;
 
-        gui.adjust_size(&mut self.plugin_handle(), size)
+        self.call_wrapped(|w| gui.adjust_size(&mut w.plugin_handle(), size))
+            .ok()
+            .flatten()
     }
 
     fn set_size(&mut self, size: GuiSize) -> Result<(), PluginError> {
@@ -222,7 +232,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return Err(PluginError::Message("Plugin does not support GUI"));
         };
 
-        gui.set_size(&mut self.plugin_handle(), size)?;
+        self.call_wrapped(|w| gui.set_size(&mut w.plugin_handle(), size))??;
         self.gui.size = Some(size);
 
         Ok(())
@@ -236,7 +246,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
         let window = window.to_standard_api_type().unwrap(); // We don't support other API types yet
 
         // SAFETY: we are still within set_parent
-        unsafe { gui.set_parent(&mut self.plugin_handle(), window)? };
+        self.call_wrapped(|w| unsafe { gui.set_parent(&mut w.plugin_handle(), window) })??;
         self.gui.parent = Some(window);
 
         Ok(())
@@ -249,7 +259,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
         let window = window.to_standard_api_type().unwrap(); // We don't support other API types yet
 
         // SAFETY: we are still within set_transient
-        unsafe { gui.set_transient(&mut self.plugin_handle(), window)? };
+        self.call_wrapped(|w| unsafe { gui.set_transient(&mut w.plugin_handle(), window) })??;
         self.gui.transient = Some(window);
 
         Ok(())
@@ -262,7 +272,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
 
         // FIXME
         let title = CString::new(title).unwrap();
-        gui.suggest_title(&mut self.plugin_handle(), &title);
+        let _ = self.call_wrapped(|w| gui.suggest_title(&mut w.plugin_handle(), &title));
         self.gui.title = Some(title)
     }
 
@@ -271,7 +281,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return Err(PluginError::Message("Plugin does not support GUI"));
         };
 
-        gui.show(&mut self.plugin_handle())?;
+        self.call_wrapped(|w| gui.show(&mut w.plugin_handle()))??;
         self.gui.shown = true;
 
         Ok(())
@@ -282,7 +292,7 @@ impl<'a> PluginGuiImpl for WrapperPluginMainThread<'a> {
             return Err(PluginError::Message("Plugin does not support GUI"));
         };
 
-        gui.hide(&mut self.plugin_handle())?;
+        self.call_wrapped(|w| gui.hide(&mut w.plugin_handle()))??;
         self.gui.shown = false;
 
         Ok(())
@@ -0,0 +1,49 @@
+use crate::log::log;
+use crate::wrapper::*;
+use clack_host::stream::{InputStream, OutputStream};
+use std::io::Cursor;
+
+/// Hands the hot state of an instance over to another, if both support the hot state extension.
+///
+/// Failing to do so isn't an error: the new instance only loses some transient state.
+pub fn transfer_hot_state(
+    src: &mut PluginInstance<WrapperHost>,
+    dst: &mut PluginInstance<WrapperHost>,
+) {
+    let Some(src_hot_state) = src.access_shared_handler(|h| h.wrapped_plugin().hot_state) else {
+        return;
+    };
+
+    let Some(dst_hot_state) = dst.access_shared_handler(|h| h.wrapped_plugin().hot_state) else {
+        return;
+    };
+
+    let mut buf = Vec::with_capacity(4096);
+
+    let mut output_stream = OutputStream::from_writer(&mut buf);
+    let Some(version) = src_hot_state.save(&mut src.plugin_handle(), &mut output_stream) else {
+        log!(
+            Debug,
+            StateTransfer,
+            "The previous instance has no hot state to hand over."
+        );
+        return;
+    };
+
+    let mut cursor = Cursor::new(buf);
+    let mut input_stream = InputStream::from_reader(&mut cursor);
+
+    if dst_hot_state.load(&mut dst.plugin_handle(), &mut input_stream, version) {
+        log!(
+            Debug,
+            StateTransfer,
+            "Transferred hot state (schema version {version})."
+        );
+    } else {
+        log!(
+            Info,
+            StateTransfer,
+            "The new instance declined the hot state (schema version {version})."
+        );
+    }
+}
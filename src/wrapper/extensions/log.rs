@@ -0,0 +1,72 @@
+use crate::log::{log_event, LogLevel, LogStage};
+use crate::wrapper::WrapperHostShared;
+use clack_extensions::log::{HostLogImpl, LogSeverity};
+
+impl HostLogImpl for WrapperHostShared {
+    fn log(&self, severity: LogSeverity, message: &str) {
+        // The wrapped plugin catches its own panics, and reports them as misbehaving.
+        if matches!(
+            severity,
+            LogSeverity::PluginMisbehaving | LogSeverity::Fatal
+        ) {
+            self.quarantine();
+        }
+
+        let level = match severity {
+            LogSeverity::Debug => LogLevel::Debug,
+            LogSeverity::Info => LogLevel::Info,
+            LogSeverity::Warning | LogSeverity::HostMisbehaving => LogLevel::Warn,
+            _ => LogLevel::Error,
+        };
+
+        log_event(level, LogStage::Plugin, format_args!("{message}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wrapper::test_plugin::{new_test_instance, TEST_AUDIO_CONFIG};
+    use crate::wrapper::WrapperHost;
+    use clack_extensions::latency::PluginLatency;
+    use clack_host::prelude::*;
+
+    #[test]
+    fn main_thread_panic_quarantines_instance() {
+        let mut instance = new_test_instance(true);
+        let latency: PluginLatency = instance.plugin_handle().get_extension().unwrap();
+
+        // The panic doesn't cross the plugin boundary: it only returns a default value.
+        assert_eq!(latency.get(&mut instance.plugin_handle()), 0);
+        assert!(instance.access_shared_handler(|h| h.is_quarantined()));
+    }
+
+    #[test]
+    fn audio_thread_panic_is_an_error() {
+        let mut instance = new_test_instance(true);
+        let mut processor: clack_host::process::PluginAudioProcessor<WrapperHost> =
+            WrapperHost::activate_instance(&mut instance, TEST_AUDIO_CONFIG)
+                .unwrap()
+                .into();
+
+        let result = processor.ensure_processing_started().unwrap().process(
+            &InputAudioBuffers::empty(),
+            &mut OutputAudioBuffers::empty(),
+            &InputEvents::empty(),
+            &mut OutputEvents::void(),
+            None,
+            None,
+        );
+
+        assert!(result.is_err());
+        assert!(processor.access_shared_handler(|h| h.is_quarantined()));
+    }
+
+    #[test]
+    fn working_instance_is_not_quarantined() {
+        let mut instance = new_test_instance(false);
+        let latency: PluginLatency = instance.plugin_handle().get_extension().unwrap();
+
+        assert_eq!(latency.get(&mut instance.plugin_handle()), 0);
+        assert!(!instance.access_shared_handler(|h| h.is_quarantined()));
+    }
+}
@@ -1,24 +1,262 @@
 use crate::wrapper::*;
 use clack_extensions::note_ports::*;
+use clack_plugin::utils::ClapId;
 
-impl<'a> PluginNotePortsImpl for WrapperPluginMainThread<'a> {
-    fn count(&mut self, is_input: bool) -> u32 {
-        let Some(note_ports) = self.wrapped_extensions().note_ports else {
-            return 0;
-        };
+#[derive(Clone, Eq, PartialEq)]
+struct CachedNotePortInfo {
+    id: ClapId,
+    name: String,
+    supported_dialects: NoteDialects,
+    preferred_dialect: Option<NoteDialect>,
+}
 
-        note_ports.count(&mut self.plugin_handle(), is_input)
+impl CachedNotePortInfo {
+    fn from_info(info: &NotePortInfo) -> Self {
+        Self {
+            id: info.id,
+            name: String::from_utf8_lossy(info.name).into_owned(),
+            supported_dialects: info.supported_dialects,
+            preferred_dialect: info.preferred_dialect,
+        }
     }
 
-    fn get(&mut self, index: u32, is_input: bool, writer: &mut NotePortInfoWriter) {
-        let Some(note_ports) = self.wrapped_extensions().note_ports else {
-            return;
+    #[inline]
+    fn as_info(&self) -> NotePortInfo {
+        NotePortInfo {
+            id: self.id,
+            name: self.name.as_bytes(),
+            supported_dialects: self.supported_dialects,
+            preferred_dialect: self.preferred_dialect,
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub(crate) struct NotePortList {
+    inputs: Vec<CachedNotePortInfo>,
+    outputs: Vec<CachedNotePortInfo>,
+}
+
+impl NotePortList {
+    pub(crate) fn read_from(instance: &mut PluginInstance<WrapperHost>) -> Self {
+        let Some(note_ports) = instance.access_shared_handler(|h| h.wrapped_plugin().note_ports)
+        else {
+            return Self {
+                inputs: vec![],
+                outputs: vec![],
+            };
         };
 
+        let mut plugin = instance.plugin_handle();
         let mut buf = NotePortInfoBuffer::new();
 
-        if let Some(data) = note_ports.get(&mut self.plugin_handle(), index, is_input, &mut buf) {
-            writer.set(&data);
+        let mut read_ports = |is_input: bool| {
+            let port_count = note_ports.count(&mut plugin, is_input);
+            let mut ports = Vec::with_capacity(port_count as usize);
+
+            for i in 0..port_count {
+                if let Some(data) = note_ports.get(&mut plugin, i, is_input, &mut buf) {
+                    ports.push(CachedNotePortInfo::from_info(&data));
+                }
+            }
+
+            ports
+        };
+
+        Self {
+            inputs: read_ports(true),
+            outputs: read_ports(false),
+        }
+    }
+
+    /// Returns the dialects of each input port.
+    pub(crate) fn input_dialects(&self) -> Box<[InputNoteDialects]> {
+        self.inputs
+            .iter()
+            .map(InputNoteDialects::from_port)
+            .collect()
+    }
+
+    fn ports(&self, is_input: bool) -> &[CachedNotePortInfo] {
+        if is_input {
+            &self.inputs
+        } else {
+            &self.outputs
+        }
+    }
+
+    fn diff_for_rescan(&self, new: &Self) -> NotePortRescanFlags {
+        let mut flags = NotePortRescanFlags::empty();
+
+        for is_input in [true, false] {
+            let (old_ports, new_ports) = (self.ports(is_input), new.ports(is_input));
+
+            if old_ports.len() != new_ports.len() {
+                return NotePortRescanFlags::ALL;
+            }
+
+            for (old_port, new_port) in old_ports.iter().zip(new_ports) {
+                if old_port.id != new_port.id
+                    || old_port.supported_dialects != new_port.supported_dialects
+                    || old_port.preferred_dialect != new_port.preferred_dialect
+                {
+                    return NotePortRescanFlags::ALL;
+                }
+
+                if old_port.name != new_port.name {
+                    flags |= NotePortRescanFlags::NAMES;
+                }
+            }
+        }
+
+        flags
+    }
+}
+
+/// The note dialects an input port of the wrapped plugin accepts.
+#[derive(Copy, Clone)]
+pub struct InputNoteDialects {
+    /// The dialect held notes are replayed in after a swap.
+    pub preferred: NoteDialect,
+    pub supported: NoteDialects,
+}
+
+impl Default for InputNoteDialects {
+    fn default() -> Self {
+        Self {
+            preferred: NoteDialect::Clap,
+            supported: NoteDialects::CLAP,
+        }
+    }
+}
+
+impl InputNoteDialects {
+    fn from_port(port: &CachedNotePortInfo) -> Self {
+        let fallback = [
+            (NoteDialects::CLAP, NoteDialect::Clap),
+            (NoteDialects::MIDI2, NoteDialect::Midi2),
+            (NoteDialects::MIDI_MPE, NoteDialect::MidiMpe),
+            (NoteDialects::MIDI, NoteDialect::Midi),
+        ]
+        .into_iter()
+        .find(|(flag, _)| port.supported_dialects.contains(*flag))
+        .map(|(_, dialect)| dialect);
+
+        Self {
+            preferred: port
+                .preferred_dialect
+                .or(fallback)
+                .unwrap_or(NoteDialect::Clap),
+            supported: port.supported_dialects,
+        }
+    }
+}
+
+pub struct NotePortInfoCache {
+    ports: NotePortList,
+    /// A port list from a reloaded instance, waiting for the plugin to be deactivated to be
+    /// applied.
+    pending_ports: Option<NotePortList>,
+}
+
+impl NotePortInfoCache {
+    pub fn new(instance: &mut PluginInstance<WrapperHost>) -> Self {
+        Self {
+            ports: NotePortList::read_from(instance),
+            pending_ports: None,
+        }
+    }
+}
+
+impl<'a> WrapperPluginMainThread<'a> {
+    /// Returns true if switching to the given note ports requires the host to restart the plugin.
+    pub(crate) fn note_ports_require_restart(&self, new_ports: &NotePortList) -> bool {
+        let changes = self.note_ports_info.ports.diff_for_rescan(new_ports);
+
+        changes.contains(NotePortRescanFlags::ALL) && self.audio_processor_channel.is_some()
+    }
+
+    /// Compares the note ports of a freshly reloaded instance to the ones the host knows about.
+    ///
+    /// Name changes are sent to the host right away. Any other change (including supported
+    /// dialects) requires a full rescan, which can only happen while the plugin is deactivated:
+    /// in that case, a restart is requested and this returns true.
+    pub(crate) fn update_note_ports(&mut self, new_ports: NotePortList) -> bool {
+        let cache = &mut self.note_ports_info;
+        let changes = cache.ports.diff_for_rescan(&new_ports);
+
+        if changes.is_empty() {
+            cache.pending_ports = None;
+            return false;
+        }
+
+        if changes.contains(NotePortRescanFlags::ALL) && self.audio_processor_channel.is_some() {
+            cache.pending_ports = Some(new_ports);
+            self.host.shared().request_restart();
+            return true;
+        }
+
+        cache.ports = new_ports;
+        cache.pending_ports = None;
+        self.notify_note_ports_changes(changes);
+
+        false
+    }
+
+    /// Applies the note ports of a reloaded instance that were waiting for the plugin to be
+    /// deactivated.
+    pub(crate) fn apply_pending_note_ports(&mut self) {
+        if let Some(ports) = self.note_ports_info.pending_ports.take() {
+            self.note_ports_info.ports = ports;
+            self.notify_note_ports_changes(NotePortRescanFlags::ALL);
+        }
+    }
+
+    /// Forwards the rescan requests the wrapped plugin made on its own to the host.
+    pub(crate) fn process_note_ports_requests(&mut self) {
+        let requested = self.plugin_instance.access_handler_mut(|h| {
+            core::mem::replace(&mut h.note_ports_rescan, NotePortRescanFlags::empty())
+        });
+
+        if requested.is_empty() {
+            return;
+        }
+
+        self.note_ports_info.ports = NotePortList::read_from(&mut self.plugin_instance);
+        self.note_ports_info.pending_ports = None;
+        self.notify_note_ports_changes(requested);
+    }
+
+    fn notify_note_ports_changes(&mut self, changes: NotePortRescanFlags) {
+        if let Some(host_note_ports) = self.shared.host_extensions.note_ports {
+            host_note_ports.rescan(&mut self.host, changes);
+        }
+    }
+}
+
+impl<'a> HostNotePortsImpl for WrapperHostMainThread<'a> {
+    fn supported_dialects(&self) -> NoteDialects {
+        self.host_capabilities.note_dialects
+    }
+
+    fn rescan(&mut self, flags: NotePortRescanFlags) {
+        self.note_ports_rescan |= flags;
+    }
+}
+
+impl<'a> PluginNotePortsImpl for WrapperPluginMainThread<'a> {
+    fn count(&mut self, is_input: bool) -> u32 {
+        self.note_ports_info.ports.ports(is_input).len() as u32
+    }
+
+    fn get(&mut self, index: u32, is_input: bool, writer: &mut NotePortInfoWriter) {
+        if let Some(port) = self
+            .note_ports_info
+            .ports
+            .ports(is_input)
+            .get(index as usize)
+        {
+            writer.set(&port.as_info());
         }
     }
 }
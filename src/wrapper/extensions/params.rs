@@ -1,12 +1,19 @@
+use crate::param_migrations::ParamMigrationTable;
 use crate::wrapper::*;
 use clack_extensions::params::*;
+use clack_host::events::event_types::ParamValueEvent;
+use clack_host::events::spaces::CoreEventSpace;
 use clack_host::utils::Cookie;
 use clack_plugin::utils::ClapId;
 use std::fmt::Write;
 use std::mem::MaybeUninit;
 
+#[derive(Clone)]
 struct CachedParamInfo {
+    /// The ID presented to the host. It stays the same across reloads.
     id: ClapId,
+    /// The ID of this parameter in the current wrapped instance.
+    inner_id: ClapId,
     flags: ParamInfoFlags,
     min_value: f64,
     max_value: f64,
@@ -14,17 +21,21 @@ struct CachedParamInfo {
     name: String,
     module: String,
     cookie: Cookie,
+    /// Set if the parameter was removed by a reload, but is kept around for the host.
+    is_ghost: bool,
 }
 
 impl CachedParamInfo {
-    fn from_info(info: &ParamInfo) -> Self {
+    fn from_info(info: &ParamInfo, id: ClapId) -> Self {
         Self {
-            id: info.id,
+            id,
+            inner_id: info.id,
             flags: info.flags,
             min_value: info.min_value,
             max_value: info.max_value,
             default_value: info.default_value,
             cookie: info.cookie,
+            is_ghost: false,
 
             module: String::from_utf8_lossy(info.module).into_owned(),
             name: String::from_utf8_lossy(info.name).into_owned(),
@@ -33,9 +44,17 @@ impl CachedParamInfo {
 
     #[inline]
     fn as_info(&self) -> ParamInfo {
+        // Only hiding ghosts needs no more than an info rescan. Events the host still sends them
+        // are dropped, see ParamIdMap.
+        let flags = if self.is_ghost {
+            self.flags | ParamInfoFlags::IS_HIDDEN
+        } else {
+            self.flags
+        };
+
         ParamInfo {
             id: self.id,
-            flags: self.flags,
+            flags,
             min_value: self.min_value,
             max_value: self.max_value,
             default_value: self.default_value,
@@ -64,28 +83,50 @@ impl CachedParamInfo {
     }
 }
 
+#[derive(Clone)]
 pub struct ParamInfoCache {
     params: Vec<CachedParamInfo>,
+    /// If true, removed params are kept as ghosts instead of being removed from the list.
+    keep_removed: bool,
 }
 
 impl ParamInfoCache {
-    pub fn new(instance: &mut PluginInstance<WrapperHost>) -> Self {
-        let mut list = Self { params: vec![] };
-        list.update(instance);
+    pub fn new(instance: &mut PluginInstance<WrapperHost>, keep_removed: bool) -> Self {
+        let mut list = Self {
+            params: vec![],
+            keep_removed,
+        };
+        list.update(instance, &ParamMigrationTable::default());
         list
     }
 
-    pub fn update(&mut self, instance: &mut PluginInstance<WrapperHost>) -> ParamRescanFlags {
+    pub fn update(
+        &mut self,
+        instance: &mut PluginInstance<WrapperHost>,
+        migrations: &ParamMigrationTable,
+    ) -> ParamRescanFlags {
         let Some(params) = instance.access_shared_handler(|h| h.wrapped_plugin().params) else {
             return ParamRescanFlags::empty();
         };
 
+        // Follow the parameters that were renumbered in the new instance
+        for param in &mut self.params {
+            if let Some(new_id) = migrations.migrate(param.inner_id) {
+                param.inner_id = new_id;
+            }
+        }
+
         let mut plugin = instance.plugin_handle();
 
         let mut flags = ParamRescanFlags::empty();
         let mut buf = ParamInfoBuffer::new();
 
-        let mut unseen_ids: Vec<_> = self.params.iter().map(|p| p.id).collect();
+        let mut unseen_ids: Vec<_> = self
+            .params
+            .iter()
+            .filter(|p| !p.is_ghost)
+            .map(|p| p.id)
+            .collect();
         let param_count = params.count(&mut plugin);
 
         for i in 0..param_count {
@@ -93,28 +134,254 @@ impl ParamInfoCache {
                 continue;
             };
 
-            let Some(matching_param) = self.params.iter_mut().find(|p| p.id == info.id) else {
-                // New param!
-                self.params.push(CachedParamInfo::from_info(&info));
+            let Some(matching_param) = self.params.iter_mut().find(|p| p.inner_id == info.id)
+            else {
+                // New param! Don't let it take over the ID of another one, the host would mix them up.
+                let id = self.unused_id(info.id);
+                self.params.push(CachedParamInfo::from_info(&info, id));
                 flags |= ParamRescanFlags::ALL;
 
                 continue;
             };
 
-            unseen_ids.retain(|i| *i != info.id);
+            let id = matching_param.id;
+            unseen_ids.retain(|i| *i != id);
 
-            flags |= matching_param.as_info().diff_for_rescan(&info);
+            if matching_param.is_ghost {
+                // Welcome back. Only the hidden flag changes, on top of whatever else did.
+                matching_param.is_ghost = false;
+                flags |= ParamRescanFlags::INFO;
+            }
+
+            flags |= matching_param
+                .as_info()
+                .diff_for_rescan(&ParamInfo { id, ..info });
             matching_param.update(&info);
         }
 
         // Handle removed params
         if !unseen_ids.is_empty() {
-            flags |= ParamRescanFlags::ALL;
-            self.params.retain(|p| !unseen_ids.contains(&p.id))
+            if self.keep_removed {
+                // Only the hidden flag changes, the list itself stays the same.
+                flags |= ParamRescanFlags::INFO;
+
+                for param in &mut self.params {
+                    if unseen_ids.contains(&param.id) {
+                        param.is_ghost = true;
+                    }
+                }
+            } else {
+                flags |= ParamRescanFlags::ALL;
+                self.params.retain(|p| !unseen_ids.contains(&p.id))
+            }
         }
 
         flags
     }
+
+    fn unused_id(&self, preferred_id: ClapId) -> ClapId {
+        let mut id = preferred_id.get();
+
+        while self.params.iter().any(|p| p.id.get() == id) {
+            id = id.wrapping_add(1) % u32::MAX;
+        }
+
+        ClapId::new(id)
+    }
+
+    /// Returns the ID the current instance uses for a parameter the host knows about, or `None`
+    /// if it is a ghost parameter.
+    pub fn to_inner(&self, id: ClapId) -> Option<ClapId> {
+        match self.params.iter().find(|p| p.id == id) {
+            Some(param) if param.is_ghost => None,
+            Some(param) => Some(param.inner_id),
+            None => Some(id),
+        }
+    }
+
+    fn ghost_default_value(&self, id: ClapId) -> Option<f64> {
+        self.params
+            .iter()
+            .find(|p| p.id == id && p.is_ghost)
+            .map(|p| p.default_value)
+    }
+
+    /// Returns the parameters whose ID changed since the host first saw them.
+    pub fn id_map(&self) -> ParamIdMap {
+        ParamIdMap {
+            remapped: self
+                .params
+                .iter()
+                .filter(|p| !p.is_ghost && p.id != p.inner_id)
+                .map(|p| (p.id, p.inner_id))
+                .collect(),
+            ghosts: self
+                .params
+                .iter()
+                .filter(|p| p.is_ghost)
+                .map(|p| p.id)
+                .collect(),
+        }
+    }
+
+    /// Reads the current value of all parameters, keyed by the ID the host knows them by.
+    pub fn read_values(&self, instance: &mut PluginInstance<WrapperHost>) -> Vec<(ClapId, f64)> {
+        let Some(params) = instance.access_shared_handler(|h| h.wrapped_plugin().params) else {
+            return Vec::new();
+        };
+
+        let mut plugin = instance.plugin_handle();
+
+        self.params
+            .iter()
+            .filter(|p| !p.is_ghost)
+            .filter_map(|p| Some((p.id, params.get_value(&mut plugin, p.inner_id)?)))
+            .collect()
+    }
+
+    /// Sets the values previously read by [`Self::read_values`] on the instance the cache was
+    /// last updated with.
+    ///
+    /// This is used to at least keep the parameter values when state transfer failed or isn't
+    /// supported. Values are clamped to the new ranges of the parameters.
+    pub fn write_values(
+        &self,
+        instance: &mut PluginInstance<WrapperHost>,
+        values: &[(ClapId, f64)],
+    ) {
+        let Some(params) = instance.access_shared_handler(|h| h.wrapped_plugin().params) else {
+            return;
+        };
+
+        let mut events = EventBuffer::with_capacity(values.len());
+
+        for (id, value) in values {
+            let Some(param) = self.params.iter().find(|p| p.id == *id && !p.is_ghost) else {
+                continue;
+            };
+
+            let value = value.max(param.min_value).min(param.max_value);
+
+            events.push(&ParamValueEvent::new(
+                0,
+                param.inner_id,
+                Pckn::match_all(),
+                value,
+                param.cookie,
+            ));
+        }
+
+        if events.is_empty() {
+            return;
+        }
+
+        params.flush(
+            &mut instance.plugin_handle(),
+            &InputEvents::from_buffer(&events),
+            &mut OutputEvents::void(),
+        );
+    }
+}
+
+/// The parameters of a wrapped instance whose ID differs from the one presented to the host.
+///
+/// This is a snapshot of the [`ParamInfoCache`], for use on the audio thread.
+pub struct ParamIdMap {
+    /// Pairs of outer and inner IDs.
+    remapped: Vec<(ClapId, ClapId)>,
+    /// Outer IDs of the parameters that don't exist in the wrapped instance anymore.
+    ghosts: Vec<ClapId>,
+}
+
+impl ParamIdMap {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.remapped.is_empty() && self.ghosts.is_empty()
+    }
+
+    /// Returns `None` if the event should be dropped.
+    fn map_id(&self, id: ClapId, to_inner: bool) -> Option<ClapId> {
+        if to_inner && self.ghosts.contains(&id) {
+            return None;
+        }
+
+        let found = if to_inner {
+            self.remapped.iter().find(|(outer, _)| *outer == id)
+        } else {
+            self.remapped.iter().find(|(_, inner)| *inner == id)
+        };
+
+        let mapped = match found {
+            Some((outer, inner)) => {
+                if to_inner {
+                    *inner
+                } else {
+                    *outer
+                }
+            }
+            None => id,
+        };
+
+        Some(mapped)
+    }
+
+    /// Copies all events from `src` to `dst`, rewriting parameter IDs either from the host's IDs
+    /// to the wrapped instance's (if `to_inner` is true), or the other way around.
+    ///
+    /// Events targeting ghost parameters are dropped.
+    pub fn map_events(&self, src: &InputEvents, dst: &mut OutputEvents, to_inner: bool) {
+        // All parameter events have a param_id, but no common trait to set it through.
+        macro_rules! push_mapped {
+            ($event:expr) => {
+                match $event.param_id() {
+                    Some(id) => {
+                        let Some(id) = self.map_id(id, to_inner) else {
+                            continue;
+                        };
+
+                        let mut event = *$event;
+                        event.set_param_id(id);
+                        dst.try_push(&event)
+                    }
+                    None => dst.try_push($event),
+                }
+            };
+        }
+
+        for event in src {
+            let _ = match event.as_core_event() {
+                Some(CoreEventSpace::ParamValue(e)) => push_mapped!(e),
+                Some(CoreEventSpace::ParamMod(e)) => push_mapped!(e),
+                Some(CoreEventSpace::ParamGestureBegin(e)) => push_mapped!(e),
+                Some(CoreEventSpace::ParamGestureEnd(e)) => push_mapped!(e),
+                _ => dst.try_push(event),
+            };
+        }
+    }
+}
+
+/// Buffers used to rewrite parameter IDs on the audio thread.
+#[derive(Default)]
+pub struct ParamEventBuffers {
+    pub input: EventBuffer,
+    pub fade_out_input: EventBuffer,
+    pub output: EventBuffer,
+}
+
+impl ParamEventBuffers {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            input: EventBuffer::with_capacity(capacity),
+            fade_out_input: EventBuffer::with_capacity(capacity),
+            output: EventBuffer::with_capacity(capacity),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.input.clear();
+        self.fade_out_input.clear();
+        self.output.clear();
+    }
 }
 
 impl<'a> PluginMainThreadParams for WrapperPluginMainThread<'a> {
@@ -129,9 +396,17 @@ impl<'a> PluginMainThreadParams for WrapperPluginMainThread<'a> {
     }
 
     fn get_value(&mut self, param_id: ClapId) -> Option<f64> {
-        self.wrapped_extensions()
-            .params?
-            .get_value(&mut self.plugin_handle(), param_id)
+        let Some(param_id) = self.param_info_cache.to_inner(param_id) else {
+            return self.param_info_cache.ghost_default_value(param_id);
+        };
+
+        self.call_wrapped(|w| {
+            w.wrapped_extensions()
+                .params?
+                .get_value(&mut w.plugin_handle(), param_id)
+        })
+        .ok()
+        .flatten()
     }
 
     fn value_to_text(
@@ -144,32 +419,87 @@ impl<'a> PluginMainThreadParams for WrapperPluginMainThread<'a> {
             return Err(std::fmt::Error);
         };
 
-        let mut buf = [MaybeUninit::zeroed(); 128];
-        let bytes = params.value_to_text(&mut self.plugin_handle(), param_id, value, &mut buf)?;
-        let str = String::from_utf8_lossy(bytes);
+        let Some(param_id) = self.param_info_cache.to_inner(param_id) else {
+            // Ghost params don't have a text representation anymore
+            return write!(writer, "{value}");
+        };
+
+        let text = self.call_wrapped(|w| {
+            let mut buf = [MaybeUninit::zeroed(); 128];
+            let bytes = params.value_to_text(&mut w.plugin_handle(), param_id, value, &mut buf)?;
 
-        writer.write_str(&str)
+            Ok::<_, std::fmt::Error>(String::from_utf8_lossy(bytes).into_owned())
+        });
+
+        match text {
+            Ok(Ok(text)) => writer.write_str(&text),
+            _ => Err(std::fmt::Error),
+        }
     }
 
     fn text_to_value(&mut self, param_id: ClapId, text: &CStr) -> Option<f64> {
-        self.wrapped_extensions()
-            .params?
-            .text_to_value(&mut self.plugin_handle(), param_id, text)
+        let param_id = self.param_info_cache.to_inner(param_id)?;
+
+        self.call_wrapped(|w| {
+            w.wrapped_extensions()
+                .params?
+                .text_to_value(&mut w.plugin_handle(), param_id, text)
+        })
+        .ok()
+        .flatten()
     }
 
     fn flush(
         &mut self,
         input_parameter_changes: &InputEvents,
         output_parameter_changes: &mut OutputEvents,
+    ) {
+        let _ = self
+            .call_wrapped(|w| w.flush_wrapped(input_parameter_changes, output_parameter_changes));
+    }
+}
+
+impl<'a> WrapperPluginMainThread<'a> {
+    fn flush_wrapped(
+        &mut self,
+        input_parameter_changes: &InputEvents,
+        output_parameter_changes: &mut OutputEvents,
     ) {
         let Some(params) = self.wrapped_extensions().params else {
             return;
         };
 
+        let id_map = self.param_info_cache.id_map();
+
+        if id_map.is_empty() {
+            params.flush(
+                &mut self.plugin_handle(),
+                input_parameter_changes,
+                output_parameter_changes,
+            );
+
+            return;
+        }
+
+        let mut input_buffer = EventBuffer::new();
+        let mut output_buffer = EventBuffer::new();
+
+        id_map.map_events(
+            input_parameter_changes,
+            &mut OutputEvents::from_buffer(&mut input_buffer),
+            true,
+        );
+
         params.flush(
             &mut self.plugin_handle(),
-            input_parameter_changes,
+            &InputEvents::from_buffer(&input_buffer),
+            &mut OutputEvents::from_buffer(&mut output_buffer),
+        );
+
+        id_map.map_events(
+            &InputEvents::from_buffer(&output_buffer),
             output_parameter_changes,
+            false,
         );
     }
 }
@@ -179,6 +509,20 @@ impl<'a> PluginAudioProcessorParams for WrapperPluginAudioProcessor<'a> {
         &mut self,
         input_parameter_changes: &InputEvents,
         output_parameter_changes: &mut OutputEvents,
+    ) {
+        // A failed flush only shows up as a misbehaving report, see call_wrapped.
+        let _ = self.call_wrapped(|p| {
+            p.flush_wrapped(input_parameter_changes, output_parameter_changes);
+            Ok(())
+        });
+    }
+}
+
+impl<'a> WrapperPluginAudioProcessor<'a> {
+    fn flush_wrapped(
+        &mut self,
+        input_parameter_changes: &InputEvents,
+        output_parameter_changes: &mut OutputEvents,
     ) {
         let Some(params) = self
             .current_audio_processor
@@ -187,10 +531,38 @@ impl<'a> PluginAudioProcessorParams for WrapperPluginAudioProcessor<'a> {
             return;
         };
 
+        let Some(id_map) = self
+            .current_audio_processor
+            .access_shared_handler(|h| h.param_id_map())
+        else {
+            params.flush_active(
+                &mut self.current_audio_processor.plugin_handle(),
+                input_parameter_changes,
+                output_parameter_changes,
+            );
+
+            return;
+        };
+
+        let buffers = &mut self.param_event_buffers;
+        buffers.clear();
+
+        id_map.map_events(
+            input_parameter_changes,
+            &mut OutputEvents::from_buffer(&mut buffers.input),
+            true,
+        );
+
         params.flush_active(
             &mut self.current_audio_processor.plugin_handle(),
-            input_parameter_changes,
+            &InputEvents::from_buffer(&buffers.input),
+            &mut OutputEvents::from_buffer(&mut buffers.output),
+        );
+
+        id_map.map_events(
+            &InputEvents::from_buffer(&buffers.output),
             output_parameter_changes,
+            false,
         );
     }
 }
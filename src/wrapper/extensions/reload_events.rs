@@ -0,0 +1,49 @@
+use crate::reload_events::{HostReloadEventsImpl, PreviousBundleInfo};
+use crate::util::LoadedBundle;
+use crate::wrapper::{WrapperHostMainThread, WrapperPluginMainThread};
+use std::ffi::CStr;
+
+impl<'a> HostReloadEventsImpl for WrapperHostMainThread<'a> {
+    fn is_replacement(&mut self) -> bool {
+        self.previous_bundle.is_some()
+    }
+
+    fn previous_bundle(&mut self) -> Option<PreviousBundleInfo> {
+        self.previous_bundle.clone()
+    }
+}
+
+impl<'a> WrapperPluginMainThread<'a> {
+    /// Tells the current instance it is about to be replaced.
+    pub(crate) fn notify_will_swap_out(&mut self) {
+        let Some(reload_events) = self.wrapped_extensions().reload_events else {
+            return;
+        };
+
+        let _ = self.call_wrapped(|w| reload_events.will_swap_out(&mut w.plugin_handle()));
+    }
+
+    /// Tells the current instance it won't be replaced after all.
+    pub(crate) fn notify_swap_out_cancelled(&mut self) {
+        let Some(reload_events) = self.wrapped_extensions().reload_events else {
+            return;
+        };
+
+        let _ = self.call_wrapped(|w| reload_events.swap_out_cancelled(&mut w.plugin_handle()));
+    }
+}
+
+/// Describes the bundle an instance of the given plugin was loaded from.
+pub fn previous_bundle_info(bundle: &LoadedBundle, plugin_id: &CStr) -> PreviousBundleInfo {
+    let plugin_version = bundle.get_plugin_factory().and_then(|f| {
+        f.plugin_descriptors()
+            .find(|d| d.id() == Some(plugin_id))
+            .and_then(|d| d.version())
+            .map(|v| v.to_string_lossy().into_owned())
+    });
+
+    PreviousBundleInfo {
+        plugin_version,
+        hash: bundle.file_hash,
+    }
+}
@@ -1,18 +1,22 @@
+use crate::log::log;
 use crate::wrapper::*;
 use clack_extensions::state::*;
 use clack_host::stream::{InputStream, OutputStream};
 use std::io::Cursor;
 
+/// Transfers the state of an instance to another using the state extension.
+///
+/// This returns `Ok(false)` if either instance does not support it.
 pub fn transfer_state(
     src: &mut PluginInstance<WrapperHost>,
     dst: &mut PluginInstance<WrapperHost>,
-) -> Result<(), StateError> {
+) -> Result<bool, StateError> {
     let Some(src_state) = src.access_shared_handler(|h| h.wrapped_plugin().state) else {
-        return Ok(());
+        return Ok(false);
     };
 
     let Some(dst_state) = dst.access_shared_handler(|h| h.wrapped_plugin().state) else {
-        return Ok(());
+        return Ok(false);
     };
 
     let mut buf = Vec::with_capacity(4096);
@@ -24,25 +28,75 @@ pub fn transfer_state(
     let mut input_stream = InputStream::from_reader(&mut cursor);
     dst_state.load(&mut dst.plugin_handle(), &mut input_stream)?;
 
+    Ok(true)
+}
+
+/// Saves the state of the wrapped instance. A build without the state extension saves nothing.
+fn save_wrapped_state(
+    plugin: &mut PluginInstance<WrapperHost>,
+    output: &mut OutputStream,
+) -> Result<(), PluginError> {
+    let Some(state) = plugin.access_shared_handler(|h| h.wrapped_plugin().state) else {
+        log!(
+            Warn,
+            StateTransfer,
+            "The wrapped plugin does not support state anymore, saving an empty state."
+        );
+        return Ok(());
+    };
+
+    state.save(&mut plugin.plugin_handle(), output)?;
+    Ok(())
+}
+
+/// Loads a state into the wrapped instance. A build without the state extension ignores it.
+fn load_wrapped_state(
+    plugin: &mut PluginInstance<WrapperHost>,
+    input: &mut InputStream,
+) -> Result<(), PluginError> {
+    let Some(state) = plugin.access_shared_handler(|h| h.wrapped_plugin().state) else {
+        log!(
+            Warn,
+            StateTransfer,
+            "The wrapped plugin does not support state anymore, ignoring the loaded state."
+        );
+        return Ok(());
+    };
+
+    state.load(&mut plugin.plugin_handle(), input)?;
     Ok(())
 }
 
 impl<'a> PluginStateImpl for WrapperPluginMainThread<'a> {
     fn save(&mut self, output: &mut OutputStream) -> Result<(), PluginError> {
-        let Some(state) = self.wrapped_extensions().state else {
-            todo!()
-        };
-
-        state.save(&mut self.plugin_handle(), output)?;
-        Ok(())
+        self.call_wrapped(|w| save_wrapped_state(&mut w.plugin_instance, output))?
     }
 
     fn load(&mut self, input: &mut InputStream) -> Result<(), PluginError> {
-        let Some(state) = self.wrapped_extensions().state else {
-            todo!()
-        };
+        self.call_wrapped(|w| load_wrapped_state(&mut w.plugin_instance, input))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapper::test_plugin::new_test_instance;
+
+    #[test]
+    fn build_without_state_saves_nothing() {
+        let mut instance = new_test_instance(false);
+        let mut buf = Vec::new();
+
+        save_wrapped_state(&mut instance, &mut OutputStream::from_writer(&mut buf)).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn build_without_state_ignores_loaded_state() {
+        let mut instance = new_test_instance(false);
+        let mut cursor = Cursor::new(vec![1, 2, 3]);
 
-        state.load(&mut self.plugin_handle(), input)?;
-        Ok(())
+        load_wrapped_state(&mut instance, &mut InputStream::from_reader(&mut cursor)).unwrap();
+        assert!(!instance.access_shared_handler(|h| h.is_quarantined()));
     }
 }
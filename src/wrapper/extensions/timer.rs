@@ -1,10 +1,22 @@
 use super::super::*;
+use crate::log::log;
 use clack_extensions::timer::*;
+use clack_host::host::HostError;
+
+/// A timer registered by the current wrapped instance, backed by a timer of the outer host.
+struct ProxiedTimer {
+    inner_id: TimerId,
+    period_ms: u32,
+    outer_id: TimerId,
+}
 
-// TODO: handle the plugins' own timers too.
 pub struct WrapperTimerHandler {
     is_initialized: bool,
     bundle_check_timer: Option<TimerId>,
+    proxied_timers: Vec<ProxiedTimer>,
+    /// Timers of a discarded instance. They can be reused by the new instance if it registers
+    /// timers with the same period, and are unregistered otherwise.
+    orphaned_timers: Vec<ProxiedTimer>,
 }
 
 impl WrapperTimerHandler {
@@ -12,6 +24,8 @@ impl WrapperTimerHandler {
         Self {
             is_initialized: false,
             bundle_check_timer: None,
+            proxied_timers: Vec::new(),
+            orphaned_timers: Vec::new(),
         }
     }
 
@@ -23,17 +37,158 @@ impl WrapperTimerHandler {
         self.is_initialized = true;
 
         if let Some(timer) = host.get_extension::<HostTimer>() {
-            let res = timer.register_timer(host, 200);
-            dbg!(&res);
-            if let Ok(timer_id) = res {
-                self.bundle_check_timer = Some(timer_id);
+            match timer.register_timer(host, 200) {
+                Ok(timer_id) => self.bundle_check_timer = Some(timer_id),
+                Err(e) => log!(Warn, Setup, "Failed to register bundle check timer: {e}"),
+            }
+        }
+    }
+
+    /// Detaches all the proxied timers from the instance that was just swapped out.
+    pub fn orphan_instance_timers(&mut self) {
+        self.orphaned_timers.append(&mut self.proxied_timers);
+    }
+
+    fn inner_timer_id(&self, outer_id: TimerId) -> Option<TimerId> {
+        self.proxied_timers
+            .iter()
+            .find(|t| t.outer_id == outer_id)
+            .map(|t| t.inner_id)
+    }
+}
+
+pub enum InnerTimerRequest {
+    Register { inner_id: TimerId, period_ms: u32 },
+    Unregister { inner_id: TimerId },
+}
+
+/// Timer (un)registrations made by a wrapped instance, waiting to be forwarded to the outer host.
+pub struct InnerTimerRequests {
+    next_id: u32,
+    pending: Vec<InnerTimerRequest>,
+}
+
+impl InnerTimerRequests {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<InnerTimerRequest> {
+        core::mem::take(&mut self.pending)
+    }
+}
+
+impl<'a> HostTimerImpl for WrapperHostMainThread<'a> {
+    fn register_timer(&mut self, period_ms: u32) -> Result<TimerId, HostError> {
+        if !self.host_capabilities.timer {
+            return Err(HostError::Message("Host does not support timers"));
+        }
+
+        let inner_id = TimerId(self.timer_requests.next_id);
+        self.timer_requests.next_id += 1;
+
+        self.timer_requests
+            .pending
+            .push(InnerTimerRequest::Register {
+                inner_id,
+                period_ms,
+            });
+
+        Ok(inner_id)
+    }
+
+    fn unregister_timer(&mut self, timer_id: TimerId) -> Result<(), HostError> {
+        if timer_id.0 >= self.timer_requests.next_id {
+            return Err(HostError::Message("Unknown timer ID"));
+        }
+
+        self.timer_requests
+            .pending
+            .push(InnerTimerRequest::Unregister { inner_id: timer_id });
+
+        Ok(())
+    }
+}
+
+impl<'a> WrapperPluginMainThread<'a> {
+    /// Registers or unregisters outer host timers for the current instance's own timers.
+    pub(crate) fn process_timer_requests(&mut self) {
+        let requests = self
+            .plugin_instance
+            .access_handler_mut(|h| h.timer_requests.take());
+
+        let Some(host_timer) = self.shared.host_extensions.timer else {
+            return;
+        };
+
+        let timers = &mut self.timers;
+
+        for request in requests {
+            match request {
+                InnerTimerRequest::Register {
+                    inner_id,
+                    period_ms,
+                } => {
+                    // Reuse a timer from a discarded instance if we can
+                    if let Some(index) = timers
+                        .orphaned_timers
+                        .iter()
+                        .position(|t| t.period_ms == period_ms)
+                    {
+                        let mut timer = timers.orphaned_timers.remove(index);
+                        timer.inner_id = inner_id;
+                        timers.proxied_timers.push(timer);
+                        continue;
+                    }
+
+                    match host_timer.register_timer(&mut self.host, period_ms) {
+                        Ok(outer_id) => timers.proxied_timers.push(ProxiedTimer {
+                            inner_id,
+                            period_ms,
+                            outer_id,
+                        }),
+                        Err(e) => log!(
+                            Warn,
+                            Swap,
+                            "Failed to register timer for wrapped plugin: {e}"
+                        ),
+                    }
+                }
+                InnerTimerRequest::Unregister { inner_id } => {
+                    let Some(index) = timers
+                        .proxied_timers
+                        .iter()
+                        .position(|t| t.inner_id == inner_id)
+                    else {
+                        continue;
+                    };
+
+                    let timer = timers.proxied_timers.remove(index);
+                    let _ = host_timer.unregister_timer(&mut self.host, timer.outer_id);
+                }
             }
         }
+
+        // Timers from discarded instances that weren't reused are not needed anymore
+        for timer in timers.orphaned_timers.drain(..) {
+            let _ = host_timer.unregister_timer(&mut self.host, timer.outer_id);
+        }
     }
 }
 
 impl<'a> PluginTimerImpl for WrapperPluginMainThread<'a> {
-    fn on_timer(&mut self, _timer_id: TimerId) {
-        self.check_for_new_bundles()
+    fn on_timer(&mut self, timer_id: TimerId) {
+        if self.timers.bundle_check_timer == Some(timer_id) {
+            self.check_for_new_bundles();
+        } else if let Some(inner_id) = self.timers.inner_timer_id(timer_id) {
+            if let Some(timer) = self.wrapped_extensions().timer {
+                let _ = self.call_wrapped(|w| timer.on_timer(&mut w.plugin_handle(), inner_id));
+            }
+        }
+
+        self.process_requests();
     }
 }
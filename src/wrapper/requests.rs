@@ -5,6 +5,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct PluginSharedRequests {
     callback_requested: AtomicBool,
+    restart_requested: AtomicBool,
+    process_requested: AtomicBool,
     pub(super) gui: PluginGuiRequests,
 }
 
@@ -12,6 +14,8 @@ impl PluginSharedRequests {
     pub fn new() -> Self {
         Self {
             callback_requested: AtomicBool::new(false),
+            restart_requested: AtomicBool::new(false),
+            process_requested: AtomicBool::new(false),
             gui: PluginGuiRequests::new(),
         }
     }
@@ -20,6 +24,14 @@ impl PluginSharedRequests {
         self.callback_requested.store(true, Ordering::Relaxed)
     }
 
+    pub fn request_restart(&self) {
+        self.restart_requested.store(true, Ordering::Relaxed)
+    }
+
+    pub fn request_process(&self) {
+        self.process_requested.store(true, Ordering::Relaxed)
+    }
+
     pub fn process_requests(
         &self,
         parent_host: &HostSharedHandle,
@@ -34,6 +46,14 @@ impl PluginSharedRequests {
             parent_host.request_callback()
         }
 
+        if self.restart_requested.swap(false, Ordering::Relaxed) {
+            parent_host.request_restart()
+        }
+
+        if self.process_requested.swap(false, Ordering::Relaxed) {
+            parent_host.request_process()
+        }
+
         self.gui.process_requests(parent_host, extensions);
     }
 }
@@ -0,0 +1,230 @@
+#![allow(unsafe_code)] // Needed to load plugin bundles from static entries
+
+//! Minimal plugins to wrap in tests, loaded in-process from static entries.
+
+use crate::dsp_handoff::{
+    PluginAudioProcessorDspHandoffImpl, PluginDspHandoff, PluginDspHandoffImpl,
+};
+use crate::entry::HotReloaderEntry;
+use crate::util::LoadedBundle;
+use crate::wrapper::{
+    OuterHostCapabilities, WrapperHost, WrapperHostMainThread, WrapperHostShared,
+};
+use crate::{HotReloadConfig, LogEvent, LogSink};
+use clack_extensions::audio_ports::RescanType;
+use clack_extensions::latency::{PluginLatency, PluginLatencyImpl};
+use clack_extensions::note_ports::NoteDialects;
+use clack_host::prelude::*;
+use clack_plugin::clack_export_entry;
+use clack_plugin::entry::{EntryDescriptor, SinglePluginEntry};
+use clack_plugin::prelude::*;
+use clack_plugin::prelude::{
+    HostMainThreadHandle, HostSharedHandle, PluginAudioProcessor, PluginDescriptor,
+    PluginMainThread,
+};
+use std::ffi::CString;
+
+pub const TEST_AUDIO_CONFIG: PluginAudioConfiguration = PluginAudioConfiguration {
+    sample_rate: 44_100.0,
+    min_frames_count: 1,
+    max_frames_count: 64,
+};
+
+/// The schema version of the test plugin's DSP state, a single `u32`.
+pub const TEST_DSP_STATE_VERSION: u32 = 1;
+
+/// A plugin that outputs nothing, or that panics whenever it processes audio or reports its
+/// latency if `PANICS` is set.
+pub struct TestPlugin<const PANICS: bool>;
+
+impl<const PANICS: bool> Plugin for TestPlugin<PANICS> {
+    type AudioProcessor<'a> = TestAudioProcessor<PANICS>;
+    type Shared<'a> = ();
+    type MainThread<'a> = TestMainThread<PANICS>;
+
+    fn declare_extensions(builder: &mut PluginExtensions<Self>, _shared: Option<&()>) {
+        builder.register::<PluginLatency>();
+        builder.register::<PluginDspHandoff>();
+    }
+}
+
+impl<const PANICS: bool> DefaultPluginFactory for TestPlugin<PANICS> {
+    fn get_descriptor() -> PluginDescriptor {
+        PluginDescriptor::new(plugin_id(PANICS), "Test plugin")
+    }
+
+    fn new_shared(_host: HostSharedHandle) -> Result<(), PluginError> {
+        Ok(())
+    }
+
+    fn new_main_thread<'a>(
+        _host: HostMainThreadHandle<'a>,
+        _shared: &'a (),
+    ) -> Result<TestMainThread<PANICS>, PluginError> {
+        Ok(TestMainThread)
+    }
+}
+
+pub struct TestMainThread<const PANICS: bool>;
+
+impl<'a, const PANICS: bool> PluginMainThread<'a, ()> for TestMainThread<PANICS> {}
+
+impl<const PANICS: bool> PluginLatencyImpl for TestMainThread<PANICS> {
+    fn get(&mut self) -> u32 {
+        if PANICS {
+            panic!("Panicking on the main thread")
+        }
+
+        0
+    }
+}
+
+impl<const PANICS: bool> PluginDspHandoffImpl for TestMainThread<PANICS> {
+    fn max_size(&mut self) -> usize {
+        size_of::<u32>()
+    }
+}
+
+pub struct TestAudioProcessor<const PANICS: bool> {
+    dsp_state: u32,
+}
+
+impl<'a, const PANICS: bool> PluginAudioProcessor<'a, (), TestMainThread<PANICS>>
+    for TestAudioProcessor<PANICS>
+{
+    fn activate(
+        _host: HostAudioProcessorHandle<'a>,
+        _main_thread: &mut TestMainThread<PANICS>,
+        _shared: &'a (),
+        _audio_config: PluginAudioConfiguration,
+    ) -> Result<Self, PluginError> {
+        Ok(Self { dsp_state: 0 })
+    }
+
+    fn process(
+        &mut self,
+        _process: Process,
+        _audio: Audio,
+        _events: Events,
+    ) -> Result<ProcessStatus, PluginError> {
+        if PANICS {
+            panic!("Panicking on the audio thread")
+        }
+
+        Ok(ProcessStatus::Continue)
+    }
+}
+
+impl<const PANICS: bool> PluginAudioProcessorDspHandoffImpl for TestAudioProcessor<PANICS> {
+    fn export_state(&mut self, buffer: &mut [u8]) -> Option<(usize, u32)> {
+        let bytes = self.dsp_state.to_le_bytes();
+        buffer.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+
+        Some((bytes.len(), TEST_DSP_STATE_VERSION))
+    }
+
+    fn import_state(&mut self, state: &[u8], version: u32) -> bool {
+        if version != TEST_DSP_STATE_VERSION {
+            return false;
+        }
+
+        let Ok(bytes) = state.try_into() else {
+            return false;
+        };
+
+        self.dsp_state = u32::from_le_bytes(bytes);
+        true
+    }
+}
+
+static SILENT_PLUGIN: EntryDescriptor = clack_export_entry!(SinglePluginEntry<TestPlugin<false>>);
+static PANICKING_PLUGIN: EntryDescriptor = clack_export_entry!(SinglePluginEntry<TestPlugin<true>>);
+
+/// The hot-reloader itself, wrapping the silent test plugin without watching for new bundles.
+static WRAPPED_SILENT_PLUGIN: EntryDescriptor = clack_export_entry!(
+    HotReloaderEntry,
+    (|path| {
+        let config = HotReloadConfig::new()
+            .with_reloading(false)
+            .with_log_sink(DiscardedLog);
+
+        HotReloaderEntry::new(path, &SILENT_PLUGIN, config)
+    })
+);
+
+/// Keeps tests from writing log files.
+struct DiscardedLog;
+
+impl LogSink for DiscardedLog {
+    fn log(&self, _event: &LogEvent) {}
+}
+
+fn plugin_id(panics: bool) -> &'static str {
+    if panics {
+        "org.rust-audio.clap-hot-reload.test.panicking"
+    } else {
+        "org.rust-audio.clap-hot-reload.test.silent"
+    }
+}
+
+/// Loads the test plugin as a bundle the hot-reloader can swap to.
+pub fn test_bundle(panics: bool) -> LoadedBundle {
+    let entry = if panics {
+        &PANICKING_PLUGIN
+    } else {
+        &SILENT_PLUGIN
+    };
+
+    // SAFETY: the entry is a valid, static CLAP entry.
+    let bundle = unsafe { PluginBundle::load_from_raw(entry, "") }.unwrap();
+    LoadedBundle::without_metadata(bundle)
+}
+
+/// Creates a wrapped instance of the test plugin, as the hot-reloader would.
+pub fn new_test_instance(panics: bool) -> PluginInstance<WrapperHost> {
+    new_instance(&test_bundle(panics), panics)
+}
+
+/// Creates an instance of the hot-reloader wrapping the silent test plugin. The wrapper host is
+/// reused as the outer host.
+pub fn new_wrapper_instance() -> PluginInstance<WrapperHost> {
+    // SAFETY: the entry is a valid, static CLAP entry.
+    let bundle = unsafe { PluginBundle::load_from_raw(&WRAPPED_SILENT_PLUGIN, "") }.unwrap();
+    new_instance(&bundle, false)
+}
+
+/// Processes an empty block, starting processing first if needed.
+pub fn process_empty_block(processor: &mut clack_host::process::PluginAudioProcessor<WrapperHost>) {
+    processor
+        .ensure_processing_started()
+        .unwrap()
+        .process(
+            &InputAudioBuffers::empty(),
+            &mut OutputAudioBuffers::empty(),
+            &InputEvents::empty(),
+            &mut OutputEvents::void(),
+            None,
+            None,
+        )
+        .unwrap();
+}
+
+fn new_instance(bundle: &PluginBundle, panics: bool) -> PluginInstance<WrapperHost> {
+    let plugin_id = CString::new(plugin_id(panics)).unwrap();
+    let info = HostInfo::new("test", "", "", "").unwrap();
+
+    let capabilities = OuterHostCapabilities {
+        audio_ports_rescan: RescanType::empty(),
+        note_dialects: NoteDialects::empty(),
+        timer: false,
+    };
+
+    PluginInstance::<WrapperHost>::new(
+        |_| WrapperHostShared::new(),
+        |s| WrapperHostMainThread::new(s, capabilities, None),
+        bundle,
+        &plugin_id,
+        &info,
+    )
+    .unwrap()
+}